@@ -1,4 +1,27 @@
+use std::{env, fs, path::Path};
+
+/// 默认的内核栈大小: 20 页
+const DEFAULT_KERNEL_STACK_SIZE: u64 = 4096 * 20;
+
 fn main() {
+    // 内核栈的大小 (单位: 字节), 由 builder 通过环境变量传入
+    println!("cargo:rerun-if-env-changed=KERNEL_STACK_SIZE");
+    let kernel_stack_size = match env::var("KERNEL_STACK_SIZE") {
+        Ok(size) => size.parse::<u64>().expect("KERNEL_STACK_SIZE must be a number of bytes"),
+        Err(_) => DEFAULT_KERNEL_STACK_SIZE,
+    };
+    assert!(kernel_stack_size > 0, "KERNEL_STACK_SIZE must not be 0");
+
+    let config = format!("pub const KERNEL_STACK_SIZE: u64 = {};\n", kernel_stack_size);
+    let out_dir = env::var("OUT_DIR").unwrap();
+    fs::write(Path::new(&out_dir).join("config.rs"), config).expect("failed to write config.rs");
+
+    // 只有在构建 bootloader 时才链接 kernel, 在 host 上运行单元测试时不需要
+    let target = std::env::var("TARGET").unwrap_or_default();
+    if !target.contains("bootloader") {
+        return;
+    }
+
     // 链接 kernel
     println!("cargo:rustc-link-search=native=./target");
     println!("cargo:rustc-link-lib=static=kernel");
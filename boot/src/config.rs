@@ -0,0 +1,6 @@
+//! Build time configuration of the bootloader.
+//!
+//! The values are generated by `build.rs` from environment variables, which are set by the
+//! builder (see `src/main.rs` in the root of the project).
+
+include!(concat!(env!("OUT_DIR"), "/config.rs"));
@@ -1,8 +1,10 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_imports))]
 #![feature(step_trait)]
 #![feature(maybe_uninit_slice)]
 
+mod config;
 mod gdt;
 mod memory;
 mod logger;
@@ -27,20 +29,28 @@ use x86_64::structures::paging::{
     PageTableFlags, PhysFrame, Size2MiB, Size4KiB,
 };
 
+// 在 host 上运行单元测试时, 不需要引导相关的汇编和符号
+#[cfg(not(test))]
 global_asm!(include_str!("asm/stage_1.s"));
+#[cfg(not(test))]
 global_asm!(include_str!("asm/stage_2_real.s"));
+#[cfg(not(test))]
 global_asm!(include_str!("asm/stage_2_protected.s"));
+#[cfg(not(test))]
 global_asm!(include_str!("asm/stage_3.s"));
 
 // Symbols defined in `linker.ld`
+#[cfg(not(test))]
 extern "C" {
     static mmap_ent: usize;
     static _memory_map: usize;
+    static _memory_map_end: usize;
     static _kernel_size: usize;
     static _p4: usize;
     static _p3: usize;
 }
 
+#[cfg(not(test))]
 #[no_mangle]
 pub unsafe extern "C" fn stage_4() -> ! {
     // 设置栈段
@@ -58,6 +68,7 @@ pub unsafe extern "C" fn stage_4() -> ! {
     let kernel_start = 0x400000;
     let kernel_size = &_kernel_size as *const _ as u64;
     let memory_map_addr = &_memory_map as *const _ as u64;
+    let memory_map_buffer_size = &_memory_map_end as *const _ as u64 - memory_map_addr;
     let memory_map_entry_count = (mmap_ent & 0xff) as u64;
 
     log::info!("Booting(4)");
@@ -66,6 +77,7 @@ pub unsafe extern "C" fn stage_4() -> ! {
         PhysAddr::new(kernel_start), 
         kernel_size, 
         VirtAddr::new(memory_map_addr), 
+        memory_map_buffer_size,
         memory_map_entry_count
     )
 }
@@ -74,12 +86,25 @@ fn bootloader_main(
     kernel_start: PhysAddr,
     kernel_size: u64,
     memory_map_addr: VirtAddr,
+    memory_map_buffer_size: u64,
     memory_map_entry_count: u64,
 ) -> ! {
-    use memory::memory_descriptor::E820MemoryRegion;
+    use memory::{e820, memory_descriptor::E820MemoryRegion};
     let e820_memory_map = {
-        let ptr = memory_map_addr.as_u64() as usize as *const E820MemoryRegion;
-        unsafe { core::slice::from_raw_parts(ptr, memory_map_entry_count as usize) }
+        let ptr = memory_map_addr.as_u64() as usize as *mut E820MemoryRegion;
+        let capacity = memory_map_buffer_size as usize / core::mem::size_of::<E820MemoryRegion>();
+        let buffer = unsafe { core::slice::from_raw_parts_mut(ptr, capacity) };
+        let entry_count = memory_map_entry_count as usize;
+
+        // sort, merge and page-align the regions, the rest of the buffer is used as scratch space
+        let len = match e820::sanitize_in_place(buffer, entry_count) {
+            Ok(len) => len,
+            Err(err) => {
+                log::warn!("{}, using the memory map as reported by the BIOS", err);
+                entry_count
+            }
+        };
+        &buffer[..len]
     };
     let max_phys_addr = e820_memory_map
         .iter()
@@ -218,6 +243,7 @@ fn create_page_tables(
     }
 }
 
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     unsafe {
@@ -0,0 +1,305 @@
+//! Sanitizing of the memory map returned by the `e820` BIOS call.
+//!
+//! The BIOS reports memory regions in no particular order, and real firmware (as well as
+//! emulators like Bochs) happily reports regions that overlap each other. The frame allocator
+//! walks the map in order and assumes that regions don't overlap, so the raw map has to be
+//! normalized before it is used:
+//!
+//! 1. overlapping parts get the most restrictive type of all regions covering them
+//! 2. the result is sorted by address and adjacent regions of the same type are merged
+//! 3. usable regions are shrunk to page boundaries, so a usable frame never shares a page with
+//!    reserved memory
+//!
+//! See http://wiki.osdev.org/Detecting_Memory_(x86)#BIOS_Function:_INT_0x15.2C_EAX_.3D_0xE820
+//! for the meaning of the region types.
+
+use super::{memory_descriptor::E820MemoryRegion, PAGE_SIZE};
+
+/// Normal RAM, usable by the kernel.
+pub const E820_USABLE: u32 = 1;
+/// Reserved, must not be used.
+pub const E820_RESERVED: u32 = 2;
+/// Holds ACPI tables, usable once the tables have been read.
+pub const E820_ACPI_RECLAIMABLE: u32 = 3;
+/// ACPI non-volatile storage, must be preserved across sleep states.
+pub const E820_ACPI_NVS: u32 = 4;
+/// Memory that the firmware detected as faulty.
+pub const E820_BAD_MEMORY: u32 = 5;
+
+/// Returns how restrictive a region type is. When regions overlap, the type with the highest
+/// value wins; unknown types are treated like [`E820_RESERVED`].
+fn restrictiveness(region_type: u32) -> u8 {
+    match region_type {
+        E820_USABLE => 0,
+        E820_ACPI_RECLAIMABLE => 1,
+        E820_ACPI_NVS => 2,
+        E820_RESERVED => 3,
+        E820_BAD_MEMORY => 4,
+        _ => restrictiveness(E820_RESERVED),
+    }
+}
+
+/// Returns the (exclusive) end address of the region, saturating on bogus lengths.
+fn end_addr(region: &E820MemoryRegion) -> u64 {
+    region.start_addr.saturating_add(region.len)
+}
+
+/// Writes the sanitized version of the `input` memory map to `output`.
+///
+/// Returns the number of regions written, or an error if `output` is too small. In the worst
+/// case the sanitized map has `2 * input.len() - 1` regions.
+///
+/// The whole map is rescanned for every boundary, which is quadratic in the number of regions.
+/// That is fine for the few dozen regions a BIOS reports and needs no scratch memory.
+pub fn sanitize(
+    input: &[E820MemoryRegion],
+    output: &mut [E820MemoryRegion],
+) -> Result<usize, &'static str> {
+    let regions = || input.iter().filter(|r| end_addr(r) > r.start_addr);
+
+    let mut len = 0;
+    let mut pos = match regions().map(|r| r.start_addr).min() {
+        Some(start) => start,
+        None => return Ok(0),
+    };
+
+    // walk from boundary to boundary; the type doesn't change in between
+    while let Some(next) = regions()
+        .flat_map(|r| [r.start_addr, end_addr(r)])
+        .filter(|&addr| addr > pos)
+        .min()
+    {
+        let region_type = regions()
+            .filter(|r| r.start_addr <= pos && pos < end_addr(r))
+            .map(|r| r.region_type)
+            .max_by_key(|&ty| (restrictiveness(ty), ty));
+
+        // `None` means that no region covers this range, i.e. it's a hole in the map
+        if let Some(region_type) = region_type {
+            match output[..len].last_mut() {
+                Some(last) if last.region_type == region_type && end_addr(last) == pos => {
+                    last.len += next - pos;
+                }
+                _ => {
+                    let slot = output
+                        .get_mut(len)
+                        .ok_or("not enough space for the sanitized memory map")?;
+                    *slot = E820MemoryRegion {
+                        start_addr: pos,
+                        len: next - pos,
+                        region_type,
+                        acpi_extended_attributes: 1,
+                    };
+                    len += 1;
+                }
+            }
+        }
+
+        pos = next;
+    }
+
+    // shrink usable regions to page boundaries and drop the ones that become empty
+    let mut aligned_len = 0;
+    for i in 0..len {
+        let mut region = output[i];
+        if region.region_type == E820_USABLE {
+            let start = align_up(region.start_addr);
+            let end = end_addr(&region) & !(PAGE_SIZE - 1);
+            if end <= start {
+                continue;
+            }
+            region.start_addr = start;
+            region.len = end - start;
+        }
+        output[aligned_len] = region;
+        aligned_len += 1;
+    }
+
+    Ok(aligned_len)
+}
+
+/// Sanitizes the first `len` regions of `buffer` in place, using the rest of `buffer` as
+/// scratch space.
+///
+/// Returns the new number of regions at the start of `buffer`.
+pub fn sanitize_in_place(
+    buffer: &mut [E820MemoryRegion],
+    len: usize,
+) -> Result<usize, &'static str> {
+    let (input, output) = buffer.split_at_mut(len);
+    let new_len = sanitize(input, output)?;
+    buffer.copy_within(len..len + new_len, 0);
+    Ok(new_len)
+}
+
+fn align_up(addr: u64) -> u64 {
+    match addr % PAGE_SIZE {
+        0 => addr,
+        rem => addr.saturating_add(PAGE_SIZE - rem),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(start_addr: u64, end: u64, region_type: u32) -> E820MemoryRegion {
+        E820MemoryRegion {
+            start_addr,
+            len: end - start_addr,
+            region_type,
+            acpi_extended_attributes: 1,
+        }
+    }
+
+    fn sanitized(input: &[E820MemoryRegion]) -> Vec<E820MemoryRegion> {
+        let mut output = vec![region(0, 0, 0); input.len() * 2];
+        let len = sanitize(input, &mut output).unwrap();
+        output.truncate(len);
+        output
+    }
+
+    #[test]
+    fn empty_map() {
+        assert!(sanitized(&[]).is_empty());
+        assert!(sanitized(&[region(0x1000, 0x1000, E820_USABLE)]).is_empty());
+    }
+
+    #[test]
+    fn sorts_by_address() {
+        let input = [
+            region(0x100000, 0x200000, E820_USABLE),
+            region(0xf0000, 0x100000, E820_RESERVED),
+            region(0, 0x9f000, E820_USABLE),
+        ];
+        assert_eq!(
+            sanitized(&input),
+            [
+                region(0, 0x9f000, E820_USABLE),
+                region(0xf0000, 0x100000, E820_RESERVED),
+                region(0x100000, 0x200000, E820_USABLE),
+            ]
+        );
+    }
+
+    #[test]
+    fn overlap_resolved_in_favor_of_most_restrictive_type() {
+        let input = [
+            region(0, 0x10000, E820_USABLE),
+            region(0x4000, 0x6000, E820_ACPI_RECLAIMABLE),
+            region(0x5000, 0x8000, E820_RESERVED),
+        ];
+        assert_eq!(
+            sanitized(&input),
+            [
+                region(0, 0x4000, E820_USABLE),
+                region(0x4000, 0x5000, E820_ACPI_RECLAIMABLE),
+                region(0x5000, 0x8000, E820_RESERVED),
+                region(0x8000, 0x10000, E820_USABLE),
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_memory_wins_over_reserved() {
+        let input = [
+            region(0x1000, 0x3000, E820_BAD_MEMORY),
+            region(0, 0x4000, E820_RESERVED),
+        ];
+        assert_eq!(
+            sanitized(&input),
+            [
+                region(0, 0x1000, E820_RESERVED),
+                region(0x1000, 0x3000, E820_BAD_MEMORY),
+                region(0x3000, 0x4000, E820_RESERVED),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_types_are_treated_as_reserved() {
+        let input = [region(0, 0x4000, E820_USABLE), region(0x1000, 0x2000, 12)];
+        assert_eq!(
+            sanitized(&input),
+            [
+                region(0, 0x1000, E820_USABLE),
+                region(0x1000, 0x2000, 12),
+                region(0x2000, 0x4000, E820_USABLE),
+            ]
+        );
+    }
+
+    #[test]
+    fn merges_adjacent_and_duplicate_regions() {
+        let input = [
+            region(0x2000, 0x3000, E820_USABLE),
+            region(0, 0x2000, E820_USABLE),
+            region(0x1000, 0x3000, E820_USABLE),
+            region(0x3000, 0x4000, E820_RESERVED),
+            region(0x4000, 0x5000, E820_RESERVED),
+        ];
+        assert_eq!(
+            sanitized(&input),
+            [
+                region(0, 0x3000, E820_USABLE),
+                region(0x3000, 0x5000, E820_RESERVED),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_holes() {
+        let input = [
+            region(0, 0x1000, E820_USABLE),
+            region(0x2000, 0x3000, E820_USABLE),
+        ];
+        assert_eq!(sanitized(&input), input);
+    }
+
+    #[test]
+    fn page_aligns_usable_regions() {
+        let input = [
+            region(0, 0x9fc00, E820_USABLE),
+            region(0x9fc00, 0xa0000, E820_RESERVED),
+            region(0x100800, 0x200400, E820_USABLE),
+            region(0x300100, 0x300f00, E820_USABLE),
+        ];
+        assert_eq!(
+            sanitized(&input),
+            [
+                region(0, 0x9f000, E820_USABLE),
+                region(0x9fc00, 0xa0000, E820_RESERVED),
+                region(0x101000, 0x200000, E820_USABLE),
+            ]
+        );
+    }
+
+    #[test]
+    fn output_too_small() {
+        let input = [
+            region(0, 0x1000, E820_USABLE),
+            region(0x1000, 0x2000, E820_RESERVED),
+        ];
+        let mut output = [region(0, 0, 0); 1];
+        assert!(sanitize(&input, &mut output).is_err());
+    }
+
+    #[test]
+    fn in_place() {
+        let mut buffer = [region(0, 0, 0); 8];
+        buffer[0] = region(0x100000, 0x800000, E820_USABLE);
+        buffer[1] = region(0, 0x9fc00, E820_USABLE);
+        buffer[2] = region(0x400000, 0x500000, E820_RESERVED);
+
+        let len = sanitize_in_place(&mut buffer, 3).unwrap();
+        assert_eq!(
+            buffer[..len],
+            [
+                region(0, 0x9f000, E820_USABLE),
+                region(0x100000, 0x400000, E820_USABLE),
+                region(0x400000, 0x500000, E820_RESERVED),
+                region(0x500000, 0x800000, E820_USABLE),
+            ]
+        );
+    }
+}
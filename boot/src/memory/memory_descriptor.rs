@@ -1,4 +1,4 @@
-use super::legacy_memory_region::LegacyMemoryRegion;
+use super::{e820::E820_USABLE, legacy_memory_region::LegacyMemoryRegion};
 
 use boot_info::MemoryRegionKind;
 
@@ -28,7 +28,7 @@ impl LegacyMemoryRegion for E820MemoryRegion {
 
     fn kind(&self) -> MemoryRegionKind {
         match self.region_type {
-            1 => MemoryRegionKind::Usable,
+            E820_USABLE => MemoryRegionKind::Usable,
             other => MemoryRegionKind::Unknown(other),
         }
     }
@@ -1,3 +1,4 @@
+pub mod e820;
 pub mod memory_descriptor;
 pub mod legacy_memory_region;
 pub mod level_4_entries;
@@ -1,4 +1,4 @@
-use crate::{loader, gdt, memory::PAGE_SIZE};
+use crate::{loader, gdt, config, memory::PAGE_SIZE};
 
 use core::{arch::asm, alloc::Layout, mem::MaybeUninit};
 
@@ -8,9 +8,10 @@ use crate::{
     memory::legacy_memory_region::{LegacyFrameAllocator, LegacyMemoryRegion},
 };
 
-use boot_info::{BootInfo, FrameBuffer, FrameBufferInfo, TlsTemplate, MemoryRegion};
+use boot_info::{BootInfo, FrameBuffer, FrameBufferInfo, KernelStack, TlsTemplate, MemoryRegion};
 
 use x86_64::{
+    align_up,
     structures::paging::{
         FrameAllocator, Mapper, Page, PageSize, PageTableFlags,
         PhysFrame, Size4KiB, Size2MiB,
@@ -106,14 +107,15 @@ where
     .expect("no entry point");
     log::info!("Entry point at: {:#x}", entry_point.as_u64());
 
-    // create a stack
-    let stack_size = PAGE_SIZE * 20;
-    let stack_start_addr = used_entries.get_free_address(stack_size, 16);
-    let stack_start: Page = Page::containing_address(stack_start_addr);
+    // create a stack, the first page is left unmapped as a guard page
+    let stack_size = align_up(config::KERNEL_STACK_SIZE, PAGE_SIZE);
+    let guard_page_addr = used_entries.get_free_address(PAGE_SIZE + stack_size, 16);
+    let stack_start: Page = Page::containing_address(guard_page_addr) + 1;
     let stack_end = {
-        let end_addr = stack_start_addr + stack_size;
+        let end_addr = stack_start.start_address() + stack_size;
         Page::containing_address(end_addr - 1u64)
     };
+    log::info!("Kernel stack at: {:#x} - {:#x}", stack_start.start_address(), stack_end.start_address() + PAGE_SIZE);
     for page in Page::range_inclusive(stack_start, stack_end) {
         let frame = frame_allocator
             .allocate_frame()
@@ -212,6 +214,7 @@ where
     Mappings {
         framebuffer: framebuffer_virt_addr,
         entry_point,
+        stack_start,
         stack_end,
         used_entries,
         physical_memory_offset,
@@ -223,6 +226,8 @@ where
 pub struct Mappings {
     /// The entry point address of the kernel.
     pub entry_point: VirtAddr,
+    /// The lowest page of the kernel stack, the page below it is an unmapped guard page.
+    pub stack_start: Page,
     /// The stack end page of the kernel.
     pub stack_end: Page,
     /// Keeps track of used entries in the level 4 page table, useful for finding a free
@@ -335,6 +340,11 @@ where
         rsdp_addr: system_info.rsdp_addr.map(|addr| addr.as_u64()).into(),
         physical_memory_offset: mappings.physical_memory_offset.as_u64(),
         tls_template: mappings.tls_template.into(),
+        kernel_stack: KernelStack {
+            start: mappings.stack_start.start_address().as_u64(),
+            end: (mappings.stack_end + 1).start_address().as_u64(),
+            guard_size: PAGE_SIZE,
+        },
     });
 
     boot_info
@@ -353,7 +363,7 @@ pub fn switch_to_kernel(
     } = page_tables;
     let addresses = Addresses {
         page_table: kernel_level_4_frame,
-        stack_top: mappings.stack_end.start_address(),
+        stack_top: (mappings.stack_end + 1).start_address(),
         entry_point: mappings.entry_point,
         boot_info
     };
@@ -37,6 +37,8 @@ pub struct BootInfo {
     pub rsdp_addr: Optional<u64>,
     /// The thread local storage (TLS) template of the kernel executable, if present.
     pub tls_template: Optional<TlsTemplate>,
+    /// The virtual address range of the stack the kernel is started on.
+    pub kernel_stack: KernelStack,
 }
 
 /// Represents the different types of memory.
@@ -47,7 +49,9 @@ pub enum MemoryRegionKind {
     Usable,
     /// Memory mappings created by the bootloader, including the kernel and boot info mappings.
     ///
-    /// This memory should _not_ be used by the kernel.
+    /// This memory should _not_ be used by the kernel, except for the frames that are no longer
+    /// mapped in the kernel's page table, which the kernel may reclaim once it is done with
+    /// the boot info.
     Bootloader,
     /// An unknown memory region reported by the BIOS firmware.
     ///
@@ -187,6 +191,29 @@ pub struct TlsTemplate {
     pub mem_size: u64,
 }
 
+/// The stack the bootloader sets up for the kernel.
+///
+/// The page directly below `start` is a guard page that is never mapped, so a stack overflow
+/// causes a page fault inside of it instead of silently overwriting other memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct KernelStack {
+    /// The lowest (virtual) address of the stack.
+    pub start: u64,
+    /// The end address (exclusive) of the stack, which is the initial stack pointer.
+    pub end: u64,
+    /// The size of the unmapped guard page below `start`.
+    pub guard_size: u64,
+}
+
+impl KernelStack {
+    /// Returns whether `addr` lies in the guard page, i.e. whether an access to it is a
+    /// stack overflow.
+    pub fn guard_page_contains(&self, addr: u64) -> bool {
+        self.start - self.guard_size <= addr && addr < self.start
+    }
+}
+
 /// FFI-safe variant of [`Option`].
 ///
 /// Implements the [`From`] and [`Into`] traits for easy conversion to and from [`Option`].
@@ -0,0 +1,169 @@
+//! # ACPI 表
+//! 参考: ACPI Specification 6.4, 5.2 ACPI System Description Tables
+//!       https://wiki.osdev.org/RSDP && https://wiki.osdev.org/MADT
+//!
+//! 目前只解析 MADT (Multiple APIC Description Table), 用于找到所有的处理器和 local APIC 的地址:
+//!
+//! ```text
+//!   RSDP ----> RSDT (32 位指针) / XSDT (64 位指针, revision >= 2)
+//!                 |
+//!                 +--> "APIC" (MADT) --> local APIC 地址
+//!                 |                      Processor Local APIC (type 0)
+//!                 +--> 其它表             Local APIC Address Override (type 5)
+//!                                         Processor Local x2APIC (type 9)
+//! ```
+//!
+//! 所有表都通过物理内存的映射 (见 [`memory::phys_to_virt`]) 读取, 表中的字段不一定是对齐的。
+
+use alloc::vec::Vec;
+
+use crate::memory;
+
+use conquer_once::spin::OnceCell;
+use x86_64::PhysAddr;
+
+static MADT: OnceCell<Madt> = OnceCell::uninit();
+
+/// 所有 System Description Table 共有的表头的长度
+const SDT_HEADER_SIZE: u64 = 36;
+
+/// MADT 中的信息
+#[derive(Debug, Clone)]
+pub struct Madt {
+    /// local APIC 寄存器的物理地址, 所有处理器都相同
+    pub local_apic_address: PhysAddr,
+    /// 按照在表中出现的顺序排列, 第一个通常是 BSP
+    pub processors: Vec<Processor>,
+}
+
+/// MADT 中的一个处理器
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    pub acpi_id: u32,
+    pub apic_id: u32,
+    /// 处理器可以被启动 (Enabled 或 Online Capable)
+    pub usable: bool,
+}
+
+/// 通过 bootloader 找到的 RSDP 解析 ACPI 表, 找不到 MADT 时只输出警告
+///
+/// 需要在内存初始化之后调用。
+pub fn init(rsdp_addr: Option<u64>) {
+    let rsdp_addr = match rsdp_addr {
+        Some(addr) => PhysAddr::new(addr),
+        None => {
+            log::warn!("no RSDP found, ACPI tables are unavailable");
+            return;
+        }
+    };
+
+    match unsafe { parse_madt(rsdp_addr) } {
+        Ok(madt) => {
+            log::info!(
+                "MADT: local APIC at {:#x}, {} processor(s)",
+                madt.local_apic_address.as_u64(),
+                madt.processors.len(),
+            );
+            MADT.init_once(|| madt);
+        }
+        Err(err) => log::warn!("failed to parse MADT: {}", err),
+    }
+}
+
+/// 解析出的 MADT, ACPI 不可用时返回 `None`
+pub fn madt() -> Option<&'static Madt> {
+    MADT.get()
+}
+
+/// 读取物理地址 `addr` 处的 `T`
+///
+/// ## Safety
+/// `addr` 处必须是一个有效的 `T`, 且位于物理内存的映射中。
+unsafe fn read<T: Copy>(addr: PhysAddr) -> T {
+    memory::phys_to_virt(addr).as_ptr::<T>().read_unaligned()
+}
+
+/// 计算 `[addr, addr + len)` 中所有字节的和, 所有 ACPI 表的和都应该为 0
+unsafe fn checksum(addr: PhysAddr, len: u64) -> u8 {
+    (0..len).fold(0u8, |sum, offset| sum.wrapping_add(read::<u8>(addr + offset)))
+}
+
+/// 检查 `addr` 处的表的签名和校验和, 返回表的长度
+unsafe fn check_table(addr: PhysAddr, signature: &[u8; 4]) -> Result<u64, &'static str> {
+    if &read::<[u8; 4]>(addr) != signature {
+        return Err("table signature mismatch");
+    }
+    let len = u64::from(read::<u32>(addr + 4u64));
+    if len < SDT_HEADER_SIZE {
+        return Err("table is too short");
+    }
+    if checksum(addr, len) != 0 {
+        return Err("table checksum mismatch");
+    }
+    Ok(len)
+}
+
+/// 在 RSDT/XSDT 中查找 MADT 并解析
+unsafe fn parse_madt(rsdp_addr: PhysAddr) -> Result<Madt, &'static str> {
+    // RSDP: signature[8], checksum, oem_id[6], revision, rsdt_address: u32,
+    //       (revision >= 2) length: u32, xsdt_address: u64, extended_checksum, reserved[3]
+    if &read::<[u8; 8]>(rsdp_addr) != b"RSD PTR " || checksum(rsdp_addr, 20) != 0 {
+        return Err("invalid RSDP");
+    }
+    let revision = read::<u8>(rsdp_addr + 15u64);
+    let xsdt_addr = read::<u64>(rsdp_addr + 24u64);
+    let (root, signature, entry_size) = if revision >= 2 && xsdt_addr != 0 {
+        (PhysAddr::new(xsdt_addr), b"XSDT", 8)
+    } else {
+        (PhysAddr::new(u64::from(read::<u32>(rsdp_addr + 16u64))), b"RSDT", 4)
+    };
+
+    let root_len = check_table(root, signature)?;
+    let madt_addr = (SDT_HEADER_SIZE..root_len)
+        .step_by(entry_size)
+        .map(|offset| match entry_size {
+            8 => PhysAddr::new(read::<u64>(root + offset)),
+            _ => PhysAddr::new(u64::from(read::<u32>(root + offset))),
+        })
+        .find(|&table| read::<[u8; 4]>(table) == *b"APIC")
+        .ok_or("MADT not found")?;
+
+    let madt_len = check_table(madt_addr, b"APIC")?;
+    let mut local_apic_address = u64::from(read::<u32>(madt_addr + SDT_HEADER_SIZE));
+    let mut processors = Vec::new();
+
+    // 表头之后是 local APIC 地址 (u32) 和 flags (u32), 然后是变长的 entry: type, length, ...
+    let mut offset = SDT_HEADER_SIZE + 8;
+    while offset + 2 <= madt_len {
+        let entry = madt_addr + offset;
+        let entry_type = read::<u8>(entry);
+        let entry_len = u64::from(read::<u8>(entry + 1u64));
+        if entry_len < 2 || offset + entry_len > madt_len {
+            return Err("invalid MADT entry");
+        }
+
+        match entry_type {
+            // Processor Local APIC: acpi_id: u8, apic_id: u8, flags: u32
+            0 => processors.push(Processor {
+                acpi_id: u32::from(read::<u8>(entry + 2u64)),
+                apic_id: u32::from(read::<u8>(entry + 3u64)),
+                usable: read::<u32>(entry + 4u64) & 0b11 != 0,
+            }),
+            // Local APIC Address Override: reserved: u16, address: u64
+            5 => local_apic_address = read::<u64>(entry + 4u64),
+            // Processor Local x2APIC: reserved: u16, x2apic_id: u32, flags: u32, acpi_uid: u32
+            9 => processors.push(Processor {
+                acpi_id: read::<u32>(entry + 12u64),
+                apic_id: read::<u32>(entry + 4u64),
+                usable: read::<u32>(entry + 8u64) & 0b11 != 0,
+            }),
+            _ => {}
+        }
+        offset += entry_len;
+    }
+
+    Ok(Madt {
+        local_apic_address: PhysAddr::new(local_apic_address),
+        processors,
+    })
+}
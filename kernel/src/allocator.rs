@@ -1,3 +1,7 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+
+use linked_list_allocator::Heap;
 use x86_64::{
     structures::paging::{
         mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
@@ -9,10 +13,24 @@ const KIB: usize = 1024;
 pub const HEAP_START: usize = 0x_4_24_1BF25_0000;
 pub const HEAP_SIZE: usize = 100 * KIB;
 
-use linked_list_allocator::LockedHeap;
+use crate::sync::IrqSpinlock;
 
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: LockedHeap = LockedHeap(IrqSpinlock::new(Heap::empty()));
+
+/// 持有堆的锁时关中断: 时钟中断会抢占线程, 如果持有锁的线程被切换出去, 而下一个线程在关中断时
+/// 分配内存 (如: 持有 [`IrqSpinlock`] 时), 它就会永远自旋
+struct LockedHeap(IrqSpinlock<Heap>);
+
+unsafe impl GlobalAlloc for LockedHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.0.lock().allocate_first_fit(layout).map_or(ptr::null_mut(), NonNull::as_ptr)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.lock().deallocate(NonNull::new_unchecked(ptr), layout);
+    }
+}
 
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
@@ -37,8 +55,21 @@ pub fn init_heap(
     }
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START as _, HEAP_SIZE);
+        ALLOCATOR.0.lock().init(HEAP_START as _, HEAP_SIZE);
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// 堆的使用情况, 单位为字节
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub size: usize,
+    pub used: usize,
+    pub free: usize,
+}
+
+pub fn heap_stats() -> HeapStats {
+    let heap = ALLOCATOR.0.lock();
+    HeapStats { size: heap.size(), used: heap.used(), free: heap.free() }
+}
@@ -0,0 +1,135 @@
+//! # Local APIC
+//! 参考: Intel® 64 and IA-32 Architectures Software Developer’s Manual
+//!       Volume 3, Chapter 10 Advanced Programmable Interrupt Controller (APIC)
+//!       https://wiki.osdev.org/APIC
+//!
+//! 每个处理器都有一个 local APIC, 用于接收中断和向其它处理器发送 IPI (Inter-Processor Interrupt) 。
+//! 这里使用 xAPIC 模式, 寄存器通过 MMIO 访问, 每个处理器访问同一个物理地址时, 看到的都是自己的
+//! local APIC 。
+//!
+//! 硬件中断目前仍然由 8259 PIC 发送给 BSP (见 [`crate::interrupts`]), local APIC 只用于 IPI,
+//! 如: 启动 AP 和唤醒空闲的执行器 (见 [`crate::task::executor`]) 。
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::memory;
+
+use x86_64::{registers::model_specific::Msr, PhysAddr};
+
+/// 伪中断 (spurious interrupt) 的中断向量, 它的处理函数不需要发送 EOI
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+/// 唤醒空闲处理器上的执行器的 IPI 的中断向量
+pub const WAKEUP_VECTOR: u8 = 0xf0;
+
+/// IA32_APIC_BASE MSR, bit 11 为 APIC Global Enable
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_GLOBAL_ENABLE: u64 = 1 << 11;
+
+// 寄存器相对于 local APIC 基地址的偏移
+const REG_ID: u64 = 0x20;
+const REG_EOI: u64 = 0xb0;
+const REG_SPURIOUS: u64 = 0xf0;
+const REG_ERROR_STATUS: u64 = 0x280;
+const REG_ICR_LOW: u64 = 0x300;
+const REG_ICR_HIGH: u64 = 0x310;
+
+/// Spurious Interrupt Vector Register 中的 APIC Software Enable
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+
+// Interrupt Command Register 的各个字段
+const ICR_DELIVERY_FIXED: u32 = 0b000 << 8;
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_NMI: u32 = 0b100 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_TRIGGER_LEVEL: u32 = 1 << 15;
+/// 发送给除了自己之外的所有处理器, 忽略 ICR 中的目标
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+/// local APIC 寄存器映射到的虚拟地址, 0 表示还没有初始化
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+/// 映射 `phys` 处的 local APIC 寄存器, 并启用 BSP 的 local APIC
+///
+/// 需要在内存初始化之后调用, `phys` 通常来自 MADT (见 [`crate::acpi`]) 。
+pub fn init(phys: PhysAddr) {
+    let base = memory::map_mmio(phys, 4096).expect("failed to map local APIC registers");
+    BASE.store(base.as_u64(), Ordering::Relaxed);
+    enable();
+}
+
+/// local APIC 是否已经被初始化
+pub fn is_initialized() -> bool {
+    BASE.load(Ordering::Relaxed) != 0
+}
+
+/// 启用当前处理器的 local APIC, 每个处理器都需要调用一次
+pub fn enable() {
+    unsafe {
+        let mut apic_base = Msr::new(IA32_APIC_BASE);
+        apic_base.write(apic_base.read() | APIC_GLOBAL_ENABLE);
+
+        // 只设置 software enable 和伪中断的向量, LVT (如: BIOS 为 PIC 配置的 LINT0) 保持不变
+        write(REG_SPURIOUS, SPURIOUS_APIC_ENABLE | u32::from(SPURIOUS_VECTOR));
+    }
+}
+
+/// 当前处理器的 local APIC ID
+pub fn id() -> u32 {
+    unsafe { read(REG_ID) >> 24 }
+}
+
+/// 发送 EOI, 由 local APIC 发送的中断的处理函数调用
+pub fn end_of_interrupt() {
+    unsafe { write(REG_EOI, 0) };
+}
+
+/// 向 `apic_id` 发送 INIT IPI, 让它进入 wait-for-SIPI 状态
+pub fn send_init(apic_id: u32) {
+    send_ipi(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT | ICR_TRIGGER_LEVEL);
+    // 部分较老的处理器需要 deassert, 现代处理器会忽略它
+    send_ipi(apic_id, ICR_DELIVERY_INIT | ICR_TRIGGER_LEVEL);
+}
+
+/// 向 `apic_id` 发送 Startup IPI, 它会从物理地址 `vector * 4096` 开始以实模式运行
+pub fn send_startup(apic_id: u32, vector: u8) {
+    send_ipi(apic_id, ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | u32::from(vector));
+}
+
+/// 向 `apic_id` 发送一个普通的中断, 中断向量为 `vector`, 它的处理函数需要发送 EOI
+pub fn send_fixed(apic_id: u32, vector: u8) {
+    send_ipi(apic_id, ICR_DELIVERY_FIXED | ICR_LEVEL_ASSERT | u32::from(vector));
+}
+
+/// 向除了当前处理器之外的所有处理器发送 NMI, 它不会被关中断屏蔽
+pub fn send_nmi_to_others() {
+    send_ipi(0, ICR_DELIVERY_NMI | ICR_LEVEL_ASSERT | ICR_ALL_EXCLUDING_SELF);
+}
+
+/// 写 ICR 发送 IPI, 并等待它被发送出去
+///
+/// 中断处理函数也会发送 IPI, 所以写 ICR 时需要关中断, 防止两次写入交错。
+fn send_ipi(apic_id: u32, command: u32) {
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        write(REG_ERROR_STATUS, 0);
+        // 写 ICR 的低 32 位时才会发送, 所以先写目标
+        write(REG_ICR_HIGH, apic_id << 24);
+        write(REG_ICR_LOW, command);
+        while read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+unsafe fn read(reg: u64) -> u32 {
+    let base = BASE.load(Ordering::Relaxed);
+    debug_assert!(base != 0, "local APIC not initialized");
+    ((base + reg) as *const u32).read_volatile()
+}
+
+unsafe fn write(reg: u64, value: u32) {
+    let base = BASE.load(Ordering::Relaxed);
+    debug_assert!(base != 0, "local APIC not initialized");
+    ((base + reg) as *mut u32).write_volatile(value);
+}
@@ -0,0 +1,45 @@
+# syscall 指令的入口, 见 src/syscall.rs
+#
+# 进入时: rcx = 用户程序的 rip, r11 = 用户程序的 rflags, rsp 仍然是用户栈,
+#        rax = 系统调用号, rdi, rsi, rdx, r10, r8, r9 = 参数
+# 返回时: rax = 返回值, 其余寄存器 (rcx 和 r11 除外) 保持不变
+#
+# gs:[8] 和 gs:[16] 分别是 PerCpu 中的 syscall_kernel_stack 和 syscall_user_stack, 见 src/percpu.rs
+.global syscall_entry
+syscall_entry:
+    # 切换到内核的 GS base, 然后切换到当前任务的内核栈 (SFMASK 会清除 IF, 所以这里不会被中断)
+    swapgs
+    mov gs:[16], rsp
+    mov rsp, gs:[8]
+
+    push qword ptr gs:[16]
+    push rcx
+    push r11
+
+    # 构造 SyscallFrame
+    push r9
+    push r8
+    push r10
+    push rdx
+    push rsi
+    push rdi
+    push rax
+
+    sti
+    mov rdi, rsp
+    call syscall_handler
+    cli
+
+    add rsp, 8      # rax 中是返回值
+    pop rdi
+    pop rsi
+    pop rdx
+    pop r10
+    pop r8
+    pop r9
+
+    pop r11
+    pop rcx
+    pop rsp
+    swapgs
+    sysretq
@@ -0,0 +1,34 @@
+# 线程的上下文切换, 见 src/thread/mod.rs
+
+# switch_context(old_rsp: *mut u64 (rdi), new_rsp: u64 (rsi))
+#
+# 保存 callee-saved 寄存器和 rflags 到当前栈上, 把栈指针保存到 [rdi],
+# 然后切换到新线程的栈, 恢复它保存的寄存器并返回到新线程中。
+.global switch_context
+switch_context:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    pushfq
+
+    mov [rdi], rsp
+    mov rsp, rsi
+
+    popfq
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+
+# 新线程第一次被切换到时, switch_context 会 "返回" 到这里, r12 中保存的是线程的 id
+.global thread_trampoline
+thread_trampoline:
+    mov rdi, r12
+    call thread_start
+    ud2
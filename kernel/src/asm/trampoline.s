@@ -0,0 +1,96 @@
+# AP 的启动代码, 见 src/smp.rs
+#
+# 这段代码会被复制到 1 MiB 以下的一页中 (物理地址为 P, 且被恒等映射), AP 收到 SIPI 后从 P 开始
+# 以实模式运行, 此时 cs = P >> 4, ip = 0 。代码直接从实模式进入 long mode (同时开启保护模式和分页),
+# 然后切换到内核为它分配的栈, 调用 ap_trampoline_params 中的入口函数。
+#
+# 进入 long mode 之前只能使用相对于 P 的偏移 (ds = cs), 之后可以使用 RIP 相对寻址。
+
+# 各个标签相对于 ap_trampoline_start 的偏移
+.set TRAMPOLINE_GDT, ap_trampoline_gdt - ap_trampoline_start
+.set TRAMPOLINE_GDT_POINTER, ap_trampoline_gdt_pointer - ap_trampoline_start
+.set TRAMPOLINE_LONG_MODE, ap_trampoline_long_mode - ap_trampoline_start
+.set TRAMPOLINE_FAR_POINTER, ap_trampoline_far_pointer - ap_trampoline_start
+.set TRAMPOLINE_PARAMS, ap_trampoline_params - ap_trampoline_start
+
+.code16
+.global ap_trampoline_start
+ap_trampoline_start:
+    cli
+    cld
+    mov ax, cs
+    mov ds, ax
+
+    # 计算 GDT 和 64 位代码的线性地址 (P + 偏移), 写入 GDT 指针和远跳转的目标中
+    xor ebx, ebx
+    mov bx, ax
+    shl ebx, 4
+    lea eax, [ebx + TRAMPOLINE_GDT]
+    mov dword ptr [TRAMPOLINE_GDT_POINTER + 2], eax
+    lea eax, [ebx + TRAMPOLINE_LONG_MODE]
+    mov dword ptr [TRAMPOLINE_FAR_POINTER], eax
+    lgdt [TRAMPOLINE_GDT_POINTER]
+
+    # CR4.PAE
+    mov eax, cr4
+    or eax, 1 << 5
+    mov cr4, eax
+
+    # 内核的 4 级页表, 物理地址必须低于 4 GiB
+    mov eax, dword ptr [TRAMPOLINE_PARAMS]
+    mov cr3, eax
+
+    # EFER.LME 和 EFER.NXE (内核的页表使用了 NO_EXECUTE)
+    mov ecx, 0xC0000080
+    rdmsr
+    or eax, (1 << 8) | (1 << 11)
+    wrmsr
+
+    # 同时开启 CR0.PE, CR0.WP 和 CR0.PG
+    mov eax, cr0
+    or eax, 0x80010001
+    mov cr0, eax
+
+    # jmp far dword ptr [ap_trampoline_far_pointer], 加载 64 位的代码段
+    .byte 0x66, 0xff, 0x2e
+    .word TRAMPOLINE_FAR_POINTER
+
+.code64
+ap_trampoline_long_mode:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    xor ax, ax
+    mov fs, ax
+    mov gs, ax
+
+    # TrampolineParams: page_table, stack_top, entry, cpu
+    mov rsp, qword ptr [rip + ap_trampoline_params + 8]
+    mov rax, qword ptr [rip + ap_trampoline_params + 16]
+    mov rdi, qword ptr [rip + ap_trampoline_params + 24]
+    call rax
+    ud2
+
+.align 8
+ap_trampoline_gdt:
+    .quad 0
+    .quad 0x00af9a000000ffff    # 0x08: 64 位代码段
+    .quad 0x00cf92000000ffff    # 0x10: 数据段
+ap_trampoline_gdt_pointer:
+    .word ap_trampoline_gdt_pointer - ap_trampoline_gdt - 1
+    .long 0
+ap_trampoline_far_pointer:
+    .long 0
+    .word 0x08
+
+.align 8
+.global ap_trampoline_params
+ap_trampoline_params:
+    .quad 0
+    .quad 0
+    .quad 0
+    .quad 0
+
+.global ap_trampoline_end
+ap_trampoline_end:
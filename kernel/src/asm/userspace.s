@@ -0,0 +1,65 @@
+# 进入 / 离开用户态 (ring 3), 见 src/userspace.rs
+
+# enter_user_mode(entry: rdi, user_stack: rsi, user_cs: rdx, user_ss: rcx, kernel_rsp: r8)
+#
+# 保存 callee-saved 寄存器, 并把此时的栈指针写入 [r8], 然后通过 iretq 跳转到 ring 3 。
+# iretq 之前需要 swapgs 切换到用户程序的 GS base, 并在此之前关中断 (RFLAGS 由 iretq 恢复) 。
+# 之后通过 iretq 进入 return_from_user 时, 栈指针必须被恢复为 [r8] 中保存的值。
+.global enter_user_mode
+enter_user_mode:
+    push rbx
+    push rbp
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [r8], rsp
+
+    # 构造 iretq 需要的栈帧
+    push rcx      # SS
+    push rsi      # RSP
+    push 0x202    # RFLAGS (IF = 1)
+    push rdx      # CS
+    push rdi      # RIP
+
+    # 不要把内核中的数据泄露给用户程序
+    xor eax, eax
+    xor ebx, ebx
+    xor ecx, ecx
+    xor edx, edx
+    xor esi, esi
+    xor edi, edi
+    xor ebp, ebp
+    xor r8d, r8d
+    xor r9d, r9d
+    xor r10d, r10d
+    xor r11d, r11d
+    xor r12d, r12d
+    xor r13d, r13d
+    xor r14d, r14d
+    xor r15d, r15d
+
+    cli
+    swapgs
+    iretq
+
+# 用户程序结束后, 从这里返回到 enter_user_mode 的调用者
+.global return_from_user
+return_from_user:
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbp
+    pop rbx
+    ret
+
+# exit_to_kernel(kernel_rsp: rdi, kernel_rflags: rsi) -> !
+#
+# 在内核态 (如: 系统调用中) 结束用户程序, 恢复 enter_user_mode 保存的栈指针后返回
+.global exit_to_kernel
+exit_to_kernel:
+    mov rsp, rdi
+    push rsi
+    popfq
+    jmp return_from_user
@@ -57,66 +57,194 @@
 //! 由于历史遗留问题, 需要在 GDT 中创建一个 selector 指向 TSS ,
 //! 然后再用 ltr 指令将这个 selector 加载到 task registor 中。
 //! 这样就可以使用 TSS 。
+//! 
+//! ### IST 栈的分配
+//! IST 栈通过 [`memory::alloc_stack`] 动态分配, 每个栈下方都有一页不映射的 guard page,
+//! 所以中断处理函数自身的栈溢出也会触发 Page Fault, 而不会悄悄覆盖其它内存。
+//! 
+//! # 特权级切换
+//! 在 ring 3 下发生中断时, CPU 会切换到 TSS 中 Privilege Stack Table 的第 0 项 (RSP0)
+//! 指定的栈, 再调用中断处理函数。所以在进入用户态之前, 需要通过 [`set_kernel_stack`] 设置好 RSP0 。
+//! 
+//! # 多处理器
+//! 每个处理器都需要自己的 TSS (各自的 IST 栈和 RSP0), 所以每个 AP 都有一份自己的 GDT,
+//! 见 [`init_ap`] 。所有 GDT 的布局都相同, 所以 [`selectors`] 对所有处理器都有效。
+//! 当前处理器的 TSS 保存在 per-CPU 数据中 (见 [`crate::percpu`]), [`set_kernel_stack`] 和
+//! [`kernel_stack`] 作用于当前处理器的 TSS 。
+//! 
+//! GDT 中段的顺序是按照 `syscall`/`sysret` 的要求排列的:
+//! 
+//! ```text
+//!  Index      Segment
+//!    0         null
+//!    1      kernel code
+//!    2      kernel data
+//!    3       user data
+//!    4       user code
+//!   5-6         TSS
+//! ```
 //!  
 
 
+use core::cell::UnsafeCell;
+
+use crate::memory::{self, Stack};
+
+use alloc::boxed::Box;
+
 use x86_64::VirtAddr;
 use x86_64::structures::gdt::{GlobalDescriptorTable, SegmentSelector, Descriptor};
 use x86_64::structures::tss::TaskStateSegment;
 use lazy_static::lazy_static;
 
+/// 内核栈溢出时, CPU 无法在 guard page 上压入 Page Fault 的栈帧, 会触发 double fault,
+/// 所以 double fault 需要一个独立的栈。Page Fault 使用当前的栈, 这样它可以嵌套
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// NMI 可能在任意时刻 (包括在切换栈的过程中) 到来, 所以需要一个独立的栈
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+pub const DEBUG_IST_INDEX: u16 = 3;
+
+/// IST 栈的数量
+pub const IST_STACK_COUNT: usize = 4;
+/// 每个 IST 栈的大小 (页数)
+const IST_STACK_PAGES: u64 = 5;
+/// 默认的 RSP0 栈的大小 (页数)
+const PRIVILEGE_STACK_PAGES: u64 = 5;
+
+/// 一个处理器的 TSS 和它的 IST 栈
+///
+/// CPU 在运行时会读取 TSS (如: RSP0), 而 RSP0 需要在切换任务时修改, 所以用 `UnsafeCell` 包装
+pub struct Tss {
+    tss: UnsafeCell<TaskStateSegment>,
+    ist_stacks: [Stack; IST_STACK_COUNT],
+}
+
+// TSS 只会被它所属的处理器在关中断的情况下修改, 见 [`set_kernel_stack`]
+unsafe impl Sync for Tss {}
+
+lazy_static! {
+    static ref TSS: Tss = Tss::new();
+}
 
 lazy_static! {
-    static ref TSS: TaskStateSegment = {
+    static ref GDT: (GlobalDescriptorTable, Selectors) = new_gdt(&TSS);
+}
+
+impl Tss {
+    /// 创建一个新的 TSS, 并为它分配 RSP0 和所有 IST 栈
+    fn new() -> Self {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            // 创建一个静态的栈 (16 字节对齐)
-            #[repr(align(16))]
-            struct Stack([u8; STACK_SIZE]);
+        let privilege_stack = memory::alloc_stack(PRIVILEGE_STACK_PAGES)
+            .expect("failed to allocate privilege stack");
+        tss.privilege_stack_table[0] = privilege_stack.end();
+        // 下标与 *_IST_INDEX 相同
+        let ist_stacks = [(); IST_STACK_COUNT].map(|_| {
+            memory::alloc_stack(IST_STACK_PAGES).expect("failed to allocate IST stack")
+        });
+        for (index, stack) in ist_stacks.iter().enumerate() {
+            tss.interrupt_stack_table[index] = stack.end();
+        }
 
-            static mut STACK: Stack = Stack([0; STACK_SIZE]);
+        Tss { tss: UnsafeCell::new(tss), ist_stacks }
+    }
 
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
+    /// IST 栈, 下标为 `*_IST_INDEX`
+    pub fn ist_stacks(&self) -> &[Stack; IST_STACK_COUNT] {
+        &self.ist_stacks
+    }
 
-            stack_end
-        };
+    /// TSS 中的 RSP0
+    pub fn kernel_stack(&self) -> VirtAddr {
+        x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+            (*self.tss.get()).privilege_stack_table[0]
+        })
+    }
 
-        tss
-    };
+    /// 只能由 TSS 所属的处理器调用
+    fn set_kernel_stack(&self, stack_top: VirtAddr) {
+        x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+            (*self.tss.get()).privilege_stack_table[0] = stack_top;
+        });
+    }
 }
 
-lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-
-        (gdt, Selectors { code_selector, data_selector, tss_selector })
-    };
+fn new_gdt(tss: &'static Tss) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*tss.tss.get() }));
+
+    (gdt, Selectors {
+        code_selector,
+        data_selector,
+        user_data_selector,
+        user_code_selector,
+        tss_selector,
+    })
 }
 
 /// 64 位段选择子
-struct Selectors {
-    code_selector: SegmentSelector,
-    data_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub code_selector: SegmentSelector,
+    pub data_selector: SegmentSelector,
+    /// RPL 为 3 的用户数据段选择子
+    pub user_data_selector: SegmentSelector,
+    /// RPL 为 3 的用户代码段选择子
+    pub user_code_selector: SegmentSelector,
+    pub tss_selector: SegmentSelector,
+}
+
+/// 返回 GDT 中各个段的选择子
+pub fn selectors() -> &'static Selectors {
+    &GDT.1
+}
+
+/// 设置当前处理器的 TSS 中的 RSP0, 即从 ring 3 进入中断处理函数时使用的内核栈,
+/// 系统调用也会使用这个栈
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        percpu!(tss).set_kernel_stack(stack_top);
+        percpu!(syscall_kernel_stack).set(stack_top.as_u64());
+    });
+}
+
+/// 返回当前处理器的 TSS 中的 RSP0
+pub fn kernel_stack() -> VirtAddr {
+    percpu!(tss).kernel_stack()
+}
+
+/// 加载 BSP 的 GDT 和 TSS, 返回 TSS 供 [`crate::percpu::init`] 使用
+pub fn init() -> &'static Tss {
+    load(&GDT);
+    &TSS
+}
+
+/// 为当前 AP 创建并加载自己的 GDT 和 TSS, 由 [`crate::smp`] 在 AP 启动时调用
+///
+/// GDT 和 TSS 永远不会被释放。
+pub fn init_ap() -> &'static Tss {
+    let tss: &'static Tss = Box::leak(Box::new(Tss::new()));
+    let gdt: &'static (GlobalDescriptorTable, Selectors) = Box::leak(Box::new(new_gdt(tss)));
+    load(gdt);
+    tss
 }
 
-pub fn init() {
+fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
     use x86_64::instructions::tables::load_tss;
     use x86_64::instructions::segmentation::{CS, DS, ES, GS, FS, Segment};
     
-    GDT.0.load();
+    gdt.0.load();
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        DS::set_reg(GDT.1.data_selector);
-        ES::set_reg(GDT.1.data_selector);
-        GS::set_reg(GDT.1.data_selector);
-        FS::set_reg(GDT.1.data_selector);
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(gdt.1.code_selector);
+        DS::set_reg(gdt.1.data_selector);
+        ES::set_reg(gdt.1.data_selector);
+        // 加载 GS 会把 GS base 清零, 所以 per-CPU 数据需要在这之后初始化
+        GS::set_reg(gdt.1.data_selector);
+        FS::set_reg(gdt.1.data_selector);
+        load_tss(gdt.1.tss_selector);
     }
 }
@@ -105,11 +105,24 @@
 //! extern "x86-interrupt"
 //! ```
 //! 
-use crate::gdt;
+//! # 设备的中断
+//! IDT 中只有 CPU 异常、时钟中断和 local APIC 使用的中断向量有固定的处理函数, 其它的中断向量都
+//! 由 [`dynamic_interrupt_handler`] 转发给驱动注册的处理函数, 见 [`crate::irq`] 。
+//! 
+//! # GS base
+//! 中断发生在用户态时, GS base 是用户程序的, 所以处理函数需要先创建 [`InterruptGuard`],
+//! 它会执行 `swapgs` 并记录中断嵌套的深度, 见 [`crate::percpu`] 。
+//! 
+use crate::{apic, gdt, irq, memory};
+use crate::percpu::InterruptGuard;
+use crate::userspace::{self, UserExit};
 
 use spin;
+use boot_info::KernelStack;
 use pic8259::ChainedPics;
 use lazy_static::lazy_static;
+use conquer_once::spin::OnceCell;
+use x86_64::set_general_handler;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 
 /// 预定义的 CPU Exception 已经占了 0 - 31 , 所以从 32 开始
@@ -124,10 +137,12 @@ pub static PICS: spin::Mutex<ChainedPics> =
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    /// IRQ 12, PS/2 鼠标
+    Mouse = PIC_2_OFFSET + 4,
 }
 
 impl InterruptIndex {
-    fn as_u8(self) -> u8 {
+    pub fn as_u8(self) -> u8 {
         self as u8
     }
 
@@ -136,20 +151,32 @@ impl InterruptIndex {
     }
 }
 
+/// 内核栈的范围, 用于判断 double fault 是否是由内核栈溢出导致的
+static KERNEL_STACK: OnceCell<KernelStack> = OnceCell::uninit();
+
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
         idt.general_protection_fault.set_handler_fn(general_protection_handler);
+        idt.page_fault.set_handler_fn(page_fault_handler);
         
         unsafe {
             idt.double_fault.set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            idt.non_maskable_interrupt.set_handler_fn(nmi_handler)
+                .set_stack_index(gdt::NMI_IST_INDEX);
+            idt.machine_check.set_handler_fn(machine_check_handler)
+                .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+            idt.debug.set_handler_fn(debug_handler)
+                .set_stack_index(gdt::DEBUG_IST_INDEX);
         }
 
+        // 先让所有的中断向量都指向动态注册的处理函数, 再设置有固定处理函数的中断向量
+        set_general_handler!(&mut idt, dynamic_interrupt_handler, PIC_1_OFFSET..=255);
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[usize::from(apic::WAKEUP_VECTOR)].set_handler_fn(wakeup_interrupt_handler);
+        idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
 
         idt
     };
@@ -158,30 +185,80 @@ lazy_static! {
 extern "x86-interrupt" fn breakpoint_handler(
     stack_frame: InterruptStackFrame)
 {
+    let _guard = unsafe { InterruptGuard::enter(&stack_frame) };
     log::debug!("BREAKPOINT\n{:#?}", stack_frame);
 }
 
+extern "x86-interrupt" fn debug_handler(
+    stack_frame: InterruptStackFrame)
+{
+    let _guard = unsafe { InterruptGuard::enter(&stack_frame) };
+    log::debug!("DEBUG\n{:#?}", stack_frame);
+}
+
+/// NMI 通常意味着硬件错误 (如: 内存奇偶校验错误) 或看门狗超时, 重启和关机时也用它让其它处理器停机
+/// (见 [`crate::power`])
+///
+/// NMI 可能发生在 `swapgs` 与 `iretq` 之间, 所以不能使用 [`InterruptGuard`]
+extern "x86-interrupt" fn nmi_handler(
+    stack_frame: InterruptStackFrame)
+{
+    crate::power::stop_if_requested();
+    log::warn!("NON-MASKABLE INTERRUPT\n{:#?}", stack_frame);
+}
+
+/// machine check 是不可恢复的硬件错误, 与 NMI 一样不能使用 [`InterruptGuard`]
+extern "x86-interrupt" fn machine_check_handler(
+    stack_frame: InterruptStackFrame) -> !
+{
+    panic!("MACHINE CHECK\n{:#?}", stack_frame);
+}
+
 extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
+    mut stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode
 )
 {
+    let _guard = unsafe { InterruptGuard::enter(&stack_frame) };
     use x86_64::registers::control::Cr2;
     // The CR2 register is automatically set by the CPU on a page fault 
     // and contains the accessed virtual address that caused the page fault. 
+    let addr = Cr2::read();
+    if userspace::is_user_mode(&stack_frame) {
+        log::warn!(concat!(
+            "user program killed by PAGE FAULT\n",
+            "Accessed Address: {:?}\n",
+            "Error Code: {:?}\n",
+            "{:#?}"
+        ), addr, error_code, stack_frame);
+
+        let exit = UserExit::PageFault { addr, error_code };
+        unsafe { userspace::exit_from_interrupt(&mut stack_frame, exit) };
+        return;
+    }
+
     log::error!(concat!(
         "EXCEPTION: PAGE FAULT\n",
         "Accessed Address: {:?}\n",
         "Error Code: {:?}\n",
         "{:#?}"
-    ), Cr2::read(), error_code, stack_frame);
+    ), addr, error_code, stack_frame);
     
     crate::hlt_loop();
 }
 
 extern "x86-interrupt" fn general_protection_handler(
-    stack_frame: InterruptStackFrame, error_code: u64)
+    mut stack_frame: InterruptStackFrame, error_code: u64)
 {
+    let _guard = unsafe { InterruptGuard::enter(&stack_frame) };
+    if userspace::is_user_mode(&stack_frame) {
+        log::warn!("user program killed by GENERAL PROTECTION FAULT\n{:#?}\nerror_code: {}", stack_frame, error_code);
+
+        let exit = UserExit::GeneralProtectionFault { error_code };
+        unsafe { userspace::exit_from_interrupt(&mut stack_frame, exit) };
+        return;
+    }
+
     panic!("General protection\n{:#?}\nerror_code: {}", stack_frame, error_code);
 }
 
@@ -205,41 +282,74 @@ extern "x86-interrupt" fn general_protection_handler(
 /// | ------------------------ | ------------------------ |
 /// ```
 /// 
-/// double fault 的 error code 恒为 0
+/// 内核栈溢出时, CPU 在 guard page 上压入 Page Fault 的栈帧会再次触发 Page Fault, 于是触发
+/// double fault 。这时 CR2 仍然是 guard page 中的地址, 据此判断是否发生了栈溢出。
+///
+/// double fault 的 error code 恒为 0, 不返回, 所以也不使用 [`InterruptGuard`]
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame, _error_code: u64) -> !
 {
+    use x86_64::registers::control::Cr2;
+    let addr = Cr2::read();
+    let boot_stack_overflow = KERNEL_STACK
+        .get()
+        .is_some_and(|stack| stack.guard_page_contains(addr.as_u64()));
+    if boot_stack_overflow || memory::is_stack_guard_page(addr) {
+        panic!("DOUBLE FAULT (kernel stack overflow)\nAccessed Address: {:?}\n{:#?}", addr, stack_frame);
+    }
     panic!("DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn timer_interrupt_handler(
-    _stack_frame: InterruptStackFrame)
+    mut stack_frame: InterruptStackFrame)
 {
-    print!(".");
+    let mut guard = unsafe { InterruptGuard::enter(&stack_frame) };
+    irq::record(InterruptIndex::Timer.as_u8());
+    crate::time::tick();
+
+    if userspace::is_user_mode(&stack_frame) && crate::process::kill_pending() {
+        unsafe { userspace::exit_from_interrupt(&mut stack_frame, UserExit::Killed) };
+    }
 
-    // 发送 EOI 信号
+    // 发送 EOI 信号, 需要在切换线程之前发送, 否则在被切换回来之前不会再有时钟中断
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
+
+    // 切换到的线程不一定处于中断中, 切换之后 GS base 仍然是内核的, 返回前才恢复
+    guard.leave();
+    crate::thread::tick();
+    drop(guard);
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use x86_64::instructions::port::Port;
+/// 没有固定处理函数的中断向量, 调用 [`irq::register_vector`] 注册的处理函数并发送 EOI,
+/// PIC 的伪中断也会到达这里
+fn dynamic_interrupt_handler(stack_frame: InterruptStackFrame, index: u8, _error_code: Option<u64>) {
+    let _guard = unsafe { InterruptGuard::enter(&stack_frame) };
+    irq::dispatch(index);
+}
 
-    // https://wiki.osdev.org/%228042%22_PS/2_Controller#Data_Port
-    const PS2_CONTROLLER_DATA_PORT: u16 = 0x60;
+/// 其它处理器放入了新的任务, 唤醒这个处理器上的执行器, 见 [`crate::task::executor`]
+extern "x86-interrupt" fn wakeup_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _guard = unsafe { InterruptGuard::enter(&stack_frame) };
+    irq::record(apic::WAKEUP_VECTOR);
+    crate::task::executor::wake_local();
+    apic::end_of_interrupt();
+}
 
-    let mut port = Port::new(PS2_CONTROLLER_DATA_PORT);
-    let scancode: u8 = unsafe { port.read() };
-    crate::task::keyboard::add_scancode(scancode);
+/// local APIC 的伪中断, 不需要发送 EOI
+extern "x86-interrupt" fn spurious_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _guard = unsafe { InterruptGuard::enter(&stack_frame) };
+    irq::record_spurious();
+}
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+pub fn init_idt(kernel_stack: KernelStack) {
+    KERNEL_STACK.init_once(|| kernel_stack);
+    IDT.load();
 }
 
-pub fn init_idt() {
+/// 在 AP 上加载 IDT, 所有处理器共用同一个 IDT, IST 栈由各自的 TSS 提供
+pub fn init_idt_ap() {
     IDT.load();
 }
\ No newline at end of file
@@ -0,0 +1,409 @@
+//! # 动态注册的中断处理函数
+//!
+//! 除了 CPU 异常和几个内核自己使用的中断向量 (见 [`is_reserved`]), IDT 中所有的中断向量都指向
+//! 同一个分发函数 [`dispatch`], 它依次调用注册在这个向量上的处理函数, 然后发送 EOI 。
+//! 驱动不需要修改 [`crate::interrupts`], 只需要注册自己的处理函数:
+//!
+//! ```ignore
+//! irq::register_irq(1, "keyboard", Sharing::Exclusive, || {
+//!     let scancode: u8 = unsafe { Port::new(0x60).read() };
+//!     ...
+//!     IrqReturn::Handled
+//! })?;
+//! ```
+//!
+//! - [`register_irq`] 注册 8259 PIC 的 IRQ 线 (0 - 15), 并在 PIC 中取消屏蔽这条线
+//! - [`register_vector`] 注册任意一个中断向量, 如: 通过 IPI 或 MSI 发送的中断
+//! - [`allocate_vector`] 分配一个空闲的中断向量
+//!
+//! ## 共享的中断线
+//! 多个设备可以共享同一条中断线, 这时所有的处理函数都需要用 [`Sharing::Shared`] 注册。
+//! 中断发生时每个处理函数都会被调用, 它们需要自己检查中断是否来自自己的设备, 并返回
+//! [`IrqReturn::Handled`] 或 [`IrqReturn::NotHandled`] 。
+//!
+//! ## EOI
+//! 处理函数不需要发送 EOI: PIC 的中断向量 ([`crate::interrupts::PIC_1_OFFSET`] 开始的 16 个)
+//! 向 PIC 发送, 其它的向 local APIC 发送。
+//!
+//! ## 统计
+//! 每个处理器都会记录每个中断向量发生的次数, [`stats`] 返回与 `/proc/interrupts` 类似的表格,
+//! [`log_stats`] 把它输出到日志。
+//!
+//! PIC 的 IRQ 7 和 IRQ 15 可能是伪中断 (spurious IRQ), 分发前会读取 PIC 的 ISR 来判断, 伪中断
+//! 不会调用处理函数, 也不会发送错误的 EOI, 只被记录在伪中断的次数中。
+//!
+//! ## 中断安全
+//! 处理函数在中断处理函数中被调用, 不能等待, 也不能分配或释放内存。它被调用时持有这个中断向量的
+//! 锁, 所以也不能注册或注销同一个中断向量上的处理函数。
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::{boxed::Box, format, string::String, vec::Vec};
+use x86_64::instructions::{interrupts, port::Port};
+
+use crate::apic;
+use crate::interrupts::{InterruptIndex, PICS, PIC_1_OFFSET};
+use crate::percpu::{self, PerCpu};
+use crate::sync::IrqSpinlock;
+
+/// 8259 PIC 的 IRQ 线的数量
+pub const PIC_IRQS: u8 = 16;
+/// 从 secondary PIC 连接到 primary PIC 的 IRQ 线, 它本身不会产生中断
+const CASCADE_IRQ: u8 = 2;
+/// [`allocate_vector`] 分配的中断向量的范围, 在 PIC 之后, 内核使用的 IPI 之前
+const DYNAMIC_VECTORS: core::ops::Range<u8> = PIC_1_OFFSET + PIC_IRQS..apic::WAKEUP_VECTOR;
+
+/// 处理函数是否处理了这次中断
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqReturn {
+    Handled,
+    /// 中断不是来自这个处理函数的设备, 只在共享的中断线上有意义
+    NotHandled,
+}
+
+/// 中断向量是否可以被其它处理函数共享, 同一个中断向量上的处理函数必须一致
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sharing {
+    Exclusive,
+    Shared,
+}
+
+/// 注册处理函数失败的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    /// CPU 异常或内核自己使用的中断向量, 见 [`is_reserved`]
+    Reserved(u8),
+    /// 中断向量已经被独占, 或与已经注册的处理函数的 [`Sharing`] 不一致
+    Busy(u8),
+    /// 不存在的 IRQ 线
+    InvalidIrq(u8),
+    /// 没有空闲的中断向量了
+    NoFreeVector,
+}
+
+impl fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegisterError::Reserved(vector) => write!(f, "interrupt vector {:#x} is reserved", vector),
+            RegisterError::Busy(vector) => write!(f, "interrupt vector {:#x} is already in use", vector),
+            RegisterError::InvalidIrq(irq) => write!(f, "there is no IRQ {}", irq),
+            RegisterError::NoFreeVector => write!(f, "no free interrupt vector"),
+        }
+    }
+}
+
+/// 注册的处理函数的句柄, 用于 [`unregister`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandlerId {
+    vector: u8,
+    id: u64,
+}
+
+impl HandlerId {
+    /// 处理函数所在的中断向量
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+}
+
+type Handler = Box<dyn Fn() -> IrqReturn + Send + Sync>;
+
+struct Entry {
+    id: u64,
+    name: &'static str,
+    handler: Handler,
+}
+
+/// 一个中断向量上注册的处理函数
+struct Line {
+    sharing: Sharing,
+    entries: Vec<Entry>,
+}
+
+static LINES: [IrqSpinlock<Line>; 256] =
+    [const { IrqSpinlock::new(Line { sharing: Sharing::Exclusive, entries: Vec::new() }) }; 256];
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// PIC 的 IRQ 线对应的中断向量
+pub const fn irq_vector(irq: u8) -> u8 {
+    PIC_1_OFFSET + irq
+}
+
+/// 中断向量是否来自 PIC, 是时返回 IRQ 线
+fn pic_irq(vector: u8) -> Option<u8> {
+    vector.checked_sub(PIC_1_OFFSET).filter(|&irq| irq < PIC_IRQS)
+}
+
+/// 不能注册处理函数的中断向量:
+/// CPU 异常 (0 - 31), 时钟中断 (切换线程, 见 [`crate::interrupts`]), PIC 的级联线,
+/// 以及 local APIC 的唤醒 IPI 和伪中断
+pub fn is_reserved(vector: u8) -> bool {
+    vector < PIC_1_OFFSET
+        || vector == InterruptIndex::Timer.as_u8()
+        || vector == irq_vector(CASCADE_IRQ)
+        || vector == apic::WAKEUP_VECTOR
+        || vector == apic::SPURIOUS_VECTOR
+}
+
+/// 在 PIC 的 IRQ 线 `irq` 上注册处理函数, 并在 PIC 中取消屏蔽这条线
+pub fn register_irq<F>(irq: u8, name: &'static str, sharing: Sharing, handler: F) -> Result<HandlerId, RegisterError>
+where
+    F: Fn() -> IrqReturn + Send + Sync + 'static,
+{
+    if irq >= PIC_IRQS {
+        return Err(RegisterError::InvalidIrq(irq));
+    }
+    register_vector(irq_vector(irq), name, sharing, handler)
+}
+
+/// 在中断向量 `vector` 上注册处理函数, 是 PIC 的中断向量时也会取消屏蔽对应的 IRQ 线
+pub fn register_vector<F>(vector: u8, name: &'static str, sharing: Sharing, handler: F) -> Result<HandlerId, RegisterError>
+where
+    F: Fn() -> IrqReturn + Send + Sync + 'static,
+{
+    if is_reserved(vector) {
+        return Err(RegisterError::Reserved(vector));
+    }
+    let mut handler = Some(Box::new(handler) as Handler);
+    let id = try_register(vector, name, sharing, &mut handler)?;
+    if let Some(irq) = pic_irq(vector) {
+        set_masked(irq, false);
+    }
+    Ok(id)
+}
+
+/// 在一个空闲的中断向量上注册处理函数, 通过 [`HandlerId::vector`] 取得分配到的中断向量
+///
+/// 分配到的中断向量不属于 PIC, 需要由设备 (如: MSI) 或 IPI 发送, EOI 发送给 local APIC 。
+pub fn allocate_vector<F>(name: &'static str, handler: F) -> Result<HandlerId, RegisterError>
+where
+    F: Fn() -> IrqReturn + Send + Sync + 'static,
+{
+    let mut handler = Some(Box::new(handler) as Handler);
+    DYNAMIC_VECTORS
+        .filter(|&vector| !is_reserved(vector))
+        .find_map(|vector| try_register(vector, name, Sharing::Exclusive, &mut handler).ok())
+        .ok_or(RegisterError::NoFreeVector)
+}
+
+/// 在 `vector` 上注册 `handler`, 成功时取走它
+fn try_register(
+    vector: u8,
+    name: &'static str,
+    sharing: Sharing,
+    handler: &mut Option<Handler>,
+) -> Result<HandlerId, RegisterError> {
+    let mut line = LINES[usize::from(vector)].lock();
+    if !line.entries.is_empty() && (line.sharing == Sharing::Exclusive || sharing == Sharing::Exclusive) {
+        return Err(RegisterError::Busy(vector));
+    }
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let handler = handler.take().expect("handler already registered");
+    line.sharing = sharing;
+    line.entries.push(Entry { id, name, handler });
+    Ok(HandlerId { vector, id })
+}
+
+/// 注销处理函数, 处理函数已经被注销时返回 `false`
+///
+/// PIC 的 IRQ 线上没有处理函数时会被重新屏蔽。
+pub fn unregister(id: HandlerId) -> bool {
+    let entry = {
+        let mut line = LINES[usize::from(id.vector)].lock();
+        let index = match line.entries.iter().position(|entry| entry.id == id.id) {
+            Some(index) => index,
+            None => return false,
+        };
+        let entry = line.entries.remove(index);
+        if line.entries.is_empty() {
+            if let Some(irq) = pic_irq(id.vector) {
+                set_masked(irq, true);
+            }
+        }
+        entry
+    };
+    // 在锁外释放处理函数
+    drop(entry);
+    true
+}
+
+/// 在中断向量 `vector` 上注册的处理函数的名字
+pub fn handler_names(vector: u8) -> Vec<&'static str> {
+    LINES[usize::from(vector)].lock().entries.iter().map(|entry| entry.name).collect()
+}
+
+/// 屏蔽或取消屏蔽 PIC 的 IRQ 线, 取消屏蔽 secondary PIC 的线时也会取消屏蔽级联线
+fn set_masked(irq: u8, masked: bool) {
+    // 中断处理函数会获取 PICS 的锁
+    interrupts::without_interrupts(|| {
+        let mut pics = PICS.lock();
+        let mut masks = unsafe { pics.read_masks() };
+        let (mask, bit) = if irq < 8 { (&mut masks[0], irq) } else { (&mut masks[1], irq - 8) };
+        if masked {
+            *mask |= 1 << bit;
+        } else {
+            *mask &= !(1 << bit);
+            if irq >= 8 {
+                masks[0] &= !(1 << CASCADE_IRQ);
+            }
+        }
+        unsafe { pics.write_masks(masks[0], masks[1]) };
+    });
+}
+
+/// 调用 `vector` 上注册的处理函数, 然后发送 EOI, 由 [`crate::interrupts`] 中的通用处理函数调用
+pub(crate) fn dispatch(vector: u8) {
+    if let Some(irq) = pic_irq(vector).filter(|&irq| is_spurious(irq)) {
+        record_spurious();
+        // 伪中断没有设置 ISR 中的位, 不能向发出它的 PIC 发送 EOI, 否则会清除其它正在处理的中断;
+        // 但 secondary PIC 的伪中断经过了 primary PIC 的级联线, 仍然需要向 primary PIC 发送 EOI
+        if irq >= 8 {
+            unsafe { Port::<u8>::new(PIC_1_COMMAND).write(PIC_EOI) };
+        }
+        return;
+    }
+    record(vector);
+
+    let mut handled = false;
+    // 共享的中断线上的每个处理函数都需要被调用, 不能在第一个处理了中断的处理函数之后停止
+    for entry in LINES[usize::from(vector)].lock().entries.iter() {
+        if (entry.handler)() == IrqReturn::Handled {
+            handled = true;
+        }
+    }
+    if !handled {
+        log::warn!("unhandled interrupt on vector {:#x}", vector);
+    }
+
+    end_of_interrupt(vector);
+}
+
+fn end_of_interrupt(vector: u8) {
+    if pic_irq(vector).is_some() {
+        unsafe { PICS.lock().notify_end_of_interrupt(vector) };
+    } else if apic::is_initialized() {
+        apic::end_of_interrupt();
+    }
+}
+
+// https://wiki.osdev.org/8259_PIC#Spurious_IRQs
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xa0;
+const PIC_EOI: u8 = 0x20;
+/// OCW3: 下一次读命令端口时返回 In-Service Register
+const PIC_READ_ISR: u8 = 0x0b;
+
+/// IRQ 7 和 IRQ 15 是否是伪中断
+///
+/// PIC 在发出中断之后、CPU 确认之前, 中断请求消失了 (如: 噪声) 时, PIC 会发送这个 PIC 优先级
+/// 最低的 IRQ (7 或 15), 但不会设置 ISR 中对应的位。
+fn is_spurious(irq: u8) -> bool {
+    if irq != 7 && irq != 15 {
+        return false;
+    }
+    let port = if irq < 8 { PIC_1_COMMAND } else { PIC_2_COMMAND };
+    // 持有锁, 防止与其它处理器上对 PIC 的操作交错
+    let _pics = PICS.lock();
+    let isr = unsafe {
+        Port::<u8>::new(port).write(PIC_READ_ISR);
+        Port::<u8>::new(port).read()
+    };
+    isr & (1 << (irq % 8)) == 0
+}
+
+/// 记录当前处理器上发生了一次 `vector` 中断, 由中断处理函数调用
+pub(crate) fn record(vector: u8) {
+    percpu!(interrupt_counts)[usize::from(vector)].fetch_add(1, Ordering::Relaxed);
+}
+
+/// 记录当前处理器收到了一个伪中断 (PIC 的 IRQ 7/15 或 local APIC 的伪中断)
+pub(crate) fn record_spurious() {
+    percpu!(spurious_interrupts).fetch_add(1, Ordering::Relaxed);
+}
+
+/// 每个中断向量在每个处理器上发生的次数, 可以通过 `{}` 输出成与 `/proc/interrupts` 类似的表格
+pub fn stats() -> Stats {
+    let cpus: Vec<&PerCpu> = percpu::cpus().collect();
+    let rows = (0..=u8::MAX)
+        .filter_map(|vector| {
+            let counts: Vec<u64> = cpus
+                .iter()
+                .map(|cpu| cpu.interrupt_counts[usize::from(vector)].load(Ordering::Relaxed))
+                .collect();
+            let handlers = vector_name(vector);
+            if counts.iter().all(|&count| count == 0) && handlers.is_empty() {
+                return None;
+            }
+            Some(VectorStats { vector, irq: pic_irq(vector), counts, handlers })
+        })
+        .collect();
+    let spurious = cpus.iter().map(|cpu| cpu.spurious_interrupts.load(Ordering::Relaxed)).collect();
+    Stats { cpus: cpus.iter().map(|cpu| cpu.cpu_id).collect(), rows, spurious }
+}
+
+/// 在中断向量上处理中断的处理函数的名字, 用逗号分隔
+fn vector_name(vector: u8) -> String {
+    if vector == InterruptIndex::Timer.as_u8() {
+        return String::from("timer");
+    }
+    if vector == apic::WAKEUP_VECTOR {
+        return String::from("wakeup IPI");
+    }
+    handler_names(vector).join(",")
+}
+
+/// 通过日志输出 [`stats`]
+pub fn log_stats() {
+    log::info!("interrupts:\n{}", stats());
+}
+
+/// 一个中断向量的统计, 见 [`stats`]
+#[derive(Debug, Clone)]
+pub struct VectorStats {
+    pub vector: u8,
+    /// 来自 PIC 时的 IRQ 线
+    pub irq: Option<u8>,
+    /// 在每个处理器上发生的次数, 顺序与 [`Stats::cpus`] 相同
+    pub counts: Vec<u64>,
+    /// 处理函数的名字, 用逗号分隔
+    pub handlers: String,
+}
+
+/// 所有中断向量的统计, 由 [`stats`] 返回
+#[derive(Debug, Clone)]
+pub struct Stats {
+    /// 处理器的编号
+    pub cpus: Vec<usize>,
+    /// 发生过中断或注册了处理函数的中断向量
+    pub rows: Vec<VectorStats>,
+    /// 每个处理器收到的伪中断的次数
+    pub spurious: Vec<u64>,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:>4} ", "")?;
+        for cpu in &self.cpus {
+            write!(f, " {:>10}", format!("CPU{}", cpu))?;
+        }
+        writeln!(f)?;
+        for row in &self.rows {
+            write!(f, "{:>4}:", row.vector)?;
+            for count in &row.counts {
+                write!(f, " {:>10}", count)?;
+            }
+            let source = match row.irq {
+                Some(irq) => format!("IRQ {}", irq),
+                None => String::from("APIC"),
+            };
+            writeln!(f, "  {:<8} {}", source, row.handlers)?;
+        }
+        write!(f, "{:>4}:", "SPU")?;
+        for count in &self.spurious {
+            write!(f, " {:>10}", count)?;
+        }
+        writeln!(f, "  spurious interrupts")
+    }
+}
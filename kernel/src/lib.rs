@@ -7,25 +7,78 @@ extern crate alloc;
 
 #[macro_use]
 pub mod logger;
+#[macro_use]
+pub mod percpu;
 pub mod interrupts;
+pub mod irq;
 pub mod gdt;
 pub mod task;
+pub mod sync;
 pub mod memory;
 pub mod allocator;
+pub mod userspace;
+pub mod syscall;
+pub mod time;
+pub mod loader;
+pub mod process;
+pub mod thread;
+pub mod acpi;
+pub mod apic;
+pub mod smp;
+pub mod tty;
+pub mod serial;
+pub mod power;
+pub mod shell;
+pub mod ps2;
 
 use core::panic::PanicInfo;
 
 use boot_info::BootInfo;
+use x86_64::VirtAddr;
 
-pub fn init(boot_info: &BootInfo) {
-    gdt::init();
-    interrupts::init_idt();
-    unsafe { interrupts::PICS.lock().initialize(); }
-
+pub fn init(boot_info: &'static BootInfo) {
     logger::init_logger(&boot_info.framebuffer);
 
+    init_memory(boot_info);
+
+    // IST 栈是动态分配的, 所以要在内存初始化之后再初始化 GDT
+    let tss = gdt::init();
+    percpu::init(0, tss);
+    syscall::init();
+    thread::init();
+    interrupts::init_idt(boot_info.kernel_stack);
+    unsafe { interrupts::PICS.lock().initialize(); }
+    if let Err(err) = ps2::init() {
+        log::warn!("failed to initialize the PS/2 controller: {}", err);
+    }
+    task::keyboard::init();
+    task::mouse::init();
+    serial::init();
+
     // 启用中断
     x86_64::instructions::interrupts::enable();
+
+    // 启动 AP 时需要通过时钟中断等待
+    acpi::init(boot_info.rsdp_addr.as_ref().copied());
+    smp::init();
+}
+
+fn init_memory(boot_info: &'static BootInfo) {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        memory::BootInfoFrameAllocator::init(&boot_info.memory_regions, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    // 不再需要 bootloader 留下的数据, 回收其中不再被引用的内存
+    let reclaimed = unsafe {
+        memory::reclaim_bootloader_memory(&boot_info.memory_regions, phys_mem_offset, &mut frame_allocator)
+    };
+    log::info!("Reclaimed {} KiB of bootloader memory", reclaimed * 4);
+
+    memory::init_globals(mapper, frame_allocator);
 }
 
 pub fn hlt_loop() -> ! {
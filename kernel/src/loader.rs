@@ -0,0 +1,255 @@
+//! # 用户程序加载器
+//! 加载静态链接的 x86_64 ELF 可执行文件, 与 bootloader 中加载内核的 `boot::loader` 类似, 但是:
+//!
+//! - 每个程序都有一个新的 [`AddressSpace`], 所有段都必须位于用户空间中
+//! - 只支持 `ET_EXEC` 类型的文件, 不支持动态链接和重定位
+//! - 段的内容会被复制到新分配的 frame 中, 而不是直接映射 ELF 文件所在的内存
+//! - 不同的 `PT_LOAD` 段不能位于同一页中 (链接时可以使用 `-z separate-code`)
+//! - 不处理 `PT_TLS`, 程序可以通过 auxv 中的 `AT_PHDR` 自行初始化 TLS
+//!
+//! ## 用户栈
+//! 参考: System V Application Binary Interface AMD64 Architecture Processor Supplement
+//!       3.4.1 Initial Stack and Register State
+//!
+//! ```text
+//!   高地址  +----------------------------+ <- USER_STACK_TOP
+//!          | argv 和 envp 中的字符串       |
+//!          +----------------------------+
+//!          | 对齐 (16 字节)               |
+//!          +----------------------------+
+//!          | AT_NULL                    |
+//!          | auxv (type, value) ...     |
+//!          | 0                          |
+//!          | envp[..]                   |
+//!          | 0                          |
+//!          | argv[..]                   |
+//!   低地址  | argc                       | <- rsp (16 字节对齐)
+//!          +----------------------------+
+//! ```
+
+use alloc::vec::Vec;
+
+use crate::userspace::{self, AddressSpace, UserExit};
+
+use x86_64::{align_down, structures::paging::PageTableFlags as Flags, VirtAddr};
+use xmas_elf::{
+    header,
+    program::{self, ProgramHeader, Type},
+    ElfFile,
+};
+
+/// 用户栈的大小
+pub const USER_STACK_SIZE: u64 = 4096 * 16;
+/// 用户栈的栈顶, 位于用户空间的最高处
+pub const USER_STACK_TOP: u64 = userspace::USER_SPACE_END;
+
+// auxv 中的类型, 参考: https://man7.org/linux/man-pages/man3/getauxval.3.html
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// 一个已经加载到自己的地址空间中的用户程序
+#[derive(Debug)]
+pub struct UserProgram {
+    address_space: AddressSpace,
+    entry_point: VirtAddr,
+    stack_pointer: VirtAddr,
+}
+
+impl UserProgram {
+    /// 程序的地址空间
+    pub fn address_space(&self) -> &AddressSpace {
+        &self.address_space
+    }
+
+    /// 程序的入口地址
+    pub fn entry_point(&self) -> VirtAddr {
+        self.entry_point
+    }
+
+    /// 程序开始执行时的栈指针, 指向 `argc`
+    pub fn stack_pointer(&self) -> VirtAddr {
+        self.stack_pointer
+    }
+
+    /// 运行程序, 直到程序结束
+    pub fn run(&self) -> UserExit {
+        // 入口地址和栈都已经在 `load` 中检查和映射过了
+        unsafe { self.address_space.run(self.entry_point, self.stack_pointer) }
+    }
+
+    /// 返回程序的地址空间, 入口地址和初始栈指针
+    pub fn into_parts(self) -> (AddressSpace, VirtAddr, VirtAddr) {
+        (self.address_space, self.entry_point, self.stack_pointer)
+    }
+}
+
+/// 将 ELF 文件 `bytes` 加载到一个新的地址空间中, 并在用户栈上准备好 `argv`, `envp` 和 auxv
+pub fn load(bytes: &[u8], argv: &[&str], envp: &[&str]) -> Result<UserProgram, &'static str> {
+    let elf_file = ElfFile::new(bytes)?;
+    header::sanity_check(&elf_file)?;
+    for program_header in elf_file.program_iter() {
+        program::sanity_check(program_header, &elf_file)?;
+    }
+
+    if elf_file.header.pt1.class() != header::Class::SixtyFour {
+        return Err("not a 64-bit ELF file");
+    }
+    if elf_file.header.pt2.machine().as_machine() != header::Machine::X86_64 {
+        return Err("not an x86_64 ELF file");
+    }
+    if elf_file.header.pt2.type_().as_type() != header::Type::Executable {
+        return Err("only static executables are supported");
+    }
+
+    let entry_point = VirtAddr::try_new(elf_file.header.pt2.entry_point())
+        .map_err(|_| "entry point is not canonical")?;
+    if !userspace::is_user_range(entry_point, 1) {
+        return Err("entry point is not in user space");
+    }
+
+    let mut address_space = AddressSpace::new().map_err(|_| "failed to create address space")?;
+    for program_header in elf_file.program_iter() {
+        match program_header.get_type()? {
+            Type::Load => load_segment(&mut address_space, bytes, program_header)?,
+            Type::Interp | Type::Dynamic => return Err("dynamically linked executables are not supported"),
+            _ => {}
+        }
+    }
+
+    let stack_bottom = VirtAddr::new(USER_STACK_TOP - USER_STACK_SIZE);
+    address_space
+        .map(stack_bottom, USER_STACK_SIZE, Flags::WRITABLE | Flags::NO_EXECUTE)
+        .map_err(|_| "failed to map user stack")?;
+
+    let mut auxv = Vec::new();
+    if let Some(phdr) = program_headers_addr(&elf_file) {
+        auxv.push((AT_PHDR, phdr));
+    }
+    auxv.push((AT_PHENT, u64::from(elf_file.header.pt2.ph_entry_size())));
+    auxv.push((AT_PHNUM, u64::from(elf_file.header.pt2.ph_count())));
+    auxv.push((AT_PAGESZ, PAGE_SIZE));
+    auxv.push((AT_ENTRY, entry_point.as_u64()));
+
+    let (stack, stack_pointer) = build_stack(USER_STACK_TOP, argv, envp, &auxv);
+    if stack.len() as u64 > USER_STACK_SIZE / 2 {
+        return Err("argument list too long");
+    }
+    address_space
+        .copy_to(stack_pointer, &stack)
+        .map_err(|_| "user stack not mapped")?;
+
+    Ok(UserProgram {
+        address_space,
+        entry_point,
+        stack_pointer,
+    })
+}
+
+fn load_segment(
+    address_space: &mut AddressSpace,
+    bytes: &[u8],
+    segment: ProgramHeader,
+) -> Result<(), &'static str> {
+    log::debug!("Loading user segment: {:x?}", segment);
+    if segment.mem_size() == 0 {
+        return Ok(());
+    }
+
+    let start = VirtAddr::try_new(segment.virtual_addr()).map_err(|_| "segment is not canonical")?;
+    if !userspace::is_user_range(start, segment.mem_size()) {
+        return Err("segment is not in user space");
+    }
+    if segment.file_size() > segment.mem_size() {
+        return Err("segment file size is larger than its memory size");
+    }
+
+    let mut segment_flags = Flags::empty();
+    if !segment.flags().is_execute() {
+        segment_flags |= Flags::NO_EXECUTE;
+    }
+    if segment.flags().is_write() {
+        segment_flags |= Flags::WRITABLE;
+    }
+
+    // 按页映射整个段, 新分配的 frame 已经清零, 所以 .bss 不需要额外处理
+    let page_start = align_down(start.as_u64(), PAGE_SIZE);
+    let size = start.as_u64() + segment.mem_size() - page_start;
+    address_space
+        .map(VirtAddr::new(page_start), size, segment_flags)
+        .map_err(|_| "failed to map segment, do two segments share a page?")?;
+
+    let data = usize::try_from(segment.offset())
+        .ok()
+        .zip(usize::try_from(segment.file_size()).ok())
+        .and_then(|(offset, len)| bytes.get(offset..offset.checked_add(len)?))
+        .ok_or("segment data out of bounds")?;
+    address_space
+        .copy_to(start, data)
+        .map_err(|_| "segment not mapped")?;
+
+    Ok(())
+}
+
+/// 返回程序头表在程序的地址空间中的地址
+fn program_headers_addr(elf_file: &ElfFile) -> Option<u64> {
+    if let Some(phdr) = elf_file.program_iter().find(|h| matches!(h.get_type(), Ok(Type::Phdr))) {
+        return Some(phdr.virtual_addr());
+    }
+
+    // 没有 PT_PHDR 时, 查找包含程序头表的 PT_LOAD 段
+    let ph_offset = elf_file.header.pt2.ph_offset();
+    elf_file
+        .program_iter()
+        .filter(|h| matches!(h.get_type(), Ok(Type::Load)))
+        .find(|h| h.offset() <= ph_offset && ph_offset < h.offset() + h.file_size())
+        .map(|h| h.virtual_addr() + (ph_offset - h.offset()))
+}
+
+/// 构造初始的用户栈, 返回栈的内容和栈指针, 栈的内容需要被复制到栈指针处
+fn build_stack(
+    stack_top: u64,
+    argv: &[&str],
+    envp: &[&str],
+    auxv: &[(u64, u64)],
+) -> (Vec<u8>, VirtAddr) {
+    let strings_size: u64 = argv.iter().chain(envp).map(|s| s.len() as u64 + 1).sum();
+    let strings_start = align_down(stack_top - strings_size, 16);
+
+    // argc, argv, NULL, envp, NULL, auxv, AT_NULL
+    let words_count = 1 + argv.len() + 1 + envp.len() + 1 + 2 * auxv.len() + 2;
+    let stack_pointer = align_down(strings_start - 8 * words_count as u64, 16);
+
+    let mut stack = alloc::vec![0u8; (stack_top - stack_pointer) as usize];
+    let mut words = Vec::with_capacity(words_count);
+    let mut string_addr = strings_start;
+    let mut string_ptrs = |strings: &[&str], words: &mut Vec<u64>| {
+        for s in strings {
+            let offset = (string_addr - stack_pointer) as usize;
+            // 字符串末尾的 '\0' 已经在 vec! 中被初始化了
+            stack[offset..offset + s.len()].copy_from_slice(s.as_bytes());
+            words.push(string_addr);
+            string_addr += s.len() as u64 + 1;
+        }
+        words.push(0);
+    };
+
+    words.push(argv.len() as u64);
+    string_ptrs(argv, &mut words);
+    string_ptrs(envp, &mut words);
+    for &(ty, value) in auxv {
+        words.extend([ty, value]);
+    }
+    words.extend([AT_NULL, 0]);
+
+    for (i, word) in words.iter().enumerate() {
+        stack[i * 8..(i + 1) * 8].copy_from_slice(&word.to_ne_bytes());
+    }
+
+    (stack, VirtAddr::new(stack_pointer))
+}
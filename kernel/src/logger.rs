@@ -320,11 +320,43 @@ impl Logger {
         let _ = unsafe { ptr::read_volatile(&self.framebuffer[pos] as *const Cell) };
     }
 
+    /// 光标左移一格, 在行首时移到上一行的行尾, 不会擦除字符
+    fn backspace(&mut self) {
+        if self.x > 0 {
+            self.x -= 1;
+        } else if self.y > 0 {
+            self.y -= 1;
+            self.x = VGA_TEXT_MODE_WIDTH - 1;
+        }
+    }
+
+    /// 把 VGA 的硬件光标移动到下一个字符的位置
+    /// 参考: https://wiki.osdev.org/Text_Mode_Cursor
+    fn update_cursor(&mut self) {
+        use x86_64::instructions::port::Port;
+
+        const CRTC_ADDRESS_PORT: u16 = 0x3d4;
+        const CRTC_DATA_PORT: u16 = 0x3d5;
+        const CURSOR_LOCATION_HIGH: u8 = 0x0e;
+        const CURSOR_LOCATION_LOW: u8 = 0x0f;
+
+        let pos = (self.y * VGA_TEXT_MODE_WIDTH + self.x.min(VGA_TEXT_MODE_WIDTH - 1)) as u16;
+        let mut address = Port::<u8>::new(CRTC_ADDRESS_PORT);
+        let mut data = Port::<u8>::new(CRTC_DATA_PORT);
+        unsafe {
+            address.write(CURSOR_LOCATION_LOW);
+            data.write(pos as u8);
+            address.write(CURSOR_LOCATION_HIGH);
+            data.write((pos >> 8) as u8);
+        }
+    }
+
     // 仅支持 ascii char
     fn write_char(&mut self, ch: char) {
         match ch as u8 {
             b'\n' => self.newline(),
             b'\r' => self.carriage_return(),
+            b'\x08' => self.backspace(),
             byte @ 0x20..=0x7e => {
                 self.write_byte(byte);
             },
@@ -341,6 +373,7 @@ impl fmt::Write for Logger {
         for c in s.chars() {
             self.write_char(c);
         }
+        self.update_cursor();
         Ok(())
     }
 }
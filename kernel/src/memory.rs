@@ -1,16 +1,93 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::{vec, vec::Vec};
 use x86_64::{
+    align_down, align_up,
     structures::paging::{
-        PageTable, OffsetPageTable, FrameAllocator, 
-        Size4KiB, PhysFrame,
+        mapper::MapToError,
+        PageTable, OffsetPageTable, FrameAllocator, FrameDeallocator,
+        Mapper, Page, PageTableFlags, Size4KiB, PhysFrame, Translate,
     },
     PhysAddr, VirtAddr,
 };
-use boot_info::{MemoryRegions, MemoryRegionKind};
+use boot_info::{MemoryRegion, MemoryRegions, MemoryRegionKind};
+use spinning_top::{const_spinlock, Spinlock, SpinlockGuard};
+use conquer_once::spin::OnceCell;
+
+const FRAME_SIZE: u64 = 4096;
+
+/// 内核栈 (IST 栈等) 所在的虚拟地址区域的起始地址
+pub const KERNEL_STACKS_START: u64 = 0x_6000_0000_0000;
+/// 设备寄存器 (MMIO) 映射到的虚拟地址区域的起始地址, 见 [`map_mmio`]
+pub const MMIO_START: u64 = 0x_6100_0000_0000;
+
+/// 内核的页表, 由 [`init_globals`] 初始化
+pub static MAPPER: OnceCell<Spinlock<OffsetPageTable<'static>>> = OnceCell::uninit();
+/// 全局的 frame allocator, 由 [`init_globals`] 初始化
+pub static FRAME_ALLOCATOR: OnceCell<Spinlock<BootInfoFrameAllocator>> = OnceCell::uninit();
+
+/// 将内核的页表和 frame allocator 保存到 [`MAPPER`] 和 [`FRAME_ALLOCATOR`] 中, 供全局使用。
+pub fn init_globals(mapper: OffsetPageTable<'static>, frame_allocator: BootInfoFrameAllocator) {
+    MAPPER.init_once(|| Spinlock::new(mapper));
+    FRAME_ALLOCATOR.init_once(|| Spinlock::new(frame_allocator));
+}
+
+/// 锁住并返回全局的页表
+///
+/// Panics if [`init_globals`] has not been called yet.
+pub fn mapper() -> SpinlockGuard<'static, OffsetPageTable<'static>> {
+    MAPPER.get().expect("memory not initialized").lock()
+}
+
+/// 锁住并返回全局的 frame allocator
+///
+/// Panics if [`init_globals`] has not been called yet.
+pub fn frame_allocator() -> SpinlockGuard<'static, BootInfoFrameAllocator> {
+    FRAME_ALLOCATOR.get().expect("memory not initialized").lock()
+}
+
+/// bootloader 提供的物理内存的布局
+///
+/// Panics if [`init_globals`] has not been called yet.
+pub fn memory_regions() -> &'static [MemoryRegion] {
+    let memory_map: &'static MemoryRegions = frame_allocator().memory_map;
+    memory_map
+}
+
+/// 返回物理地址 `addr` 在物理内存映射中的虚拟地址
+///
+/// Panics if [`init_globals`] has not been called yet.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    mapper().phys_offset() + addr.as_u64()
+}
 
 /// A FrameAllocator that returns usable frames from the bootloader's memory map.
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryRegions,
     next: usize,
+    /// 被归还的 frame 组成的链表, 每个 frame 的前 8 个字节保存下一个 frame 的物理地址
+    free_list: Option<PhysFrame>,
+    /// `free_list` 中的 frame 的数量
+    free_list_len: u64,
+    physical_memory_offset: VirtAddr,
+}
+
+/// frame 的使用情况, 由 [`BootInfoFrameAllocator::stats`] 返回
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    /// 内存布局中可用的 frame 的数量
+    pub usable: u64,
+    /// 还没有被分配过的可用的 frame 的数量
+    pub untouched: u64,
+    /// 被归还的 frame 的数量, 包括回收的 bootloader 的内存
+    pub recycled: u64,
+}
+
+impl FrameStats {
+    /// 空闲的 frame 的数量
+    pub fn free(&self) -> u64 {
+        self.untouched + self.recycled
+    }
 }
 
 impl BootInfoFrameAllocator {
@@ -18,15 +95,67 @@ impl BootInfoFrameAllocator {
     ///
     /// This function is unsafe because the caller must guarantee that the passed
     /// memory map is valid. The main requirement is that all frames that are marked
-    /// as `USABLE` in it are really unused.
-    pub unsafe fn init(memory_map: &'static MemoryRegions) -> Self {
+    /// as `USABLE` in it are really unused. The complete physical memory must also
+    /// be mapped at `physical_memory_offset`, which is used to link deallocated frames.
+    pub unsafe fn init(memory_map: &'static MemoryRegions, physical_memory_offset: VirtAddr) -> Self {
         BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            free_list: None,
+            free_list_len: 0,
+            physical_memory_offset,
+        }
+    }
+
+    /// 返回 `frame` 中保存下一个空闲 frame 地址的指针
+    fn free_list_link(&self, frame: PhysFrame) -> *mut u64 {
+        (self.physical_memory_offset + frame.start_address().as_u64()).as_mut_ptr()
+    }
+
+    /// 分配一个结束地址不超过 `limit` 的 frame, 用于只能使用低端内存的场合 (如: AP 的启动代码)
+    ///
+    /// 先在被归还的 frame 中查找, 再尝试下一个还没有被分配过的 frame 。
+    pub fn allocate_frame_below(&mut self, limit: PhysAddr) -> Option<PhysFrame> {
+        let fits = |frame: PhysFrame| frame.start_address() + FRAME_SIZE <= limit;
+
+        let mut prev: Option<PhysFrame> = None;
+        let mut current = self.free_list;
+        while let Some(frame) = current {
+            let next = unsafe { self.free_list_link(frame).read() };
+            if fits(frame) {
+                match prev {
+                    Some(prev) => unsafe { self.free_list_link(prev).write(next) },
+                    None => {
+                        self.free_list = (next != 0)
+                            .then(|| PhysFrame::containing_address(PhysAddr::new(next)));
+                    }
+                }
+                self.free_list_len -= 1;
+                return Some(frame);
+            }
+            prev = current;
+            current = (next != 0).then(|| PhysFrame::containing_address(PhysAddr::new(next)));
+        }
+
+        let frame = self.usable_frames().nth(self.next).filter(|&frame| fits(frame))?;
+        self.next += 1;
+        Some(frame)
+    }
+
+    /// frame 的使用情况
+    pub fn stats(&self) -> FrameStats {
+        let usable = self.usable_frames().count() as u64;
+        FrameStats {
+            usable,
+            untouched: usable.saturating_sub(self.next as u64),
+            recycled: self.free_list_len,
         }
     }
 }
 
+// The memory map is only read, and the allocator is only reachable through [`FRAME_ALLOCATOR`].
+unsafe impl Send for BootInfoFrameAllocator {}
+
 impl BootInfoFrameAllocator {
     /// Returns an iterator over the usable frames specified in the memory map.
     fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
@@ -46,12 +175,130 @@ impl BootInfoFrameAllocator {
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        // 优先使用被归还的 frame
+        if let Some(frame) = self.free_list {
+            let next = unsafe { self.free_list_link(frame).read() };
+            self.free_list = match next {
+                0 => None,
+                addr => Some(PhysFrame::containing_address(PhysAddr::new(addr))),
+            };
+            self.free_list_len -= 1;
+            return Some(frame);
+        }
+
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
         frame
     }
 }
 
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    /// The frame must not be in use anymore. Frame 0 can't be deallocated, because its
+    /// address is used to mark the end of the free list.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        debug_assert!(frame.start_address().as_u64() != 0, "frame 0 can not be deallocated");
+        let next = self.free_list.map_or(0, |f| f.start_address().as_u64());
+        self.free_list_link(frame).write(next);
+        self.free_list = Some(frame);
+        self.free_list_len += 1;
+    }
+}
+
+/// A [`MemoryRegionKind::Bootloader`] region, with one bit per frame that is still in use.
+struct BootloaderRegion {
+    start: u64,
+    end: u64,
+    in_use: Vec<u64>,
+}
+
+impl BootloaderRegion {
+    fn new(start: u64, end: u64) -> Self {
+        let frames = ((end - start) / FRAME_SIZE) as usize;
+        BootloaderRegion { start, end, in_use: vec![0; frames.div_ceil(64)] }
+    }
+
+    /// Marks all frames of the region that overlap with `[addr, addr + size)` as used.
+    fn mark_used(&mut self, addr: u64, size: u64) {
+        let start = addr.max(self.start);
+        let end = addr.saturating_add(size).min(self.end);
+        let mut frame = align_down(start, FRAME_SIZE);
+        while frame < end {
+            let index = ((frame - self.start) / FRAME_SIZE) as usize;
+            self.in_use[index / 64] |= 1 << (index % 64);
+            frame += FRAME_SIZE;
+        }
+    }
+
+    fn unused_frames(&self) -> impl Iterator<Item = PhysFrame> + '_ {
+        (self.start..self.end)
+            .step_by(FRAME_SIZE as usize)
+            .enumerate()
+            .filter(|(index, _)| self.in_use[index / 64] & (1 << (index % 64)) == 0)
+            .map(|(_, addr)| PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+}
+
+/// 将 bootloader 使用过、但已不再被内核页表引用的 frame 归还给 `frame_allocator`,
+/// 返回归还的 frame 的数量。
+///
+/// 所有 [`MemoryRegionKind::Bootloader`] 区域 (bootloader 的低端内存、页表、内核 ELF 文件的副本等)
+/// 中, 仍被当前页表映射的 frame (内核的各个段、栈、boot info、GDT 以及页表本身) 会被保留,
+/// 其余的 frame 都会被归还。
+///
+/// This function is unsafe because the caller must guarantee that the complete
+/// physical memory is mapped to virtual memory at the passed `physical_memory_offset`,
+/// and that nothing outside of the active page table still refers to memory used by
+/// the bootloader, i.e. the boot info must already have been consumed and copied.
+pub unsafe fn reclaim_bootloader_memory(
+    memory_map: &MemoryRegions,
+    physical_memory_offset: VirtAddr,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) -> u64 {
+    use x86_64::registers::control::Cr3;
+
+    let mut regions: Vec<BootloaderRegion> = memory_map
+        .iter()
+        .filter(|r| r.kind == MemoryRegionKind::Bootloader)
+        .map(|r| (align_up(r.start, FRAME_SIZE), align_down(r.end, FRAME_SIZE)))
+        .filter(|(start, end)| start < end)
+        .map(|(start, end)| BootloaderRegion::new(start, end))
+        .collect();
+
+    // 物理地址 0 被用来表示空闲链表的结尾, 所以 frame 0 不能被回收
+    mark_used(&mut regions, 0, FRAME_SIZE);
+
+    // bootloader 映射全部物理内存时使用的 level 4 entry
+    let physical_memory_p4_indexes = {
+        let max_phys_addr = memory_map.iter().map(|r| r.end).max().unwrap_or(FRAME_SIZE);
+        let start = usize::from(physical_memory_offset.p4_index());
+        let end = usize::from((physical_memory_offset + (max_phys_addr - 1)).p4_index());
+        start..=end
+    };
+
+    let (level_4_table_frame, _) = Cr3::read();
+    mark_used(&mut regions, level_4_table_frame.start_address().as_u64(), FRAME_SIZE);
+    let level_4_table = page_table_at(level_4_table_frame, physical_memory_offset);
+    for (index, entry) in level_4_table.iter().enumerate() {
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        // 物理内存的映射覆盖了所有 frame, 所以它映射的 frame 不算被引用, 但它使用的页表依然要保留
+        let mark_leaves = !physical_memory_p4_indexes.contains(&index);
+        let frame = PhysFrame::containing_address(entry.addr());
+        mark_page_table(&mut regions, frame, 3, physical_memory_offset, mark_leaves);
+    }
+
+    let mut reclaimed = 0;
+    for region in regions.iter() {
+        for frame in region.unused_frames() {
+            frame_allocator.deallocate_frame(frame);
+            reclaimed += 1;
+        }
+    }
+
+    reclaimed
+}
+
 /// Initialize a new OffsetPageTable.
 ///
 /// This function is unsafe because the caller must guarantee that the
@@ -132,4 +379,220 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr)
 
     // calculate the physical address by adding the page offset
     Some(frame.start_address() + u64::from(addr.page_offset()))
-}
\ No newline at end of file
+}
+
+fn mark_used(regions: &mut [BootloaderRegion], addr: u64, size: u64) {
+    for region in regions.iter_mut() {
+        region.mark_used(addr, size);
+    }
+}
+
+/// 标记第 `level` 级页表 `table_frame` 以及它引用的所有 frame。
+///
+/// `mark_leaves` 为 `false` 时, 只标记各级页表本身, 而不标记被映射的 frame。
+unsafe fn mark_page_table(
+    regions: &mut [BootloaderRegion],
+    table_frame: PhysFrame,
+    level: u8,
+    physical_memory_offset: VirtAddr,
+    mark_leaves: bool,
+) {
+    mark_used(regions, table_frame.start_address().as_u64(), FRAME_SIZE);
+
+    let table = page_table_at(table_frame, physical_memory_offset);
+    for entry in table.iter() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            if mark_leaves {
+                // 4KiB, 2MiB 或 1GiB
+                let page_size = FRAME_SIZE << (9 * (level - 1));
+                mark_used(regions, entry.addr().as_u64(), page_size);
+            }
+        } else {
+            let frame = PhysFrame::containing_address(entry.addr());
+            mark_page_table(regions, frame, level - 1, physical_memory_offset, mark_leaves);
+        }
+    }
+}
+
+unsafe fn page_table_at(frame: PhysFrame, physical_memory_offset: VirtAddr) -> &'static PageTable {
+    let virt = physical_memory_offset + frame.start_address().as_u64();
+    &*virt.as_ptr()
+}
+
+/// 一个已经映射好的内核栈, 它下方的一页是不映射的 guard page。
+#[derive(Debug, Clone, Copy)]
+pub struct Stack {
+    start: VirtAddr,
+    end: VirtAddr,
+}
+
+impl Stack {
+    /// The lowest address of the stack.
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    /// The end address (exclusive) of the stack, which is the initial stack pointer.
+    pub fn end(&self) -> VirtAddr {
+        self.end
+    }
+
+    /// Returns whether `addr` lies in the guard page below the stack.
+    pub fn guard_page_contains(&self, addr: VirtAddr) -> bool {
+        self.start - FRAME_SIZE <= addr && addr < self.start
+    }
+}
+
+/// 由 [`alloc_stack`] 分配且还没有释放的栈的 guard page, 按地址排序
+static GUARD_PAGES: Spinlock<Vec<VirtAddr>> = const_spinlock(Vec::new());
+
+/// `addr` 是否在一个由 [`alloc_stack`] 分配的栈的 guard page 中, 用于在 double fault 中判断
+/// 是否发生了栈溢出, 所以不会等待锁: 锁被占用时只检查 `addr` 是否在栈的地址范围内且没有被映射
+pub fn is_stack_guard_page(addr: VirtAddr) -> bool {
+    let page = addr.align_down(FRAME_SIZE);
+    match GUARD_PAGES.try_lock() {
+        Some(guard_pages) => guard_pages.binary_search(&page).is_ok(),
+        None => {
+            (KERNEL_STACKS_START..MMIO_START).contains(&addr.as_u64())
+                && MAPPER.get().and_then(Spinlock::try_lock).is_some_and(|mapper| mapper.translate_addr(addr).is_none())
+        }
+    }
+}
+
+/// 在 [`KERNEL_STACKS_START`] 之后分配并映射一个 `pages` 页大小的栈, 并在它下方留出一页 guard page。
+///
+/// Panics if [`init_globals`] has not been called yet.
+pub fn alloc_stack(pages: u64) -> Result<Stack, MapToError<Size4KiB>> {
+    static NEXT: AtomicU64 = AtomicU64::new(KERNEL_STACKS_START);
+
+    let guard_page = NEXT.fetch_add((pages + 1) * FRAME_SIZE, Ordering::Relaxed);
+    let start = VirtAddr::new(guard_page + FRAME_SIZE);
+    let end = start + pages * FRAME_SIZE;
+
+    let mut mapper = mapper();
+    let mut frame_allocator = frame_allocator();
+    let start_page = Page::containing_address(start);
+    let end_page = Page::containing_address(end - 1u64);
+    for page in Page::range_inclusive(start_page, end_page) {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        unsafe {
+            mapper.map_to(page, frame, flags, &mut *frame_allocator)?.flush()
+        };
+    }
+
+    let guard_page = VirtAddr::new(guard_page);
+    let mut guard_pages = GUARD_PAGES.lock();
+    let index = guard_pages.binary_search(&guard_page).unwrap_or_else(|index| index);
+    guard_pages.insert(index, guard_page);
+
+    Ok(Stack { start, end })
+}
+
+/// 将物理地址 `[phys, phys + size)` 处的设备寄存器映射到 [`MMIO_START`] 之后, 返回 `phys` 对应的虚拟地址
+///
+/// 映射的页是不可缓存的 (`NO_CACHE | WRITE_THROUGH`), 不会被取消映射。
+///
+/// Panics if [`init_globals`] has not been called yet.
+pub fn map_mmio(phys: PhysAddr, size: u64) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    static NEXT: AtomicU64 = AtomicU64::new(MMIO_START);
+
+    let phys_start = phys.align_down(FRAME_SIZE);
+    let pages = (phys + size - phys_start).div_ceil(FRAME_SIZE);
+    let virt_start = VirtAddr::new(NEXT.fetch_add(pages * FRAME_SIZE, Ordering::Relaxed));
+
+    let mut mapper = mapper();
+    let mut frame_allocator = frame_allocator();
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_EXECUTE;
+    for i in 0..pages {
+        let page = Page::containing_address(virt_start + i * FRAME_SIZE);
+        let frame = PhysFrame::containing_address(phys_start + i * FRAME_SIZE);
+        unsafe {
+            mapper.map_to(page, frame, flags, &mut *frame_allocator)?.flush()
+        };
+    }
+
+    Ok(virt_start + (phys - phys_start))
+}
+
+/// 释放一个由 [`alloc_stack`] 分配的栈, 栈所在的虚拟地址不会被重新使用
+///
+/// ## Safety
+/// 栈必须已经不再被使用了。
+pub unsafe fn free_stack(stack: Stack) {
+    {
+        let mut guard_pages = GUARD_PAGES.lock();
+        if let Ok(index) = guard_pages.binary_search(&(stack.start - FRAME_SIZE)) {
+            guard_pages.remove(index);
+        }
+    }
+    let mut mapper = mapper();
+    let mut frame_allocator = frame_allocator();
+    let start_page: Page = Page::containing_address(stack.start);
+    let end_page = Page::containing_address(stack.end - 1u64);
+    for page in Page::range_inclusive(start_page, end_page) {
+        match mapper.unmap(page) {
+            Ok((frame, flush)) => {
+                flush.flush();
+                frame_allocator.deallocate_frame(frame);
+            }
+            Err(err) => log::warn!("failed to unmap stack page {:?}: {:?}", page, err),
+        }
+    }
+}
+
+/// 页表中的一项, 由 [`walk_page_table`] 返回
+#[derive(Debug, Clone, Copy)]
+pub struct PageTableLevel {
+    /// 页表的级别, 4 为最高级
+    pub level: u8,
+    /// 在这一级页表中的下标
+    pub index: u16,
+    pub flags: PageTableFlags,
+    /// 下一级页表或被映射的 frame 的物理地址
+    pub addr: PhysAddr,
+}
+
+/// 在当前页表中查找 `addr`, 返回经过的每一级页表项和 `addr` 被映射到的物理地址
+///
+/// 遇到不存在的页表项或 huge page 时停止, 不存在时物理地址为 `None` 。
+///
+/// Panics if [`init_globals`] has not been called yet.
+pub fn walk_page_table(addr: VirtAddr) -> (Vec<PageTableLevel>, Option<PhysAddr>) {
+    use x86_64::registers::control::Cr3;
+
+    let physical_memory_offset = mapper().phys_offset();
+    let indexes = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    let (mut frame, _) = Cr3::read();
+    let mut levels = Vec::new();
+    for (level, index) in (1..=4u8).rev().zip(indexes) {
+        let table = unsafe { page_table_at(frame, physical_memory_offset) };
+        let entry = &table[index];
+        levels.push(PageTableLevel {
+            level,
+            index: u16::from(index),
+            flags: entry.flags(),
+            addr: entry.addr(),
+        });
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return (levels, None);
+        }
+        if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            // 4KiB, 2MiB 或 1GiB
+            let page_size = FRAME_SIZE << (9 * (level - 1));
+            return (levels, Some(entry.addr() + (addr.as_u64() & (page_size - 1))));
+        }
+        frame = PhysFrame::containing_address(entry.addr());
+    }
+    unreachable!()
+}
@@ -0,0 +1,239 @@
+//! # Per-CPU 数据
+//! 参考: https://wiki.osdev.org/SWAPGS && Intel® 64 and IA-32 Architectures Software Developer’s Manual
+//!       Volume 2B, SWAPGS
+//!
+//! 每个处理器都有一个 [`PerCpu`], 在内核态时 GS base 指向它, 通过 [`percpu!`] 访问当前处理器的数据:
+//!
+//! ```ignore
+//! let cpu = percpu!(cpu_id);
+//! percpu!(run_queue).push(task);
+//! ```
+//!
+//! ## GS 与 swapgs
+//! 内核态时 GS base 为当前处理器的 [`PerCpu`], KERNEL_GS_BASE 为用户程序的 GS base (目前恒为 0),
+//! 用户态时两者相反。每次在两者之间切换时都需要执行 `swapgs`:
+//!
+//! - `syscall_entry` 的入口和 `sysretq` 之前 (见 `asm/syscall.s`)
+//! - 进入用户态的 `iretq` 之前 (见 `asm/userspace.s`)
+//! - 中断处理函数的入口和返回前, 只在中断发生在用户态时 (见 [`InterruptGuard`])
+//!
+//! NMI、double fault 和 machine check 可能发生在 `swapgs` 与 `sysretq`/`iretq` 之间,
+//! 这时无法判断 GS base 是哪一个, 所以它们的处理函数不会执行 `swapgs`, 也不能访问 per-CPU 数据。
+//!
+//! ## 线程
+//! 线程目前不会在处理器之间迁移, 所以一个线程在运行期间读到的 per-CPU 数据总是同一个处理器的。
+//! `Cell` 字段只会被所属的处理器访问, 修改时需要关中断, 防止被同一处理器上的中断打断。
+//!
+//! ## 其它处理器
+//! 通过 [`get`] 和 [`cpus`] 可以访问其它处理器的数据 (如: 把任务放入它的就绪队列),
+//! 这时只能使用本身线程安全的字段。
+
+use core::arch::asm;
+use core::cell::Cell;
+use core::mem::offset_of;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+
+use crate::gdt::Tss;
+use crate::task::{self, Task};
+use crate::thread::ThreadId;
+
+use crossbeam_queue::{ArrayQueue, SegQueue};
+use spinning_top::Spinlock;
+use x86_64::{
+    registers::model_specific::{GsBase, KernelGsBase},
+    structures::idt::InterruptStackFrame,
+    VirtAddr,
+};
+
+/// 支持的处理器的最大数量
+pub const MAX_CPUS: usize = 64;
+
+/// BSP 的 per-CPU 数据是否已经初始化, 在这之前 GS base 为 0
+static READY: AtomicBool = AtomicBool::new(false);
+/// 所有处理器的 per-CPU 数据, 下标为 CPU 编号
+static CPUS: [AtomicPtr<PerCpu>; MAX_CPUS] = [const { AtomicPtr::new(core::ptr::null_mut()) }; MAX_CPUS];
+
+/// 访问当前处理器的 [`PerCpu`] 中的字段, 返回 `&'static` 引用
+#[macro_export]
+macro_rules! percpu {
+    ($field:ident) => {
+        &$crate::percpu::current().$field
+    };
+}
+
+/// 一个处理器的数据, 前三个字段的偏移会被汇编代码使用
+#[repr(C)]
+pub struct PerCpu {
+    /// 指向自己, 用于通过 `gs:[0]` 得到 [`PerCpu`] 的地址
+    self_ptr: *const PerCpu,
+    /// 当前线程的内核栈, 由 `syscall_entry` 使用 (`gs:[8]`), 与 TSS 中的 RSP0 相同
+    pub(crate) syscall_kernel_stack: Cell<u64>,
+    /// 进入系统调用时用户程序的栈指针, 只在切换栈的过程中使用 (`gs:[16]`)
+    pub(crate) syscall_user_stack: Cell<u64>,
+    /// 逻辑 CPU 编号, BSP 为 0
+    pub cpu_id: usize,
+    /// local APIC ID
+    pub apic_id: u32,
+    /// 这个处理器的 TSS 和 IST 栈
+    pub tss: &'static Tss,
+    /// 当前处理器上正在运行的线程, 由 [`crate::thread`] 维护
+    pub(crate) current_thread: Cell<Option<ThreadId>>,
+    /// 就绪的任务, 由这个处理器上的执行器取出, 空闲的处理器也会从这里窃取任务,
+    /// 见 [`crate::task`]
+    pub(crate) run_queue: SegQueue<Arc<Task>>,
+    /// 可以被没有线程的处理器窃取的就绪任务, 只在运行线程的处理器上使用, 见 [`crate::task`]
+    pub(crate) any_cpu_run_queue: SegQueue<Arc<Task>>,
+    /// 在中断处理函数中被唤醒的任务, 容量为 [`task::MAX_TASKS`]
+    pub(crate) irq_run_queue: ArrayQueue<Arc<Task>>,
+    /// 在这个处理器上运行执行器的线程, 只在关中断时访问, 见 [`crate::task::executor`]
+    pub(crate) executor_threads: Spinlock<Vec<ThreadId>>,
+    /// 正在等待任务的执行器的数量, 大于 0 时需要通过 IPI 唤醒这个处理器
+    pub(crate) idle_executors: AtomicUsize,
+    /// 每个中断向量在这个处理器上发生的次数, 见 [`crate::irq::stats`]
+    pub(crate) interrupt_counts: [AtomicU64; 256],
+    /// 这个处理器收到的伪中断的次数
+    pub(crate) spurious_interrupts: AtomicU64,
+    /// 中断嵌套的深度
+    interrupt_depth: Cell<usize>,
+}
+
+// `asm/syscall.s` 中硬编码了这些偏移
+const _: () = {
+    assert!(offset_of!(PerCpu, self_ptr) == 0);
+    assert!(offset_of!(PerCpu, syscall_kernel_stack) == 8);
+    assert!(offset_of!(PerCpu, syscall_user_stack) == 16);
+};
+
+// `Cell` 字段只会被它所属的处理器访问, 其它字段是线程安全的 (见模块文档)
+unsafe impl Sync for PerCpu {}
+
+/// 创建当前处理器的 per-CPU 数据, 并写入 GS base
+///
+/// 每个处理器都需要在 [`crate::gdt::init`] (或 `init_ap`) 之后调用一次, 因为加载 GS 会清零 GS base 。
+/// per-CPU 数据永远不会被释放。
+///
+/// Panics if `cpu_id` is not less than [`MAX_CPUS`].
+pub fn init(cpu_id: usize, tss: &'static Tss) {
+    assert!(cpu_id < MAX_CPUS, "CPU {} exceeds MAX_CPUS", cpu_id);
+    let percpu: *mut PerCpu = Box::into_raw(Box::new(PerCpu {
+        self_ptr: core::ptr::null(),
+        syscall_kernel_stack: Cell::new(tss.kernel_stack().as_u64()),
+        syscall_user_stack: Cell::new(0),
+        cpu_id,
+        apic_id: local_apic_id(),
+        tss,
+        current_thread: Cell::new(None),
+        run_queue: SegQueue::new(),
+        any_cpu_run_queue: SegQueue::new(),
+        irq_run_queue: ArrayQueue::new(task::MAX_TASKS),
+        executor_threads: Spinlock::new(Vec::new()),
+        idle_executors: AtomicUsize::new(0),
+        interrupt_counts: [const { AtomicU64::new(0) }; 256],
+        spurious_interrupts: AtomicU64::new(0),
+        interrupt_depth: Cell::new(0),
+    }));
+    unsafe { (*percpu).self_ptr = percpu };
+
+    GsBase::write(VirtAddr::from_ptr(percpu));
+    KernelGsBase::write(VirtAddr::zero());
+    CPUS[cpu_id].store(percpu, Ordering::Release);
+    READY.store(true, Ordering::Release);
+}
+
+/// 编号为 `cpu` 的处理器的 per-CPU 数据, 这个处理器还没有初始化时返回 `None`
+pub fn get(cpu: usize) -> Option<&'static PerCpu> {
+    let percpu = CPUS.get(cpu)?.load(Ordering::Acquire);
+    unsafe { percpu.as_ref() }
+}
+
+/// 所有已经初始化的处理器的 per-CPU 数据, 按 CPU 编号排序
+pub fn cpus() -> impl Iterator<Item = &'static PerCpu> {
+    (0..MAX_CPUS).filter_map(get)
+}
+
+/// 当前处理器的 per-CPU 数据, 通常通过 [`percpu!`] 使用
+///
+/// Panics if [`init`] has not been called.
+pub fn current() -> &'static PerCpu {
+    assert!(READY.load(Ordering::Acquire), "percpu::init has not been called");
+    let percpu: *const PerCpu;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) percpu, options(nostack, preserves_flags, readonly));
+        &*percpu
+    }
+}
+
+/// 当前处理器是否正在处理中断
+pub fn in_interrupt() -> bool {
+    current().interrupt_depth.get() > 0
+}
+
+/// 中断处理函数的上下文, 在创建时进入, drop 时离开
+///
+/// 中断发生在用户态时, 创建和 drop 时都会执行 `swapgs` 。drop 时会重新检查中断栈帧, 所以
+/// 中断处理函数可以修改它 (如: [`crate::userspace::exit_from_interrupt`]) 。
+pub struct InterruptGuard {
+    frame: *const InterruptStackFrame,
+    nested: bool,
+}
+
+impl InterruptGuard {
+    /// 进入中断处理函数, 必须在处理函数访问 per-CPU 数据之前创建
+    ///
+    /// ## Safety
+    /// `frame` 必须是当前中断处理函数的中断栈帧, 且这个值必须在处理函数返回之前被 drop 。
+    pub unsafe fn enter(frame: &InterruptStackFrame) -> Self {
+        if is_user(frame) {
+            asm!("swapgs", options(nostack, preserves_flags));
+        }
+        let depth = &current().interrupt_depth;
+        depth.set(depth.get() + 1);
+        InterruptGuard { frame, nested: true }
+    }
+
+    /// 提前离开中断嵌套 (如: 在时钟中断中切换线程之前), GS base 仍然在 drop 时恢复
+    pub fn leave(&mut self) {
+        if core::mem::take(&mut self.nested) {
+            let depth = &current().interrupt_depth;
+            depth.set(depth.get() - 1);
+        }
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        self.leave();
+        unsafe {
+            if is_user(&*self.frame) {
+                asm!("swapgs", options(nostack, preserves_flags));
+            }
+        }
+    }
+}
+
+fn is_user(frame: &InterruptStackFrame) -> bool {
+    // 栈帧可能在创建 InterruptGuard 之后被修改
+    let code_segment = unsafe { core::ptr::read_volatile(&frame.code_segment) };
+    code_segment & 0b11 == 3
+}
+
+/// 通过 CPUID leaf 1 读取当前处理器的初始 local APIC ID (EBX[31:24])
+fn local_apic_id() -> u32 {
+    let ebx: u64;
+    unsafe {
+        // rbx 被 LLVM 保留, 不能直接作为 asm 的输出
+        asm!(
+            "mov {0}, rbx",
+            "cpuid",
+            "xchg {0}, rbx",
+            out(reg) ebx,
+            inout("eax") 1u32 => _,
+            out("ecx") _,
+            out("edx") _,
+            options(nostack, preserves_flags, nomem),
+        );
+    }
+    (ebx >> 24) as u32 & 0xff
+}
@@ -0,0 +1,119 @@
+//! # 重启和关机
+//!
+//! 重启通过 8042 PS/2 控制器的 CPU reset 线完成, 失败时加载一个空的 IDT 并触发异常, 让处理器
+//! triple fault 。
+//!
+//! 正常的 ACPI 关机需要解析 DSDT 中的 `\_S5` 对象 (AML), 目前还不支持, 所以只尝试模拟器提供的
+//! 关机端口 (QEMU, Bochs 和 VirtualBox), 都失败时停机。
+//!
+//! 重启和关机之前先通过 NMI 让其它处理器停机 (见 `stop_other_cpus`), 这样它们的执行器不会在
+//! 重启或关机的过程中继续访问设备或输出日志。
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use x86_64::instructions::{interrupts, port::Port};
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::VirtAddr;
+
+use crate::{apic, logger, smp};
+
+/// 8042 PS/2 控制器的命令端口
+const PS2_COMMAND_PORT: u16 = 0x64;
+const PS2_STATUS_INPUT_FULL: u8 = 1 << 1;
+/// 拉低 CPU reset 线
+const PS2_COMMAND_RESET: u8 = 0xfe;
+
+/// 模拟器的 ACPI PM1a control 端口和进入 S5 (soft off) 时写入的值
+const SHUTDOWN_PORTS: [(u16, u16); 3] = [
+    // QEMU (piix4 / q35)
+    (0x604, 0x2000),
+    // Bochs 和旧版本的 QEMU
+    (0xb004, 0x2000),
+    // VirtualBox
+    (0x4004, 0x3400),
+];
+
+/// 等待其它处理器停机时轮询的次数
+const STOP_TIMEOUT: u32 = 1_000_000;
+
+/// 某个处理器正在重启或关机, 其它处理器收到 NMI 后停机
+static STOPPING: AtomicBool = AtomicBool::new(false);
+/// 已经停机的处理器的数量
+static STOPPED: AtomicUsize = AtomicUsize::new(0);
+
+pub fn reboot() -> ! {
+    interrupts::disable();
+    log::info!("rebooting");
+    stop_other_cpus();
+
+    unsafe {
+        let mut command = Port::<u8>::new(PS2_COMMAND_PORT);
+        // 等待控制器的输入缓冲区为空
+        for _ in 0..0x10000 {
+            if command.read() & PS2_STATUS_INPUT_FULL == 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        command.write(PS2_COMMAND_RESET);
+    }
+
+    // 控制器没有重启处理器时, 用空的 IDT 让 int3 变成 triple fault
+    unsafe {
+        let idt = DescriptorTablePointer { limit: 0, base: VirtAddr::zero() };
+        x86_64::instructions::tables::lidt(&idt);
+        core::arch::asm!("int3", options(noreturn));
+    }
+}
+
+pub fn shutdown() -> ! {
+    interrupts::disable();
+    log::info!("shutting down");
+    stop_other_cpus();
+
+    for (port, value) in SHUTDOWN_PORTS {
+        unsafe { Port::<u16>::new(port).write(value) };
+    }
+
+    log::warn!("ACPI shutdown is not supported on this machine, it is now safe to turn it off");
+    crate::hlt_loop();
+}
+
+/// 让其它处理器停机, 需要在关中断时调用
+///
+/// 另一个处理器已经在重启或关机时, 当前处理器会等待它的 NMI 并停机, 不会返回。
+fn stop_other_cpus() {
+    if STOPPING.swap(true, Ordering::SeqCst) {
+        crate::hlt_loop();
+    }
+    let others = smp::cpu_count() - 1;
+    if others == 0 || !apic::is_initialized() {
+        return;
+    }
+    apic::send_nmi_to_others();
+
+    let mut stopped = 0;
+    for _ in 0..STOP_TIMEOUT {
+        stopped = STOPPED.load(Ordering::Acquire);
+        if stopped >= others {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    // 其它处理器可能在持有日志的锁时停机, 和 panic 时一样强制释放它
+    if let Some(logger) = logger::LOGGER.get() {
+        unsafe { logger.force_unlock() };
+    }
+    if stopped < others {
+        log::warn!("{} of {} other CPUs did not stop", others - stopped, others);
+    }
+}
+
+/// 由 NMI 处理函数调用, 有处理器正在重启或关机时让当前处理器停机
+pub(crate) fn stop_if_requested() {
+    if STOPPING.load(Ordering::SeqCst) {
+        STOPPED.fetch_add(1, Ordering::Release);
+        // NMI 处理函数返回之前不会再收到 NMI, 中断也是关闭的
+        crate::hlt_loop();
+    }
+}
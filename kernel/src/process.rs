@@ -0,0 +1,325 @@
+//! # 进程
+//!
+//! 一个进程由以下部分组成:
+//!
+//! - PID, 父进程和子进程
+//! - 地址空间 ([`AddressSpace`]), 即进程的四级页表
+//! - 文件描述符表
+//! - 凭证 (uid, gid), 默认继承自父进程
+//! - 退出状态
+//!
+//! 所有进程都保存在进程表中, 可以通过 [`list`] 列出。
+//!
+//! ## 进程的生命周期
+//! ```text
+//!   spawn       被执行器调度      exit / 异常 / kill       wait
+//! -------> Ready ---------> Running ------------> Exited ------> (从进程表中移除)
+//!            |                                      ^
+//!            +----------------- kill ---------------+
+//! ```
+//!
+//! 每个进程由一个内核线程 (见 [`crate::thread`]) 运行, 线程在进程退出后结束。
+//! 已经退出的进程会一直留在进程表中, 直到有人通过 [`wait`] 取走它的退出状态。
+
+use core::fmt;
+use core::future::Future;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::Poll;
+
+use alloc::{collections::BTreeMap, string::{String, ToString}, sync::Arc, vec::Vec};
+
+use crate::{loader, thread::{self, ThreadId}, userspace::{AddressSpace, UserExit}};
+
+use futures_util::task::AtomicWaker;
+use spinning_top::{const_spinlock, Spinlock};
+use x86_64::VirtAddr;
+
+/// 进程表
+static PROCESSES: Spinlock<BTreeMap<Pid, Process>> = const_spinlock(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(u64);
+
+impl Pid {
+    fn new() -> Self {
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// 进程的凭证
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Credentials {
+    pub const ROOT: Credentials = Credentials { uid: 0, gid: 0 };
+}
+
+/// 文件描述符指向的对象
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum File {
+    /// 键盘输入和屏幕输出
+    Console,
+}
+
+/// 进程的退出状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// 通过 `exit` 系统调用退出
+    Exited(i64),
+    /// 因为异常被结束
+    Faulted(UserExit),
+    /// 被 [`kill`] 结束
+    Killed,
+}
+
+impl ExitStatus {
+    /// 与 shell 类似的退出码, 被异常或 kill 结束时为 128 + 信号值
+    pub fn code(&self) -> i64 {
+        match self {
+            ExitStatus::Exited(code) => *code,
+            ExitStatus::Faulted(_) => 128 + 11, // SIGSEGV
+            ExitStatus::Killed => 128 + 9,     // SIGKILL
+        }
+    }
+}
+
+impl From<UserExit> for ExitStatus {
+    fn from(exit: UserExit) -> Self {
+        match exit {
+            UserExit::Exited(code) => ExitStatus::Exited(code),
+            UserExit::Killed => ExitStatus::Killed,
+            fault => ExitStatus::Faulted(fault),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Ready,
+    Running,
+    Exited(ExitStatus),
+}
+
+struct Process {
+    name: String,
+    parent: Option<Pid>,
+    children: Vec<Pid>,
+    credentials: Credentials,
+    files: BTreeMap<u64, File>,
+    /// 进程退出后会被释放
+    address_space: Option<Arc<AddressSpace>>,
+    state: ProcessState,
+    /// 运行这个进程的线程, 进程开始运行前为 `None`
+    thread: Option<ThreadId>,
+    /// 正在运行的进程被 kill 了, 由时钟中断检查
+    kill_requested: bool,
+    /// 等待这个进程退出的任务
+    waker: AtomicWaker,
+}
+
+/// 进程表中一个进程的信息, 由 [`list`] 返回
+#[derive(Debug, Clone)]
+pub struct ProcessInfo {
+    pub pid: Pid,
+    pub parent: Option<Pid>,
+    pub name: String,
+    pub credentials: Credentials,
+    pub state: ProcessState,
+    /// 四级页表所在的物理地址, 进程退出后为 `None`
+    pub level_4_table: Option<u64>,
+}
+
+/// 加载 ELF 文件 `elf` 并创建一个新进程, 父进程为当前进程
+///
+/// 进程会在它的线程下一次被调度时开始运行。
+pub fn spawn(name: &str, elf: &[u8], argv: &[&str], envp: &[&str]) -> Result<Pid, &'static str> {
+    let program = loader::load(elf, argv, envp)?;
+    let (address_space, entry_point, stack_pointer) = program.into_parts();
+    let address_space = Arc::new(address_space);
+
+    let pid = Pid::new();
+    let parent = current();
+    let mut processes = PROCESSES.lock();
+    let credentials = match parent.and_then(|parent| processes.get_mut(&parent)) {
+        Some(parent) => {
+            parent.children.push(pid);
+            parent.credentials
+        }
+        None => Credentials::ROOT,
+    };
+
+    let files = [(0, File::Console), (1, File::Console), (2, File::Console)]
+        .into_iter()
+        .collect();
+    processes.insert(pid, Process {
+        name: name.to_string(),
+        parent,
+        children: Vec::new(),
+        credentials,
+        files,
+        address_space: Some(address_space.clone()),
+        state: ProcessState::Ready,
+        thread: None,
+        kill_requested: false,
+        waker: AtomicWaker::new(),
+    });
+    drop(processes);
+
+    thread::spawn(name, move || run(pid, address_space, entry_point, stack_pointer));
+    Ok(pid)
+}
+
+/// 等待进程 `pid` 退出, 并将它从进程表中移除
+///
+/// 如果进程不存在 (或者已经被其它任务 `wait` 过了), 返回 `None` 。
+pub fn wait(pid: Pid) -> impl Future<Output = Option<ExitStatus>> {
+    futures_util::future::poll_fn(move |cx| {
+        let mut processes = PROCESSES.lock();
+        let process = match processes.get(&pid) {
+            Some(process) => process,
+            None => return Poll::Ready(None),
+        };
+
+        if let ProcessState::Exited(status) = process.state {
+            let process = processes.remove(&pid).expect("process disappeared");
+            if let Some(parent) = process.parent.and_then(|parent| processes.get_mut(&parent)) {
+                parent.children.retain(|&child| child != pid);
+            }
+            return Poll::Ready(Some(status));
+        }
+
+        // 进程只会在持有锁的情况下退出, 所以不会错过唤醒
+        process.waker.register(cx.waker());
+        Poll::Pending
+    })
+}
+
+/// 结束进程 `pid`
+///
+/// 还没有开始运行的进程会被直接结束, 正在运行的进程会在下一次时钟中断时被结束。
+/// 返回进程是否存在且还没有退出。
+pub fn kill(pid: Pid) -> bool {
+    let mut processes = PROCESSES.lock();
+    let process = match processes.get_mut(&pid) {
+        Some(process) => process,
+        None => return false,
+    };
+
+    match process.state {
+        ProcessState::Ready => {
+            exit(&mut processes, pid, ExitStatus::Killed);
+            true
+        }
+        ProcessState::Running => {
+            process.kill_requested = true;
+            true
+        }
+        ProcessState::Exited(_) => false,
+    }
+}
+
+/// 列出进程表中的所有进程
+pub fn list() -> Vec<ProcessInfo> {
+    PROCESSES
+        .lock()
+        .iter()
+        .map(|(&pid, process)| ProcessInfo {
+            pid,
+            parent: process.parent,
+            name: process.name.clone(),
+            credentials: process.credentials,
+            state: process.state,
+            level_4_table: process
+                .address_space
+                .as_ref()
+                .map(|space| space.level_4_frame().start_address().as_u64()),
+        })
+        .collect()
+}
+
+/// 当前线程正在运行的进程
+pub fn current() -> Option<Pid> {
+    let thread = thread::current();
+    PROCESSES
+        .lock()
+        .iter()
+        .find(|(_, process)| process.state == ProcessState::Running && process.thread == Some(thread))
+        .map(|(&pid, _)| pid)
+}
+
+/// 在当前进程的文件描述符表中查找 `fd`
+///
+/// 没有正在运行的进程时 (如: 直接通过 [`AddressSpace::run`] 运行的程序), 使用默认的
+/// 标准输入、标准输出和标准错误。
+pub fn file(fd: u64) -> Option<File> {
+    match current() {
+        Some(pid) => PROCESSES.lock().get(&pid)?.files.get(&fd).copied(),
+        None if fd <= 2 => Some(File::Console),
+        None => None,
+    }
+}
+
+/// 当前线程正在运行的进程是否需要被结束, 由时钟中断调用
+///
+/// 被打断的代码可能正持有进程表的锁, 这时返回 `false`, 在之后的时钟中断中再检查。
+pub(crate) fn kill_pending() -> bool {
+    let thread = thread::current();
+    PROCESSES.try_lock().is_some_and(|processes| {
+        processes
+            .values()
+            .any(|process| process.thread == Some(thread) && process.kill_requested)
+    })
+}
+
+fn run(pid: Pid, address_space: Arc<AddressSpace>, entry_point: VirtAddr, stack_pointer: VirtAddr) {
+    {
+        let mut processes = PROCESSES.lock();
+        match processes.get_mut(&pid) {
+            Some(process) if process.state == ProcessState::Ready => {
+                process.state = ProcessState::Running;
+                process.thread = Some(thread::current());
+            }
+            // 在开始运行之前就被 kill 了
+            _ => return,
+        }
+    }
+
+    // 入口地址和栈都已经由 loader 检查和映射过了
+    let user_exit = unsafe { address_space.run(entry_point, stack_pointer) };
+
+    let status = ExitStatus::from(user_exit);
+    log::info!("process {} exited: {:?}", pid, status);
+    exit(&mut PROCESSES.lock(), pid, status);
+}
+
+/// 将进程标记为已退出, 释放它的资源, 并唤醒等待它的任务
+fn exit(processes: &mut BTreeMap<Pid, Process>, pid: Pid, status: ExitStatus) {
+    let process = processes.get_mut(&pid).expect("exiting process not in process table");
+    process.state = ProcessState::Exited(status);
+    process.thread = None;
+    process.kill_requested = false;
+    process.address_space = None;
+    process.files.clear();
+    process.waker.wake();
+
+    // 子进程成为孤儿进程
+    for child in core::mem::take(&mut process.children) {
+        if let Some(child) = processes.get_mut(&child) {
+            child.parent = None;
+        }
+    }
+}
@@ -0,0 +1,660 @@
+//! # PS/2 控制器 (8042)
+//! 参考: https://wiki.osdev.org/%228042%22_PS/2_Controller, https://wiki.osdev.org/PS/2_Keyboard
+//!
+//! [`init`] 不依赖 BIOS 的设置: 它先禁用两个端口, 进行控制器和端口的自检, 然后复位并识别每个端口
+//! 上的设备, 最后只启用有设备的端口和它们的中断。控制器保持 scancode 翻译 (set 2 转换成 set 1),
+//! 所以键盘驱动仍然按 set 1 解码。
+//!
+//! 初始化期间 (启用中断之前) 通过 [`send`] 向设备发送命令: 持有控制器的锁并暂时关闭端口中断,
+//! 轮询读取设备的回复。之后的命令 (如: [`set_leds`]) 通过 [`queue`] 加入端口的发送队列后立即返回,
+//! 端口中断保持开启: 中断处理函数收到 ACK 后发送队列中的下一个字节, 收到 RESEND 时重发。
+//!
+//! 设备发送的数据交给端口的接收者 (见 [`set_receiver`]), 包括轮询时读到的另一个端口的字节和等待回复时
+//! 收到的其它字节 (如: 按键)。等待回复时只有 ACK 和 RESEND 被当作回复, 所以鼠标应该在停止发送数据时
+//! 接收命令。
+
+use core::fmt;
+use core::time::Duration;
+
+use x86_64::instructions::port::Port as IoPort;
+
+use crate::irq::IrqReturn;
+use crate::sync::IrqSpinlock;
+use crate::time;
+
+const DATA_PORT: u16 = 0x60;
+/// 读时为状态寄存器, 写时为命令寄存器
+const COMMAND_PORT: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+/// 输出缓冲区中的字节来自第二个端口
+const STATUS_SECOND_PORT_DATA: u8 = 1 << 5;
+
+// 控制器命令
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const DISABLE_SECOND: u8 = 0xa7;
+const ENABLE_SECOND: u8 = 0xa8;
+const TEST_SECOND: u8 = 0xa9;
+const SELF_TEST: u8 = 0xaa;
+const TEST_FIRST: u8 = 0xab;
+const DISABLE_FIRST: u8 = 0xad;
+const ENABLE_FIRST: u8 = 0xae;
+/// 把下一个写入数据端口的字节发送给第二个端口上的设备
+const WRITE_SECOND: u8 = 0xd4;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+// 配置字节
+const CONFIG_FIRST_INTERRUPT: u8 = 1;
+const CONFIG_SECOND_INTERRUPT: u8 = 1 << 1;
+const CONFIG_SECOND_CLOCK_DISABLED: u8 = 1 << 5;
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+// 设备命令和回复
+const DEVICE_SET_LEDS: u8 = 0xed;
+const DEVICE_IDENTIFY: u8 = 0xf2;
+const DEVICE_SET_TYPEMATIC: u8 = 0xf3;
+const DEVICE_ENABLE_SCANNING: u8 = 0xf4;
+const DEVICE_DISABLE_SCANNING: u8 = 0xf5;
+const DEVICE_RESET: u8 = 0xff;
+const DEVICE_ACK: u8 = 0xfa;
+const DEVICE_RESEND: u8 = 0xfe;
+const DEVICE_SELF_TEST_PASSED: u8 = 0xaa;
+/// MF2 键盘回复 identify 的第一个字节
+const KEYBOARD_ID: u8 = 0xab;
+
+/// 设备要求重发时最多发送的次数
+const ATTEMPTS: usize = 3;
+/// 轮询状态寄存器的次数, 每次读端口大约需要 1 微秒
+const TIMEOUT: u32 = 100_000;
+/// 设备复位后的自检 (BAT) 可能需要几百毫秒
+const RESET_TIMEOUT: u32 = 1_000_000;
+/// 中断启用后等待设备回复的时间, 超时的命令在下一次加入队列时被丢弃
+const REPLY_TIMEOUT: Duration = Duration::from_millis(100);
+/// 每个端口的发送队列的长度
+const QUEUE_SIZE: usize = 16;
+
+/// 初始化时设置的键盘重复延迟 (毫秒) 和速率 (每秒次数)
+const DEFAULT_TYPEMATIC_DELAY: u32 = 250;
+const DEFAULT_TYPEMATIC_RATE: u32 = 30;
+/// 每秒重复次数 × 10, 下标为 typematic 字节的低 5 位
+const TYPEMATIC_RATES: [u32; 32] = [
+    300, 267, 240, 218, 207, 185, 171, 160, 150, 133, 120, 109, 100, 92, 86, 80,
+    75, 67, 60, 55, 50, 46, 43, 40, 37, 33, 30, 27, 25, 23, 21, 20,
+];
+
+static CONTROLLER: IrqSpinlock<Controller> = IrqSpinlock::new(Controller {
+    config: 0,
+    dual: false,
+    devices: [None, None],
+    queues: [Queue::new(), Queue::new()],
+});
+
+/// 每个端口上的设备发送的数据的接收者, 可能在中断处理函数中调用
+static RECEIVERS: [IrqSpinlock<Option<Receiver>>; 2] = [IrqSpinlock::new(None), IrqSpinlock::new(None)];
+
+type Receiver = fn(u8);
+
+/// 控制器的端口, 第一个端口通常连接键盘, 第二个 (aux) 端口通常连接鼠标
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Port {
+    First,
+    Second,
+}
+
+impl Port {
+    const ALL: [Port; 2] = [Port::First, Port::Second];
+
+    fn index(self) -> usize {
+        match self {
+            Port::First => 0,
+            Port::Second => 1,
+        }
+    }
+
+    fn other(self) -> Port {
+        match self {
+            Port::First => Port::Second,
+            Port::Second => Port::First,
+        }
+    }
+
+    fn interrupt(self) -> u8 {
+        match self {
+            Port::First => CONFIG_FIRST_INTERRUPT,
+            Port::Second => CONFIG_SECOND_INTERRUPT,
+        }
+    }
+}
+
+impl fmt::Display for Port {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "port {}", self.index() + 1)
+    }
+}
+
+/// 通过 identify 命令识别出的设备
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Device {
+    /// 不回复 identify 的老式 AT 键盘
+    AtKeyboard,
+    Mf2Keyboard,
+    Mouse,
+    /// 有滚轮的 IntelliMouse
+    ScrollMouse,
+    /// 有滚轮和 5 个按键的 IntelliMouse
+    FiveButtonMouse,
+    Unknown(u8),
+}
+
+impl Device {
+    fn from_id(id: Option<u8>) -> Self {
+        match id {
+            None => Device::AtKeyboard,
+            Some(KEYBOARD_ID) => Device::Mf2Keyboard,
+            Some(0x00) => Device::Mouse,
+            Some(0x03) => Device::ScrollMouse,
+            Some(0x04) => Device::FiveButtonMouse,
+            Some(id) => Device::Unknown(id),
+        }
+    }
+
+    pub fn is_keyboard(self) -> bool {
+        matches!(self, Device::AtKeyboard | Device::Mf2Keyboard)
+    }
+
+    pub fn is_mouse(self) -> bool {
+        matches!(self, Device::Mouse | Device::ScrollMouse | Device::FiveButtonMouse)
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Device::AtKeyboard => write!(f, "AT keyboard"),
+            Device::Mf2Keyboard => write!(f, "MF2 keyboard"),
+            Device::Mouse => write!(f, "mouse"),
+            Device::ScrollMouse => write!(f, "mouse with scroll wheel"),
+            Device::FiveButtonMouse => write!(f, "5-button mouse"),
+            Device::Unknown(id) => write!(f, "unknown device {:#04x}", id),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// 控制器或设备没有在规定时间内回复
+    Timeout,
+    SelfTestFailed(u8),
+    PortTestFailed(Port, u8),
+    /// 端口上没有设备, 或者不是这个命令需要的设备
+    NoDevice(Port),
+    /// 设备的回复不是 ACK
+    UnexpectedResponse(Port, u8),
+    /// 端口的发送队列中没有足够的空间
+    QueueFull(Port),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Timeout => write!(f, "PS/2 controller timed out"),
+            Error::SelfTestFailed(response) => write!(f, "PS/2 controller self test failed: {:#04x}", response),
+            Error::PortTestFailed(port, response) => write!(f, "PS/2 {} test failed: {:#04x}", port, response),
+            Error::NoDevice(port) => write!(f, "no suitable device on PS/2 {}", port),
+            Error::UnexpectedResponse(port, response) => {
+                write!(f, "unexpected response {:#04x} from PS/2 {}", response, port)
+            }
+            Error::QueueFull(port) => write!(f, "PS/2 {} command queue is full", port),
+        }
+    }
+}
+
+/// 键盘的指示灯
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Leds {
+    pub scroll_lock: bool,
+    pub num_lock: bool,
+    pub caps_lock: bool,
+}
+
+impl Leds {
+    fn bits(self) -> u8 {
+        u8::from(self.scroll_lock) | u8::from(self.num_lock) << 1 | u8::from(self.caps_lock) << 2
+    }
+}
+
+struct Controller {
+    /// 初始化完成后的配置字节, 发送设备命令时用来恢复端口中断
+    config: u8,
+    /// 是否有第二个端口
+    dual: bool,
+    devices: [Option<Device>; 2],
+    queues: [Queue; 2],
+}
+
+/// 中断启用后发送给设备的字节, 设备回复 ACK 后才发送下一个
+struct Queue {
+    bytes: [u8; QUEUE_SIZE],
+    head: usize,
+    len: usize,
+    /// 已经发送、正在等待回复的字节
+    pending: Option<Pending>,
+}
+
+#[derive(Clone, Copy)]
+struct Pending {
+    byte: u8,
+    attempts: usize,
+    sent_at: Duration,
+}
+
+impl Queue {
+    const fn new() -> Self {
+        Queue { bytes: [0; QUEUE_SIZE], head: 0, len: 0, pending: None }
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.bytes[(self.head + self.len) % QUEUE_SIZE] = byte;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.bytes[self.head];
+        self.head = (self.head + 1) % QUEUE_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+        self.pending = None;
+    }
+}
+
+impl Controller {
+    fn status(&self) -> u8 {
+        unsafe { IoPort::new(COMMAND_PORT).read() }
+    }
+
+    fn read_data(&self) -> u8 {
+        unsafe { IoPort::new(DATA_PORT).read() }
+    }
+
+    fn write(&mut self, port: u16, byte: u8) -> Result<(), Error> {
+        for _ in 0..TIMEOUT {
+            if self.status() & STATUS_INPUT_FULL == 0 {
+                unsafe { IoPort::new(port).write(byte) };
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(Error::Timeout)
+    }
+
+    fn command(&mut self, command: u8) -> Result<(), Error> {
+        self.write(COMMAND_PORT, command)
+    }
+
+    /// 等待控制器的回复
+    fn read(&mut self, timeout: u32) -> Result<u8, Error> {
+        for _ in 0..timeout {
+            if self.status() & STATUS_OUTPUT_FULL != 0 {
+                return Ok(self.read_data());
+            }
+            core::hint::spin_loop();
+        }
+        Err(Error::Timeout)
+    }
+
+    /// 等待 `port` 上的设备发送的字节, 来自另一个端口的字节交给它的接收者
+    fn read_from(&mut self, port: Port, timeout: u32) -> Result<u8, Error> {
+        for _ in 0..timeout {
+            let status = self.status();
+            if status & STATUS_OUTPUT_FULL != 0 {
+                let byte = self.read_data();
+                if self.is_from(port, status) {
+                    return Ok(byte);
+                }
+                deliver(port.other(), byte);
+            }
+            core::hint::spin_loop();
+        }
+        Err(Error::Timeout)
+    }
+
+    fn is_from(&self, port: Port, status: u8) -> bool {
+        !self.dual || (status & STATUS_SECOND_PORT_DATA != 0) == (port == Port::Second)
+    }
+
+    /// 清空输出缓冲区
+    fn flush(&mut self) {
+        for _ in 0..TIMEOUT {
+            if self.status() & STATUS_OUTPUT_FULL == 0 {
+                return;
+            }
+            self.read_data();
+        }
+    }
+
+    fn read_config(&mut self) -> Result<u8, Error> {
+        self.command(READ_CONFIG)?;
+        self.read(TIMEOUT)
+    }
+
+    fn write_config(&mut self, config: u8) -> Result<(), Error> {
+        self.command(WRITE_CONFIG)?;
+        self.write(DATA_PORT, config)
+    }
+
+    fn write_device(&mut self, port: Port, byte: u8) -> Result<(), Error> {
+        if port == Port::Second {
+            self.command(WRITE_SECOND)?;
+        }
+        self.write(DATA_PORT, byte)
+    }
+
+    /// 向设备发送一个字节并等待 ACK, 设备要求重发时重试
+    fn send(&mut self, port: Port, byte: u8) -> Result<(), Error> {
+        let mut response = DEVICE_RESEND;
+        for _ in 0..ATTEMPTS {
+            self.write_device(port, byte)?;
+            // ACK 之前到达的数据 (如: 按键) 交给接收者
+            response = loop {
+                match self.read_from(port, TIMEOUT)? {
+                    response @ (DEVICE_ACK | DEVICE_RESEND) => break response,
+                    other => deliver(port, other),
+                }
+            };
+            if response == DEVICE_ACK {
+                return Ok(());
+            }
+        }
+        Err(Error::UnexpectedResponse(port, response))
+    }
+
+    /// 在端口中断关闭时和设备通信, 这样设备的回复不会进入中断处理函数
+    fn without_port_interrupts<R>(&mut self, f: impl FnOnce(&mut Self) -> Result<R, Error>) -> Result<R, Error> {
+        self.write_config(self.config & !(CONFIG_FIRST_INTERRUPT | CONFIG_SECOND_INTERRUPT))?;
+        let result = f(self);
+        self.write_config(self.config)?;
+        result
+    }
+
+    fn send_all(&mut self, port: Port, bytes: &[u8]) -> Result<(), Error> {
+        self.without_port_interrupts(|controller| bytes.iter().try_for_each(|&byte| controller.send(port, byte)))
+    }
+
+    /// 把 `bytes` 加入 `port` 的发送队列, 没有字节在等待回复时立即发送第一个
+    fn enqueue(&mut self, port: Port, bytes: &[u8]) -> Result<(), Error> {
+        let queue = &mut self.queues[port.index()];
+        if let Some(pending) = queue.pending {
+            if time::uptime().saturating_sub(pending.sent_at) > REPLY_TIMEOUT {
+                log::warn!("ps2: no reply to {:#04x} from {}, dropping {} queued bytes", pending.byte, port, queue.len);
+                queue.clear();
+            }
+        }
+        if QUEUE_SIZE - queue.len < bytes.len() {
+            return Err(Error::QueueFull(port));
+        }
+        for &byte in bytes {
+            queue.push(byte);
+        }
+        self.send_next(port)
+    }
+
+    /// 没有字节在等待回复时发送队列中的下一个字节
+    fn send_next(&mut self, port: Port) -> Result<(), Error> {
+        let queue = &mut self.queues[port.index()];
+        if queue.pending.is_some() {
+            return Ok(());
+        }
+        let byte = match queue.pop() {
+            Some(byte) => byte,
+            None => return Ok(()),
+        };
+        queue.pending = Some(Pending { byte, attempts: 1, sent_at: time::uptime() });
+        let result = self.write_device(port, byte);
+        if result.is_err() {
+            self.queues[port.index()].clear();
+        }
+        result
+    }
+
+    /// 处理中断时从 `port` 读到的字节, 返回不是命令回复的字节
+    fn receive(&mut self, port: Port, byte: u8) -> Option<u8> {
+        let queue = &mut self.queues[port.index()];
+        let pending = match queue.pending {
+            Some(pending) => pending,
+            None => return Some(byte),
+        };
+        let result = match byte {
+            DEVICE_ACK => {
+                queue.pending = None;
+                self.send_next(port)
+            }
+            DEVICE_RESEND if pending.attempts < ATTEMPTS => {
+                queue.pending = Some(Pending { attempts: pending.attempts + 1, sent_at: time::uptime(), ..pending });
+                let result = self.write_device(port, pending.byte);
+                if result.is_err() {
+                    self.queues[port.index()].clear();
+                }
+                result
+            }
+            DEVICE_RESEND => {
+                log::warn!("ps2: {} rejected {:#04x}, dropping {} queued bytes", port, pending.byte, queue.len);
+                queue.clear();
+                Ok(())
+            }
+            _ => return Some(byte),
+        };
+        if let Err(err) = result {
+            log::warn!("ps2: failed to send a queued byte to {}: {}", port, err);
+        }
+        None
+    }
+
+    fn init(&mut self) -> Result<(), Error> {
+        // 禁用两个端口, 防止设备在初始化期间发送数据
+        self.command(DISABLE_FIRST)?;
+        self.command(DISABLE_SECOND)?;
+        self.flush();
+
+        // 关闭端口中断, 保留 scancode 翻译
+        let mut config = self.read_config()?;
+        config &= !(CONFIG_FIRST_INTERRUPT | CONFIG_SECOND_INTERRUPT);
+        config |= CONFIG_TRANSLATION;
+        self.write_config(config)?;
+
+        // 有的控制器在自检后会复位, 所以之后要重新写入配置字节
+        self.command(SELF_TEST)?;
+        match self.read(TIMEOUT)? {
+            SELF_TEST_PASSED => {}
+            response => return Err(Error::SelfTestFailed(response)),
+        }
+        self.write_config(config)?;
+        self.config = config;
+
+        // 第二个端口被禁用时时钟禁用位为 1, 启用后仍为 1 说明没有第二个端口
+        if config & CONFIG_SECOND_CLOCK_DISABLED != 0 {
+            self.command(ENABLE_SECOND)?;
+            self.dual = self.read_config()? & CONFIG_SECOND_CLOCK_DISABLED == 0;
+            self.command(DISABLE_SECOND)?;
+        }
+
+        for port in Port::ALL {
+            if port == Port::Second && !self.dual {
+                continue;
+            }
+            self.command(if port == Port::First { TEST_FIRST } else { TEST_SECOND })?;
+            match self.read(TIMEOUT)? {
+                PORT_TEST_PASSED => {}
+                response => {
+                    log::warn!("{}", Error::PortTestFailed(port, response));
+                    continue;
+                }
+            }
+
+            self.command(if port == Port::First { ENABLE_FIRST } else { ENABLE_SECOND })?;
+            match self.detect(port) {
+                Ok(device) => {
+                    log::info!("ps2: {}: {}", port, device);
+                    self.devices[port.index()] = Some(device);
+                }
+                Err(err) => {
+                    log::info!("ps2: nothing on {}: {}", port, err);
+                    self.command(if port == Port::First { DISABLE_FIRST } else { DISABLE_SECOND })?;
+                }
+            }
+        }
+
+        // 启用端口会修改配置字节中的时钟禁用位, 所以重新读取
+        self.config = self.read_config()?;
+        for port in Port::ALL {
+            if self.devices[port.index()].is_some() {
+                self.config |= port.interrupt();
+            }
+        }
+        self.write_config(self.config)
+    }
+
+    /// 复位并识别设备, 只有键盘会被启用扫描, 鼠标由它的驱动启用
+    fn detect(&mut self, port: Port) -> Result<Device, Error> {
+        self.send(port, DEVICE_RESET)?;
+        match self.read_from(port, RESET_TIMEOUT)? {
+            DEVICE_SELF_TEST_PASSED => {}
+            response => return Err(Error::UnexpectedResponse(port, response)),
+        }
+
+        // 鼠标在自检成功后还会发送它的 ID, 会在等待 ACK 时交给接收者 (这时还没有接收者)
+        self.send(port, DEVICE_DISABLE_SCANNING)?;
+        let device = self.identify(port)?;
+
+        if device.is_keyboard() {
+            let typematic = typematic_byte(DEFAULT_TYPEMATIC_DELAY, DEFAULT_TYPEMATIC_RATE);
+            self.send(port, DEVICE_SET_TYPEMATIC)?;
+            self.send(port, typematic)?;
+            self.send(port, DEVICE_ENABLE_SCANNING)?;
+        }
+        Ok(device)
+    }
+
+    fn identify(&mut self, port: Port) -> Result<Device, Error> {
+        self.send(port, DEVICE_IDENTIFY)?;
+        // AT 键盘不回复, MF2 键盘回复两个字节, 鼠标回复一个字节
+        let id = self.read_from(port, TIMEOUT).ok();
+        if id == Some(KEYBOARD_ID) {
+            let _ = self.read_from(port, TIMEOUT);
+        }
+        Ok(Device::from_id(id))
+    }
+
+    fn keyboard_port(&self) -> Result<Port, Error> {
+        Port::ALL
+            .into_iter()
+            .find(|port| self.devices[port.index()].is_some_and(Device::is_keyboard))
+            .ok_or(Error::NoDevice(Port::First))
+    }
+}
+
+/// 初始化控制器并识别设备, 需要在启用中断之前调用
+pub fn init() -> Result<(), Error> {
+    CONTROLLER.lock().init()
+}
+
+/// `port` 上的设备, 没有设备或初始化失败时为 `None`
+pub fn device(port: Port) -> Option<Device> {
+    CONTROLLER.lock().devices[port.index()]
+}
+
+/// 设置 `port` 上的设备发送的数据的接收者, 它可能在中断处理函数中调用, 不能阻塞或分配内存
+pub fn set_receiver(port: Port, receiver: Receiver) {
+    *RECEIVERS[port.index()].lock() = Some(receiver);
+}
+
+/// 向 `port` 上的设备依次发送 `bytes` (命令和它的参数), 每个字节都需要设备回复 ACK。
+/// 轮询等待回复, 只能在启用中断之前使用 (如: 驱动初始化时), 之后使用 [`queue`]
+pub fn send(port: Port, bytes: &[u8]) -> Result<(), Error> {
+    let mut controller = CONTROLLER.lock();
+    if controller.devices[port.index()].is_none() {
+        return Err(Error::NoDevice(port));
+    }
+    controller.send_all(port, bytes)
+}
+
+/// 把 `bytes` 加入 `port` 的发送队列后立即返回, 由中断处理函数在设备回复 ACK 后发送下一个字节。
+/// 设备拒绝或者不回复时只记录日志
+pub fn queue(port: Port, bytes: &[u8]) -> Result<(), Error> {
+    let mut controller = CONTROLLER.lock();
+    if controller.devices[port.index()].is_none() {
+        return Err(Error::NoDevice(port));
+    }
+    controller.enqueue(port, bytes)
+}
+
+/// 重新识别 `port` 上的设备并更新 [`device`] 的返回值, 如: 鼠标启用滚轮之后 ID 会改变。
+/// 设备需要先停止发送数据, 和 [`send`] 一样只能在启用中断之前使用
+pub fn identify(port: Port) -> Result<Device, Error> {
+    let mut controller = CONTROLLER.lock();
+    if controller.devices[port.index()].is_none() {
+        return Err(Error::NoDevice(port));
+    }
+    let device = controller.without_port_interrupts(|controller| controller.identify(port))?;
+    controller.devices[port.index()] = Some(device);
+    Ok(device)
+}
+
+/// 设置键盘的指示灯, 不等待键盘回复
+pub fn set_leds(leds: Leds) -> Result<(), Error> {
+    let mut controller = CONTROLLER.lock();
+    let port = controller.keyboard_port()?;
+    controller.enqueue(port, &[DEVICE_SET_LEDS, leds.bits()])
+}
+
+/// 设置按住键盘按键时的重复延迟 (250 到 1000 毫秒) 和速率 (每秒 2 到 30 次), 取最接近的值。
+/// 不等待键盘回复
+pub fn set_typematic(delay_ms: u32, rate: u32) -> Result<(), Error> {
+    let mut controller = CONTROLLER.lock();
+    let port = controller.keyboard_port()?;
+    controller.enqueue(port, &[DEVICE_SET_TYPEMATIC, typematic_byte(delay_ms, rate)])
+}
+
+fn typematic_byte(delay_ms: u32, rate: u32) -> u8 {
+    let delay = (delay_ms.saturating_add(125) / 250).clamp(1, 4) - 1;
+    let rate = TYPEMATIC_RATES
+        .iter()
+        .position(|&r| r <= rate.saturating_mul(10))
+        .unwrap_or(TYPEMATIC_RATES.len() - 1);
+    (delay as u8) << 5 | rate as u8
+}
+
+/// 处理 `port` 的中断: 读取设备发送的字节, 命令的回复由发送队列处理, 其它字节交给接收者。
+/// 输出缓冲区为空或者字节来自另一个端口时返回 [`IrqReturn::NotHandled`]
+pub(crate) fn handle_interrupt(port: Port) -> IrqReturn {
+    let mut controller = CONTROLLER.lock();
+    let status = controller.status();
+    if status & STATUS_OUTPUT_FULL == 0 || !controller.is_from(port, status) {
+        return IrqReturn::NotHandled;
+    }
+    let byte = controller.read_data();
+    let data = controller.receive(port, byte);
+    drop(controller);
+    if let Some(byte) = data {
+        deliver(port, byte);
+    }
+    IrqReturn::Handled
+}
+
+fn deliver(port: Port, byte: u8) {
+    let receiver = *RECEIVERS[port.index()].lock();
+    match receiver {
+        Some(receiver) => receiver(byte),
+        None => log::debug!("ps2: no receiver for byte {:#04x} from {}", byte, port),
+    }
+}
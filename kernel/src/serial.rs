@@ -0,0 +1,129 @@
+//! # 串口 (COM1)
+//! 参考: https://wiki.osdev.org/Serial_Ports
+//!
+//! COM1 是一个 16550 UART, 配置为 38400 baud, 8N1 。发送时轮询 Line Status Register,
+//! 接收时由 IRQ 4 的处理函数把收到的字节放入 [`IrqChannel`], 通过 [`input`] 读取。
+//!
+//! QEMU 中可以通过 `-serial stdio` 把 COM1 连接到终端。
+
+use core::fmt;
+
+use x86_64::instructions::port::Port;
+
+use crate::irq::{self, IrqReturn, Sharing};
+use crate::sync::{IrqChannel, IrqSpinlock, IrqStream};
+
+const COM1: u16 = 0x3f8;
+/// COM1 的 IRQ 线
+pub const COM1_IRQ: u8 = 4;
+
+// 寄存器相对于 COM1 的偏移, DLAB 为 1 时前两个寄存器是分频系数
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+/// 115200 / 3 = 38400 baud
+const DIVISOR: u16 = 3;
+const LINE_CONTROL_DLAB: u8 = 1 << 7;
+/// 8 个数据位, 无校验, 1 个停止位
+const LINE_CONTROL_8N1: u8 = 0b11;
+/// 启用并清空 FIFO, 收到 14 个字节时触发中断
+const FIFO_ENABLE: u8 = 0xc7;
+/// DTR, RTS 和 OUT2, 不设置 OUT2 时 UART 不会发出中断
+const MODEM_CONTROL_NORMAL: u8 = 0x0b;
+/// 收到数据时触发中断
+const INTERRUPT_DATA_AVAILABLE: u8 = 1;
+const LINE_STATUS_DATA_READY: u8 = 1;
+const LINE_STATUS_TRANSMIT_EMPTY: u8 = 1 << 5;
+
+/// 收到的字节, 由 IRQ 4 的处理函数放入
+static INPUT: IrqChannel<u8, 256> = IrqChannel::new();
+
+/// 发送时持有, 防止不同处理器的输出交错
+static PORT: IrqSpinlock<SerialPort> = IrqSpinlock::new(SerialPort { base: COM1 });
+
+struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+    fn register(&self, offset: u16) -> Port<u8> {
+        Port::new(self.base + offset)
+    }
+
+    fn init(&mut self) {
+        unsafe {
+            self.register(INTERRUPT_ENABLE).write(0);
+            self.register(LINE_CONTROL).write(LINE_CONTROL_DLAB);
+            self.register(DATA).write(DIVISOR as u8);
+            self.register(INTERRUPT_ENABLE).write((DIVISOR >> 8) as u8);
+            self.register(LINE_CONTROL).write(LINE_CONTROL_8N1);
+            self.register(FIFO_CONTROL).write(FIFO_ENABLE);
+            self.register(MODEM_CONTROL).write(MODEM_CONTROL_NORMAL);
+            self.register(INTERRUPT_ENABLE).write(INTERRUPT_DATA_AVAILABLE);
+        }
+    }
+
+    fn line_status(&self) -> u8 {
+        unsafe { self.register(LINE_STATUS).read() }
+    }
+
+    fn send(&mut self, byte: u8) {
+        while self.line_status() & LINE_STATUS_TRANSMIT_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        unsafe { self.register(DATA).write(byte) };
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        if self.line_status() & LINE_STATUS_DATA_READY == 0 {
+            return None;
+        }
+        Some(unsafe { self.register(DATA).read() })
+    }
+}
+
+/// 初始化 COM1, 并注册接收数据的中断处理函数
+pub fn init() {
+    PORT.lock().init();
+    irq::register_irq(COM1_IRQ, "serial", Sharing::Exclusive, serial_interrupt)
+        .expect("failed to register the serial interrupt handler");
+}
+
+/// 读出 FIFO 中所有的字节, 在中断处理函数中调用, 不会分配内存
+fn serial_interrupt() -> IrqReturn {
+    let mut port = PORT.lock();
+    let mut handled = IrqReturn::NotHandled;
+    while let Some(byte) = port.receive() {
+        handled = IrqReturn::Handled;
+        if INPUT.push(byte).is_err() {
+            log::warn!("serial input queue full; dropping input");
+        }
+    }
+    handled
+}
+
+/// 从串口收到的字节, 只能调用一次
+pub fn input() -> IrqStream<u8> {
+    INPUT.stream()
+}
+
+/// 通过串口输出, 把 `'\n'` 转换成 `"\r\n"`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SerialWriter;
+
+impl fmt::Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut port = PORT.lock();
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                port.send(b'\r');
+            }
+            port.send(byte);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,339 @@
+//! # 调试 shell
+//!
+//! 一个用于查看和修改运行中的内核的命令行, 输入 `help` 列出所有命令。它建立在 [`crate::tty`]
+//! 之上, 所以可以同时运行在键盘和屏幕 ([`KeyboardInput`] 和 [`Console`]) 以及串口
+//! ([`TerminalInput`] 和 [`SerialWriter`]) 上:
+//!
+//! ```ignore
+//! Builder::new().name("shell").spawn(Shell::console().run())?;
+//! Builder::new().name("serial-shell").spawn(Shell::serial().run())?;
+//! ```
+//!
+//! `peek` 和 `poke` 会先检查地址是否已经被映射 (`poke` 还会检查是否可写), 但仍然可以修改任何
+//! 内核数据, 只用于调试。
+//!
+//! [`KeyboardInput`]: crate::tty::KeyboardInput
+//! [`TerminalInput`]: crate::tty::TerminalInput
+
+use core::fmt::{self, Write};
+
+use futures_util::stream::Stream;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use crate::serial::{self, SerialWriter};
+use crate::task::executor::Executor;
+use crate::task::keyboard::{self, Layout};
+use crate::tty::{Console, Input, KeyboardInput, ReadError, TerminalInput, Tty};
+use crate::{allocator, irq, memory, power, ps2, time};
+
+/// `peek` 一次最多输出的字节数
+const MAX_PEEK: u64 = 4096;
+const PAGE_SIZE: u64 = 4096;
+
+pub struct Shell<I, W> {
+    tty: Tty<I, W>,
+    /// 在提示符中显示, 用于区分不同终端上的 shell
+    name: &'static str,
+}
+
+impl Shell<KeyboardInput, Console> {
+    /// 使用键盘和屏幕的 shell, 需要运行 [`keyboard::service`]
+    pub fn console() -> Self {
+        Shell::new("console", KeyboardInput::new(), Console)
+    }
+}
+
+impl Shell<TerminalInput<crate::sync::IrqStream<u8>>, SerialWriter> {
+    /// 使用串口的 shell, 只能创建一个
+    pub fn serial() -> Self {
+        Shell::new("serial", TerminalInput::new(serial::input()), SerialWriter)
+    }
+}
+
+impl<I, W> Shell<I, W>
+where
+    I: Stream<Item = Input> + Unpin,
+    W: Write,
+{
+    pub fn new(name: &'static str, input: I, output: W) -> Self {
+        Shell { tty: Tty::new(input, output), name }
+    }
+
+    /// 读取并执行命令, 直到输入结束
+    pub async fn run(mut self) {
+        let _ = writeln!(self.tty.output(), "kernel debug shell, type `help` for a list of commands");
+        loop {
+            let _ = write!(self.tty.output(), "{}> ", self.name);
+            match self.tty.read_line().await {
+                Ok(line) => execute(self.tty.output(), &line),
+                // Ctrl+C 和 Ctrl+D 只放弃当前行
+                Err(ReadError::Interrupted) | Err(ReadError::Eof) => {}
+                Err(ReadError::Closed) => return,
+            }
+        }
+    }
+}
+
+/// 命令失败的原因
+enum CommandError {
+    /// 参数错误, 输出命令的用法
+    Usage,
+    Failed(&'static str),
+    Output,
+}
+
+impl From<fmt::Error> for CommandError {
+    fn from(_: fmt::Error) -> Self {
+        CommandError::Output
+    }
+}
+
+type CommandResult = Result<(), CommandError>;
+
+struct Command {
+    name: &'static str,
+    args: &'static str,
+    help: &'static str,
+    run: fn(&mut dyn Write, &[&str]) -> CommandResult,
+}
+
+const COMMANDS: &[Command] = &[
+    Command { name: "help", args: "", help: "list all commands", run: help },
+    Command { name: "meminfo", args: "", help: "frame allocator and heap usage", run: meminfo },
+    Command { name: "regions", args: "", help: "physical memory map from the bootloader", run: regions },
+    Command { name: "tasks", args: "", help: "async tasks and run queues", run: tasks },
+    Command { name: "irqs", args: "", help: "interrupt counts per CPU", run: irqs },
+    Command { name: "pt", args: "<addr>", help: "walk the page table for a virtual address", run: pt },
+    Command { name: "peek", args: "<addr> [len]", help: "dump memory at a virtual address", run: peek },
+    Command { name: "poke", args: "<addr> <byte>...", help: "write bytes to a virtual address", run: poke },
+    Command { name: "layout", args: "[name]", help: "show or switch the keyboard layout", run: layout },
+    Command { name: "typematic", args: "<delay ms> <rate hz>", help: "set the keyboard repeat delay and rate", run: typematic },
+    Command { name: "uptime", args: "", help: "time since boot", run: uptime },
+    Command { name: "reboot", args: "", help: "reset the machine", run: reboot },
+    Command { name: "shutdown", args: "", help: "power off the machine", run: shutdown },
+];
+
+fn execute(out: &mut impl Write, line: &str) {
+    let mut words = line.split_whitespace();
+    let name = match words.next() {
+        Some(name) => name,
+        None => return,
+    };
+    let args: alloc::vec::Vec<&str> = words.collect();
+
+    let command = match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => command,
+        None => {
+            let _ = writeln!(out, "{}: command not found", name);
+            return;
+        }
+    };
+    let result = match (command.run)(out, &args) {
+        Err(CommandError::Usage) => writeln!(out, "usage: {} {}", command.name, command.args),
+        Err(CommandError::Failed(err)) => writeln!(out, "{}: {}", command.name, err),
+        Err(CommandError::Output) | Ok(()) => Ok(()),
+    };
+    let _ = result;
+}
+
+/// 解析十进制或以 `0x` 开头的十六进制数
+fn parse_number(arg: &str) -> Result<u64, CommandError> {
+    let result = match arg.strip_prefix("0x").or_else(|| arg.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16),
+        None => arg.parse(),
+    };
+    result.map_err(|_| CommandError::Failed("invalid number"))
+}
+
+fn parse_addr(arg: &str) -> Result<VirtAddr, CommandError> {
+    VirtAddr::try_new(parse_number(arg)?).map_err(|_| CommandError::Failed("non-canonical address"))
+}
+
+/// 检查 `[addr, addr + len)` 中的每一页都已经被映射, `writable` 时还需要可写
+fn check_mapped(addr: VirtAddr, len: u64, writable: bool) -> CommandResult {
+    let end = addr.as_u64().checked_add(len).ok_or(CommandError::Failed("address overflow"))?;
+    let mut page = addr.align_down(PAGE_SIZE).as_u64();
+    while page < end {
+        let page_addr = VirtAddr::try_new(page).map_err(|_| CommandError::Failed("non-canonical address"))?;
+        let (levels, phys) = memory::walk_page_table(page_addr);
+        if phys.is_none() {
+            return Err(CommandError::Failed("address not mapped"));
+        }
+        // 每一级页表项都需要可写
+        if writable && !levels.iter().all(|level| level.flags.contains(PageTableFlags::WRITABLE)) {
+            return Err(CommandError::Failed("address not writable"));
+        }
+        page += PAGE_SIZE;
+    }
+    Ok(())
+}
+
+fn help(out: &mut dyn Write, _args: &[&str]) -> CommandResult {
+    for command in COMMANDS {
+        let usage = alloc::format!("{} {}", command.name, command.args);
+        writeln!(out, "  {:<32} {}", usage, command.help)?;
+    }
+    Ok(())
+}
+
+fn meminfo(out: &mut dyn Write, _args: &[&str]) -> CommandResult {
+    let frames = memory::frame_allocator().stats();
+    let heap = allocator::heap_stats();
+    writeln!(
+        out,
+        "frames: {} usable, {} free ({} never used, {} recycled), {} KiB per frame",
+        frames.usable,
+        frames.free(),
+        frames.untouched,
+        frames.recycled,
+        PAGE_SIZE / 1024,
+    )?;
+    writeln!(out, "heap:   {} / {} bytes used, {} free", heap.used, heap.size, heap.free)?;
+    Ok(())
+}
+
+fn regions(out: &mut dyn Write, _args: &[&str]) -> CommandResult {
+    writeln!(out, "{:<18} {:<18} {:>10}  KIND", "START", "END", "SIZE (KiB)")?;
+    for region in memory::memory_regions() {
+        writeln!(
+            out,
+            "{:#018x} {:#018x} {:>10}  {:?}",
+            region.start,
+            region.end,
+            (region.end - region.start) / 1024,
+            region.kind,
+        )?;
+    }
+    Ok(())
+}
+
+fn tasks(out: &mut dyn Write, _args: &[&str]) -> CommandResult {
+    write!(out, "{}", Executor::snapshot())?;
+    Ok(())
+}
+
+fn irqs(out: &mut dyn Write, _args: &[&str]) -> CommandResult {
+    write!(out, "{}", irq::stats())?;
+    Ok(())
+}
+
+fn pt(out: &mut dyn Write, args: &[&str]) -> CommandResult {
+    let addr = match args {
+        [addr] => parse_addr(addr)?,
+        _ => return Err(CommandError::Usage),
+    };
+    let (levels, phys) = memory::walk_page_table(addr);
+    for level in &levels {
+        writeln!(
+            out,
+            "P{}[{:>3}] {:#014x} {:?}",
+            level.level,
+            level.index,
+            level.addr.as_u64(),
+            level.flags,
+        )?;
+    }
+    match phys {
+        Some(phys) => writeln!(out, "{:#x} -> {:#x}", addr.as_u64(), phys.as_u64())?,
+        None => writeln!(out, "{:#x} is not mapped", addr.as_u64())?,
+    }
+    Ok(())
+}
+
+fn peek(out: &mut dyn Write, args: &[&str]) -> CommandResult {
+    let (addr, len) = match args {
+        [addr] => (parse_addr(addr)?, 64),
+        [addr, len] => (parse_addr(addr)?, parse_number(len)?),
+        _ => return Err(CommandError::Usage),
+    };
+    if len > MAX_PEEK {
+        return Err(CommandError::Failed("length too large"));
+    }
+    check_mapped(addr, len, false)?;
+
+    let bytes: alloc::vec::Vec<u8> = (0..len)
+        .map(|offset| unsafe { (addr + offset).as_ptr::<u8>().read_volatile() })
+        .collect();
+    for (row, chunk) in bytes.chunks(16).enumerate() {
+        write!(out, "{:#018x}: ", addr.as_u64() + row as u64 * 16)?;
+        for byte in chunk {
+            write!(out, "{:02x} ", byte)?;
+        }
+        for _ in chunk.len()..16 {
+            write!(out, "   ")?;
+        }
+        for &byte in chunk {
+            let ch = if byte.is_ascii_graphic() { char::from(byte) } else { '.' };
+            out.write_char(ch)?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+fn poke(out: &mut dyn Write, args: &[&str]) -> CommandResult {
+    let (addr, values) = match args {
+        [addr, values @ ..] if !values.is_empty() => (parse_addr(addr)?, values),
+        _ => return Err(CommandError::Usage),
+    };
+    let bytes = values
+        .iter()
+        .map(|value| u8::try_from(parse_number(value)?).map_err(|_| CommandError::Failed("byte out of range")))
+        .collect::<Result<alloc::vec::Vec<u8>, CommandError>>()?;
+    check_mapped(addr, bytes.len() as u64, true)?;
+
+    for (offset, &byte) in bytes.iter().enumerate() {
+        unsafe { (addr + offset as u64).as_mut_ptr::<u8>().write_volatile(byte) };
+    }
+    writeln!(out, "wrote {} bytes at {:#x}", bytes.len(), addr.as_u64())?;
+    Ok(())
+}
+
+fn layout(out: &mut dyn Write, args: &[&str]) -> CommandResult {
+    match args {
+        [] => {
+            write!(out, "current: {}, available:", keyboard::layout())?;
+            for layout in Layout::ALL {
+                write!(out, " {}", layout)?;
+            }
+            writeln!(out)?;
+        }
+        [name] => keyboard::set_layout(name.parse().map_err(CommandError::Failed)?),
+        _ => return Err(CommandError::Usage),
+    }
+    Ok(())
+}
+
+fn typematic(out: &mut dyn Write, args: &[&str]) -> CommandResult {
+    let (delay, rate) = match args {
+        [delay, rate] => (parse_number(delay)?, parse_number(rate)?),
+        _ => return Err(CommandError::Usage),
+    };
+    let delay = u32::try_from(delay).map_err(|_| CommandError::Failed("delay out of range"))?;
+    let rate = u32::try_from(rate).map_err(|_| CommandError::Failed("rate out of range"))?;
+    if let Err(err) = ps2::set_typematic(delay, rate) {
+        writeln!(out, "typematic: {}", err)?;
+    }
+    Ok(())
+}
+
+fn uptime(out: &mut dyn Write, _args: &[&str]) -> CommandResult {
+    let uptime = time::uptime();
+    writeln!(
+        out,
+        "up {}.{:03}s, {} timer ticks",
+        uptime.as_secs(),
+        uptime.subsec_millis(),
+        time::ticks(),
+    )?;
+    Ok(())
+}
+
+fn reboot(_out: &mut dyn Write, _args: &[&str]) -> CommandResult {
+    power::reboot()
+}
+
+fn shutdown(_out: &mut dyn Write, _args: &[&str]) -> CommandResult {
+    power::shutdown()
+}
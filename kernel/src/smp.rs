@@ -0,0 +1,236 @@
+//! # 多处理器 (SMP)
+//! 参考: https://wiki.osdev.org/SMP && Intel® 64 and IA-32 Architectures Software Developer’s Manual
+//!       Volume 3, 8.4 Multiple-Processor (MP) Initialization
+//!
+//! 启动时只有 BSP (bootstrap processor) 在运行, 其它处理器 (AP, application processor) 处于
+//! wait-for-SIPI 状态。启动 AP 的过程:
+//!
+//! 1. 从 MADT (见 [`acpi`]) 中找到所有处理器的 local APIC ID
+//! 2. 将启动代码 (`asm/trampoline.s`) 复制到 1 MiB 以下的一页中, 并恒等映射这一页,
+//!    这样 AP 开启分页之后还能继续执行它
+//! 3. 对每个 AP: 分配栈, 填写启动参数, 发送 INIT IPI, 等待 10 ms, 再发送 SIPI (最多两次)
+//! 4. AP 进入 long mode 后调用 [`ap_main`], 加载自己的 GDT/TSS, per-CPU 数据, IDT 和 syscall MSR,
+//!    然后通知 BSP
+//!
+//! AP 共用启动代码和启动参数, 所以是一个一个启动的。AP 超时没有运行到 [`ap_main`] 时, BSP 会放弃它,
+//! 并再次发送 INIT IPI 让它回到 wait-for-SIPI 状态, 之后才会为下一个 AP 改写启动参数。AP 和 BSP
+//! 通过 `AP_STATE` 上的 compare-exchange 决定 AP 是否还能启动, 所以不会出现 BSP 放弃了 AP 而 AP
+//! 仍在运行的情况。
+//!
+//! AP 启动后不会运行线程, 只运行自己的执行器, 从其它处理器窃取任务或运行被分配给它的任务。
+//! 没有当前线程时不能使用线程和进程的 API, 所以 AP 只运行 [`crate::task::Builder::any_cpu`] 的任务
+//! (见 [`crate::task`]) 。
+
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use crate::{acpi, apic, gdt, interrupts, memory, percpu, syscall, thread, time};
+use crate::task::executor::Executor;
+
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+global_asm!(include_str!("asm/trampoline.s"));
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_params: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// 启动代码所在的页必须低于这个地址 (SIPI 的向量只有 8 位, 且 0xA0000 以上是显存和 BIOS)
+const TRAMPOLINE_LIMIT: u64 = 0xa0000;
+/// AP 的内核栈的大小 (页数)
+const AP_STACK_PAGES: u64 = 16;
+/// 等待 AP 启动的最长时间
+const AP_START_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// 在线的处理器数量, 包括 BSP
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+/// 正在启动的 AP 的状态, 为下面的 `AP_*` 之一
+static AP_STATE: AtomicU8 = AtomicU8::new(AP_WAITING);
+/// 还没有运行到 [`ap_main`]
+const AP_WAITING: u8 = 0;
+/// 已经进入 [`ap_main`], 正在初始化
+const AP_CLAIMED: u8 = 1;
+const AP_ONLINE: u8 = 2;
+/// BSP 等待超时, 放弃了这个 AP, 之后进入 [`ap_main`] 的 AP 会停机
+const AP_ABANDONED: u8 = 3;
+
+/// 传递给 AP 的启动参数, 布局需要与 `asm/trampoline.s` 中的 `ap_trampoline_params` 一致
+#[repr(C)]
+struct TrampolineParams {
+    /// 4 级页表的物理地址
+    page_table: u64,
+    stack_top: u64,
+    /// `extern "C" fn(cpu: u64) -> !`
+    entry: u64,
+    /// 逻辑 CPU 编号, BSP 为 0
+    cpu: u64,
+}
+
+/// 启动 MADT 中列出的所有 AP, 并输出在线的处理器数量
+///
+/// 需要在开启中断、初始化线程和 [`acpi::init`] 之后调用。
+pub fn init() {
+    let madt = match acpi::madt() {
+        Some(madt) => madt,
+        None => {
+            log::info!("1 CPU online (no MADT, SMP disabled)");
+            return;
+        }
+    };
+
+    apic::init(madt.local_apic_address);
+    let bsp = apic::id();
+    let usable = madt.processors.iter().filter(|processor| processor.usable).count();
+
+    let trampoline = match unsafe { install_trampoline() } {
+        Ok(frame) => frame,
+        Err(err) => {
+            log::warn!("failed to install AP trampoline: {}", err);
+            log::info!("1 of {} CPUs online", usable);
+            return;
+        }
+    };
+    let vector = (trampoline.start_address().as_u64() >> 12) as u8;
+
+    let aps = madt
+        .processors
+        .iter()
+        .filter(|processor| processor.usable && processor.apic_id != bsp);
+    for (index, processor) in aps.enumerate() {
+        let cpu = index as u64 + 1;
+        if cpu as usize >= percpu::MAX_CPUS {
+            log::warn!("CPU {} (APIC ID {}) exceeds MAX_CPUS, not started", cpu, processor.apic_id);
+            continue;
+        }
+        if let Err(err) = unsafe { start_ap(trampoline, vector, cpu, processor.apic_id) } {
+            log::warn!("failed to start CPU {} (APIC ID {}): {}", cpu, processor.apic_id, err);
+        }
+    }
+
+    // 没有启动成功的 AP 可能之后还会执行启动代码, 所以启动代码所在的 frame 不会被释放
+    unsafe { unmap_identity(trampoline) };
+    log::info!("{} of {} CPUs online", cpu_count(), usable);
+}
+
+/// 在线的处理器数量, 包括 BSP
+pub fn cpu_count() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// 分配 1 MiB 以下的一页, 恒等映射它并复制启动代码
+unsafe fn install_trampoline() -> Result<PhysFrame, &'static str> {
+    let start = &ap_trampoline_start as *const u8;
+    let end = &ap_trampoline_end as *const u8;
+    let size = end as usize - start as usize;
+    if size > 4096 {
+        return Err("trampoline is larger than a page");
+    }
+
+    let frame = memory::frame_allocator()
+        .allocate_frame_below(PhysAddr::new(TRAMPOLINE_LIMIT))
+        .ok_or("no free frame below 640 KiB")?;
+
+    let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    {
+        let mut mapper = memory::mapper();
+        let mut frame_allocator = memory::frame_allocator();
+        mapper
+            .map_to(page, frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE, &mut *frame_allocator)
+            .map_err(|_| "failed to identity map trampoline")?
+            .flush();
+    }
+
+    let dest = memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+    core::ptr::copy_nonoverlapping(start, dest, size);
+    Ok(frame)
+}
+
+unsafe fn unmap_identity(frame: PhysFrame) {
+    let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    match memory::mapper().unmap(page) {
+        Ok((_, flush)) => flush.flush(),
+        Err(err) => log::warn!("failed to unmap trampoline: {:?}", err),
+    }
+}
+
+/// 通过 INIT-SIPI-SIPI 启动一个 AP, 并等待它运行到 [`ap_main`]
+unsafe fn start_ap(trampoline: PhysFrame, vector: u8, cpu: u64, apic_id: u32) -> Result<(), &'static str> {
+    // 在启动过程中调用, 此时使用的是内核的页表
+    let page_table = Cr3::read().0.start_address().as_u64();
+    if page_table > u64::from(u32::MAX) {
+        return Err("kernel page table is above 4 GiB");
+    }
+
+    let stack = memory::alloc_stack(AP_STACK_PAGES).map_err(|_| "failed to allocate stack")?;
+
+    let offset = &ap_trampoline_params as *const u8 as usize - &ap_trampoline_start as *const u8 as usize;
+    let params = (memory::phys_to_virt(trampoline.start_address()) + offset).as_mut_ptr::<TrampolineParams>();
+    params.write_volatile(TrampolineParams {
+        page_table,
+        stack_top: stack.end().as_u64(),
+        entry: ap_main as *const () as u64,
+        cpu,
+    });
+    AP_STATE.store(AP_WAITING, Ordering::Release);
+
+    apic::send_init(apic_id);
+    thread::sleep(Duration::from_millis(10));
+    for _ in 0..2 {
+        apic::send_startup(apic_id, vector);
+        thread::sleep(Duration::from_micros(200));
+        if AP_STATE.load(Ordering::Acquire) == AP_ONLINE {
+            return Ok(());
+        }
+    }
+
+    let deadline = time::uptime() + AP_START_TIMEOUT;
+    while time::uptime() < deadline {
+        if AP_STATE.load(Ordering::Acquire) == AP_ONLINE {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    if AP_STATE.compare_exchange(AP_WAITING, AP_ABANDONED, Ordering::AcqRel, Ordering::Acquire).is_err() {
+        // AP 已经进入了 ap_main, 不能再打断它, 等待它初始化完成
+        while AP_STATE.load(Ordering::Acquire) != AP_ONLINE {
+            thread::sleep(Duration::from_millis(1));
+        }
+        return Ok(());
+    }
+
+    // AP 可能还在启动代码中或者稍后才收到 SIPI, 让它回到 wait-for-SIPI 状态,
+    // 之后它不会再使用启动参数和栈
+    apic::send_init(apic_id);
+    thread::sleep(Duration::from_millis(10));
+    memory::free_stack(stack);
+    Err("timed out")
+}
+
+/// AP 在 long mode 中的入口, 由启动代码调用
+extern "C" fn ap_main(cpu: u64) -> ! {
+    // BSP 已经放弃了这个 AP, 会通过 INIT IPI 重置它; 启动代码关闭了中断
+    if AP_STATE.compare_exchange(AP_WAITING, AP_CLAIMED, Ordering::AcqRel, Ordering::Acquire).is_err() {
+        crate::hlt_loop();
+    }
+
+    let tss = gdt::init_ap();
+    percpu::init(cpu as usize, tss);
+    interrupts::init_idt_ap();
+    syscall::init();
+    apic::enable();
+
+    ONLINE.fetch_add(1, Ordering::AcqRel);
+    AP_STATE.store(AP_ONLINE, Ordering::Release);
+    log::info!("CPU {} (APIC ID {}) online", cpu, apic::id());
+
+    x86_64::instructions::interrupts::enable();
+    Executor::new().run();
+}
@@ -0,0 +1,214 @@
+//! 从中断处理函数向任务传递事件, 见 [`IrqChannel`]
+
+use core::fmt;
+use core::future::poll_fn;
+use core::mem::MaybeUninit;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+
+use super::IrqSpinlock;
+
+/// 最多缓存 `N` 个事件的通道, 中断处理函数用 [`IrqChannel::push`] 放入事件, 一个任务通过
+/// [`IrqStream`] 等待事件
+///
+/// 缓冲区是固定大小的数组, `new` 是 `const` 的, 所以通道可以是 `static`, 放入事件不会分配内存。
+/// 缓冲区满时新的事件会被丢弃, 并记录在 [`IrqChannel::dropped`] 中。
+///
+/// ```ignore
+/// static SCANCODES: IrqChannel<u8, 128> = IrqChannel::new();
+///
+/// // 中断处理函数
+/// let _ = SCANCODES.push(scancode);
+///
+/// // 任务
+/// let mut scancodes = SCANCODES.stream();
+/// while let Some(scancode) = scancodes.next().await { ... }
+/// ```
+pub struct IrqChannel<T, const N: usize> {
+    ring: IrqSpinlock<Ring<T, N>>,
+    waker: AtomicWaker,
+    /// 因为缓冲区满而被丢弃的事件数
+    dropped: AtomicUsize,
+    /// 是否已经创建了 [`IrqStream`]
+    taken: AtomicBool,
+}
+
+struct Ring<T, const N: usize> {
+    slots: [MaybeUninit<T>; N],
+    /// 最早的事件的位置
+    head: usize,
+    len: usize,
+}
+
+impl<T, const N: usize> Ring<T, N> {
+    fn push(&mut self, value: T) -> Result<(), T> {
+        if self.len == N {
+            return Err(value);
+        }
+        self.slots[(self.head + self.len) % N].write(value);
+        self.len += 1;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        let value = unsafe { self.slots[self.head].assume_init_read() };
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(value)
+    }
+}
+
+impl<T, const N: usize> Drop for Ring<T, N> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+impl<T: Send, const N: usize> IrqChannel<T, N> {
+    pub const fn new() -> Self {
+        assert!(N > 0, "IrqChannel capacity must be positive");
+        IrqChannel {
+            ring: IrqSpinlock::new(Ring { slots: [const { MaybeUninit::uninit() }; N], head: 0, len: 0 }),
+            waker: AtomicWaker::new(),
+            dropped: AtomicUsize::new(0),
+            taken: AtomicBool::new(false),
+        }
+    }
+
+    /// 放入一个事件并唤醒等待的任务, 缓冲区满时丢弃它并原样返回
+    ///
+    /// 不会分配内存, 可以在中断处理函数中调用。
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let result = self.ring.lock().push(value);
+        match result {
+            Ok(()) => self.waker.wake(),
+            Err(_) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        result
+    }
+
+    /// 创建接收事件的 stream, 每个通道只能有一个接收者
+    ///
+    /// Panics if called more than once.
+    pub fn stream(&'static self) -> IrqStream<T> {
+        self.try_stream().expect("IrqChannel::stream should only be called once")
+    }
+
+    /// 和 [`IrqChannel::stream`] 一样, 但已经创建过时返回 `None`
+    pub fn try_stream(&'static self) -> Option<IrqStream<T>> {
+        if self.taken.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+        Some(IrqStream { source: self })
+    }
+
+    /// 缓冲区中的事件数
+    pub fn len(&self) -> usize {
+        self.ring.lock().len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// 因为缓冲区满而被丢弃的事件数
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<T: Send, const N: usize> Default for IrqChannel<T, N> {
+    fn default() -> Self {
+        IrqChannel::new()
+    }
+}
+
+impl<T: Send, const N: usize> fmt::Debug for IrqChannel<T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IrqChannel")
+            .field("len", &self.len())
+            .field("capacity", &N)
+            .field("dropped", &self.dropped())
+            .finish()
+    }
+}
+
+/// 让 [`IrqStream`] 不需要带上通道的容量
+trait Source<T>: Sync {
+    fn pop(&self) -> Option<T>;
+    fn register(&self, waker: &Waker);
+    fn unregister(&self);
+}
+
+impl<T: Send, const N: usize> Source<T> for IrqChannel<T, N> {
+    fn pop(&self) -> Option<T> {
+        self.ring.lock().pop()
+    }
+
+    fn register(&self, waker: &Waker) {
+        self.waker.register(waker);
+    }
+
+    fn unregister(&self) {
+        self.waker.take();
+    }
+}
+
+/// [`IrqChannel`] 的接收者, 是一个永远不会结束的 [`Stream`]
+pub struct IrqStream<T: 'static> {
+    source: &'static dyn Source<T>,
+}
+
+impl<T> IrqStream<T> {
+    /// 等待下一个事件
+    pub async fn recv(&mut self) -> T {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    /// 不等待, 缓冲区为空时返回 `None`
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.source.pop()
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<T> {
+        if let Some(value) = self.source.pop() {
+            return Poll::Ready(value);
+        }
+        // 先注册 waker 再检查一次, 保证检查之后放入的事件一定会唤醒这个任务
+        self.source.register(cx.waker());
+        match self.source.pop() {
+            Some(value) => {
+                self.source.unregister();
+                Poll::Ready(value)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Stream for IrqStream<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(cx).map(Some)
+    }
+}
+
+impl<T> fmt::Debug for IrqStream<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IrqStream").finish_non_exhaustive()
+    }
+}
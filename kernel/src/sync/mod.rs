@@ -0,0 +1,35 @@
+//! # 同步原语
+//!
+//! 异步的同步原语在资源不可用时把任务的 waker 放入等待队列并返回 `Poll::Pending`, 让出执行器,
+//! 而不是自旋。它们只依赖标准的 [`core::task::Waker`], 所以可以和 [`crate::task`] 的执行器一起使用:
+//!
+//! - [`Semaphore`] - 先来先得的信号量, 也是其它原语的基础
+//! - [`Mutex`] 和 [`RwLock`] - guard 可以跨越 `.await` 持有
+//! - [`Notify`] - 不携带数据的通知
+//! - [`oneshot`] - 只发送一个值的通道
+//! - [`mpsc`] - 有界的多生产者单消费者通道
+//! - [`IrqChannel`] - 中断处理函数向任务传递事件的固定大小的通道
+//!
+//! 等待队列由 [`IrqSpinlock`] 保护, 它在持有期间关中断, 也可以直接用来保护中断处理函数和普通代码
+//! 共享的数据。开始等待时等待队列可能在关中断时扩容: 堆的锁同样会关中断 (见 [`crate::allocator`]),
+//! 所以不会有持有堆的锁的线程被抢占, 让关中断的分配永远自旋。
+//!
+//! ## 中断安全
+//! 中断处理函数不能等待, 也不能分配或释放内存, 只有这些操作可以在中断处理函数中使用:
+//! [`Semaphore::add_permits`], [`Notify::notify_one`], [`Notify::notify_waiters`] 和 [`IrqChannel::push`] 。
+//! 它们唤醒的任务会被放入不需要分配内存的 `irq_run_queue` 中。
+
+mod irq_channel;
+mod mutex;
+mod notify;
+mod semaphore;
+mod spin;
+
+pub mod mpsc;
+pub mod oneshot;
+
+pub use irq_channel::{IrqChannel, IrqStream};
+pub use mutex::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use notify::{Notified, Notify};
+pub use semaphore::{Acquire, AcquireError, Semaphore, SemaphorePermit, TryAcquireError};
+pub use spin::{IrqSpinlock, IrqSpinlockGuard};
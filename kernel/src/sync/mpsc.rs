@@ -0,0 +1,225 @@
+//! 有界的多生产者单消费者通道
+
+use core::fmt;
+use core::future::poll_fn;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+
+use alloc::sync::Arc;
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+
+use super::semaphore::{Semaphore, TryAcquireError};
+
+struct Chan<T> {
+    queue: ArrayQueue<T>,
+    /// 发送许可, 数量等于队列中的空位, 接收者被释放时关闭
+    permits: Semaphore,
+    receiver_waker: AtomicWaker,
+    senders: AtomicUsize,
+}
+
+/// 创建一个最多缓存 `capacity` 个值的通道, 通道满时 [`Sender::send`] 会等待
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "mpsc channel capacity must be positive");
+    let chan = Arc::new(Chan {
+        queue: ArrayQueue::new(capacity),
+        permits: Semaphore::new(capacity),
+        receiver_waker: AtomicWaker::new(),
+        senders: AtomicUsize::new(1),
+    });
+    (Sender { chan: chan.clone() }, Receiver { chan })
+}
+
+/// 接收者已经被释放, 包含没有发送出去的值
+#[derive(PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SendError(..)")
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "channel closed")
+    }
+}
+
+/// [`Sender::try_send`] 失败的原因, 包含没有发送出去的值
+#[derive(PartialEq, Eq)]
+pub enum TrySendError<T> {
+    Full(T),
+    Closed(T),
+}
+
+impl<T> TrySendError<T> {
+    pub fn into_inner(self) -> T {
+        match self {
+            TrySendError::Full(value) | TrySendError::Closed(value) => value,
+        }
+    }
+}
+
+impl<T> fmt::Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("Full(..)"),
+            TrySendError::Closed(_) => f.write_str("Closed(..)"),
+        }
+    }
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "channel full"),
+            TrySendError::Closed(_) => write!(f, "channel closed"),
+        }
+    }
+}
+
+/// [`Receiver::try_recv`] 失败的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    /// 所有发送者都已经被释放, 并且通道中没有值了
+    Disconnected,
+}
+
+pub struct Sender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Sender<T> {
+    /// 发送 `value`, 通道满时等待接收者取出值
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        match self.chan.permits.acquire().await {
+            Ok(permit) => {
+                // 许可在接收者取出值后归还
+                permit.forget();
+                self.push(value);
+                Ok(())
+            }
+            Err(_) => Err(SendError(value)),
+        }
+    }
+
+    /// 不等待, 通道满或已经关闭时返回 `value`
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        match self.chan.permits.try_acquire() {
+            Ok(permit) => {
+                permit.forget();
+                self.push(value);
+                Ok(())
+            }
+            Err(TryAcquireError::NoPermits) => Err(TrySendError::Full(value)),
+            Err(TryAcquireError::Closed) => Err(TrySendError::Closed(value)),
+        }
+    }
+
+    fn push(&self, value: T) {
+        if self.chan.queue.push(value).is_err() {
+            unreachable!("mpsc queue overflow");
+        }
+        self.chan.receiver_waker.wake();
+    }
+
+    /// 接收者是否已经被释放
+    pub fn is_closed(&self) -> bool {
+        self.chan.permits.is_closed()
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.chan.senders.fetch_add(1, Ordering::Relaxed);
+        Sender { chan: self.chan.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.chan.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.chan.receiver_waker.wake();
+        }
+    }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sender").field("closed", &self.is_closed()).finish()
+    }
+}
+
+pub struct Receiver<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// 取出下一个值, 所有发送者都被释放并且通道中没有值时返回 `None`
+    pub async fn recv(&mut self) -> Option<T> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+        if let Some(value) = self.pop() {
+            return Poll::Ready(Some(value));
+        }
+        // 先注册 waker 再检查一次, 否则可能错过检查之后发送的值
+        self.chan.receiver_waker.register(cx.waker());
+        if let Some(value) = self.pop() {
+            self.chan.receiver_waker.take();
+            return Poll::Ready(Some(value));
+        }
+        if self.chan.senders.load(Ordering::Acquire) == 0 {
+            // 最后一个发送者可能在上面的检查之后才发送并释放
+            return Poll::Ready(self.pop());
+        }
+        Poll::Pending
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        if let Some(value) = self.pop() {
+            return Ok(value);
+        }
+        if self.chan.senders.load(Ordering::Acquire) == 0 {
+            return self.pop().ok_or(TryRecvError::Disconnected);
+        }
+        Err(TryRecvError::Empty)
+    }
+
+    /// 关闭通道, 之后的发送会失败, 已经发送的值仍然可以取出
+    pub fn close(&mut self) {
+        self.chan.permits.close();
+    }
+
+    fn pop(&self) -> Option<T> {
+        let value = self.chan.queue.pop()?;
+        self.chan.permits.add_permits(1);
+        Some(value)
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.chan.permits.close();
+    }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Receiver").field("len", &self.chan.queue.len()).finish()
+    }
+}
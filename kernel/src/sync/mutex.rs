@@ -0,0 +1,177 @@
+//! 异步互斥锁和读写锁, 被占用时让出执行器而不是自旋
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+
+use super::semaphore::{Semaphore, SemaphorePermit};
+
+/// 异步互斥锁, 等待者按照先来先得的顺序获得锁
+///
+/// guard 可以跨越 `.await` 持有。不能在中断处理函数中使用, 见 [`super::IrqSpinlock`] 。
+pub struct Mutex<T: ?Sized> {
+    semaphore: Semaphore,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex { semaphore: Semaphore::new(1), value: UnsafeCell::new(value) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    pub async fn lock(&self) -> MutexGuard<'_, T> {
+        // 信号量永远不会被关闭
+        let permit = self.semaphore.acquire().await.unwrap();
+        MutexGuard { mutex: self, _permit: permit }
+    }
+
+    /// 锁被占用 (或有任务在等待) 时返回 `None`
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let permit = self.semaphore.try_acquire().ok()?;
+        Some(MutexGuard { mutex: self, _permit: permit })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Mutex::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("Mutex").field("data", &&*guard).finish(),
+            None => f.write_str("Mutex { <locked> }"),
+        }
+    }
+}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+/// 读者最多同时有这么多个, 写者一次获取所有的许可
+const MAX_READERS: usize = Semaphore::MAX_PERMITS;
+
+/// 异步读写锁, 可以同时有多个读者或一个写者
+///
+/// 读者和写者按照先来先得的顺序获得锁, 所以在等待的写者不会被之后的读者饿死。
+pub struct RwLock<T: ?Sized> {
+    semaphore: Semaphore,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        RwLock { semaphore: Semaphore::new(MAX_READERS), value: UnsafeCell::new(value) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    pub async fn read(&self) -> RwLockReadGuard<'_, T> {
+        let permit = self.semaphore.acquire().await.unwrap();
+        RwLockReadGuard { lock: self, _permit: permit }
+    }
+
+    pub async fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let permit = self.semaphore.acquire_many(MAX_READERS).await.unwrap();
+        RwLockWriteGuard { lock: self, _permit: permit }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let permit = self.semaphore.try_acquire().ok()?;
+        Some(RwLockReadGuard { lock: self, _permit: permit })
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        let permit = self.semaphore.try_acquire_many(MAX_READERS).ok()?;
+        Some(RwLockWriteGuard { lock: self, _permit: permit })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> Self {
+        RwLock::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_read() {
+            Some(guard) => f.debug_struct("RwLock").field("data", &&*guard).finish(),
+            None => f.write_str("RwLock { <locked> }"),
+        }
+    }
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
@@ -0,0 +1,165 @@
+//! 在任务之间传递通知, 不携带数据
+
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+use alloc::collections::VecDeque;
+
+use super::IrqSpinlock;
+
+/// 唤醒等待通知的任务
+///
+/// [`Notify::notify_one`] 在没有任务等待时会留下一个许可, 下一次 [`Notify::notified`] 会直接返回,
+/// 所以先通知再等待也不会丢失通知。[`Notify::notify_waiters`] 只唤醒已经在等待的任务, 不会留下许可。
+pub struct Notify {
+    state: IrqSpinlock<State>,
+}
+
+struct State {
+    /// `notify_one` 留下的许可, 最多一个
+    permit: bool,
+    waiters: VecDeque<Waiter>,
+    next_id: u64,
+}
+
+struct Waiter {
+    id: u64,
+    waker: Option<Waker>,
+    notified: Option<Notification>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Notification {
+    One,
+    All,
+}
+
+impl Notify {
+    pub const fn new() -> Self {
+        Notify {
+            state: IrqSpinlock::new(State { permit: false, waiters: VecDeque::new(), next_id: 0 }),
+        }
+    }
+
+    /// 等待通知, 返回的 future 在第一次被 poll 时才开始等待
+    pub fn notified(&self) -> Notified<'_> {
+        Notified { notify: self, id: None }
+    }
+
+    /// 唤醒最早开始等待的任务, 没有任务在等待时留下一个许可
+    ///
+    /// 不会分配内存, 可以在中断处理函数中调用。
+    pub fn notify_one(&self) {
+        self.state.lock().notify_one();
+    }
+
+    /// 唤醒所有正在等待的任务
+    ///
+    /// 不会分配内存, 可以在中断处理函数中调用。
+    pub fn notify_waiters(&self) {
+        let mut state = self.state.lock();
+        for waiter in state.waiters.iter_mut().filter(|waiter| waiter.notified.is_none()) {
+            waiter.notified = Some(Notification::All);
+            if let Some(waker) = waiter.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl State {
+    fn notify_one(&mut self) {
+        match self.waiters.iter_mut().find(|waiter| waiter.notified.is_none()) {
+            Some(waiter) => {
+                waiter.notified = Some(Notification::One);
+                if let Some(waker) = waiter.waker.take() {
+                    waker.wake();
+                }
+            }
+            None => self.permit = true,
+        }
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Notify::new()
+    }
+}
+
+impl fmt::Debug for Notify {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock();
+        f.debug_struct("Notify")
+            .field("permit", &state.permit)
+            .field("waiters", &state.waiters.len())
+            .finish()
+    }
+}
+
+/// [`Notify::notified`] 返回的 future
+///
+/// 被 [`Notify::notify_one`] 选中后、在被 poll 之前就被释放时, 通知会转交给下一个等待者。
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    /// 在等待队列中的 id
+    id: Option<u64>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let mut state = self.notify.state.lock();
+
+        let id = match self.id {
+            Some(id) => id,
+            None => {
+                if core::mem::take(&mut state.permit) {
+                    return Poll::Ready(());
+                }
+                let id = state.next_id;
+                state.next_id += 1;
+                // 可能在关中断时分配内存, 见 `super` 的模块文档
+                state.waiters.push_back(Waiter { id, waker: None, notified: None });
+                self.id = Some(id);
+                id
+            }
+        };
+
+        let index = state
+            .waiters
+            .iter()
+            .position(|waiter| waiter.id == id)
+            .expect("notify waiter missing");
+        if state.waiters[index].notified.is_some() {
+            state.waiters.remove(index);
+            self.id = None;
+            return Poll::Ready(());
+        }
+
+        let waiter = &mut state.waiters[index];
+        if !waiter.waker.as_ref().is_some_and(|waker| waker.will_wake(cx.waker())) {
+            waiter.waker = Some(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        let id = match self.id {
+            Some(id) => id,
+            None => return,
+        };
+        let mut state = self.notify.state.lock();
+        if let Some(index) = state.waiters.iter().position(|waiter| waiter.id == id) {
+            let waiter = state.waiters.remove(index).unwrap();
+            if waiter.notified == Some(Notification::One) {
+                state.notify_one();
+            }
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//! 只能发送一个值的通道, 常用于等待另一个任务的回复
+
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+use alloc::sync::Arc;
+
+use super::IrqSpinlock;
+
+struct Inner<T> {
+    state: IrqSpinlock<State<T>>,
+}
+
+struct State<T> {
+    value: Option<T>,
+    receiver_waker: Option<Waker>,
+    sender_dropped: bool,
+    receiver_dropped: bool,
+}
+
+/// 创建一个 oneshot 通道
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let inner = Arc::new(Inner {
+        state: IrqSpinlock::new(State {
+            value: None,
+            receiver_waker: None,
+            sender_dropped: false,
+            receiver_dropped: false,
+        }),
+    });
+    (Sender { inner: inner.clone() }, Receiver { inner })
+}
+
+/// 发送者在发送之前就被释放了
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sender dropped without sending")
+    }
+}
+
+/// [`Receiver::try_recv`] 失败的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// 还没有发送
+    Empty,
+    /// 发送者在发送之前就被释放了, 或值已经被取走
+    Closed,
+}
+
+pub struct Sender<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Sender<T> {
+    /// 发送 `value`, 接收者已经被释放时把它原样返回
+    pub fn send(self, value: T) -> Result<(), T> {
+        let waker = {
+            let mut state = self.inner.state.lock();
+            if state.receiver_dropped {
+                return Err(value);
+            }
+            state.value = Some(value);
+            state.receiver_waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// 接收者是否已经被释放
+    pub fn is_closed(&self) -> bool {
+        self.inner.state.lock().receiver_dropped
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let waker = {
+            let mut state = self.inner.state.lock();
+            state.sender_dropped = true;
+            state.receiver_waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sender").field("closed", &self.is_closed()).finish()
+    }
+}
+
+/// 接收者, 它本身是一个 future, 返回发送的值
+pub struct Receiver<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// 不等待, 立即尝试取出发送的值
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let mut state = self.inner.state.lock();
+        match state.value.take() {
+            Some(value) => Ok(value),
+            None if state.sender_dropped => Err(TryRecvError::Closed),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// 关闭通道, 之后的发送会失败, 已经发送的值仍然可以取出
+    pub fn close(&mut self) {
+        self.inner.state.lock().receiver_dropped = true;
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.inner.state.lock();
+        if let Some(value) = state.value.take() {
+            return Poll::Ready(Ok(value));
+        }
+        if state.sender_dropped {
+            return Poll::Ready(Err(RecvError));
+        }
+        if !state.receiver_waker.as_ref().is_some_and(|waker| waker.will_wake(cx.waker())) {
+            state.receiver_waker = Some(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.inner.state.lock();
+        state.receiver_dropped = true;
+        // 在锁外释放值和 waker
+        let value = state.value.take();
+        let waker = state.receiver_waker.take();
+        drop(state);
+        drop((value, waker));
+    }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Receiver").finish_non_exhaustive()
+    }
+}
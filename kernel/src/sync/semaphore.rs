@@ -0,0 +1,264 @@
+//! 异步信号量, 也是 [`super::Mutex`], [`super::RwLock`] 和 [`super::mpsc`] 的基础
+
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+use alloc::collections::VecDeque;
+
+use super::IrqSpinlock;
+
+/// 异步信号量
+///
+/// 等待者按照先来先得的顺序获得许可: 只要有任务在等待, 新的请求就会排在它后面, 即使剩余的
+/// 许可足够, 所以一次请求很多许可的任务 (如: [`super::RwLock::write`]) 不会被饿死。
+pub struct Semaphore {
+    state: IrqSpinlock<State>,
+}
+
+struct State {
+    permits: usize,
+    closed: bool,
+    waiters: VecDeque<Waiter>,
+    next_id: u64,
+}
+
+struct Waiter {
+    id: u64,
+    needed: usize,
+    waker: Option<Waker>,
+    /// 许可已经分配给了这个等待者, 等待它被 poll 时取走
+    granted: bool,
+}
+
+/// 信号量已经被关闭, 见 [`Semaphore::close`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AcquireError;
+
+impl fmt::Display for AcquireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "semaphore closed")
+    }
+}
+
+/// [`Semaphore::try_acquire`] 失败的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryAcquireError {
+    /// 没有足够的许可, 或有其它任务在等待
+    NoPermits,
+    Closed,
+}
+
+impl Semaphore {
+    /// 许可的最大数量
+    pub const MAX_PERMITS: usize = usize::MAX >> 3;
+
+    pub const fn new(permits: usize) -> Self {
+        assert!(permits <= Self::MAX_PERMITS, "too many permits");
+        Semaphore {
+            state: IrqSpinlock::new(State {
+                permits,
+                closed: false,
+                waiters: VecDeque::new(),
+                next_id: 0,
+            }),
+        }
+    }
+
+    /// 当前可用的许可的数量
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().permits
+    }
+
+    /// 获取一个许可
+    pub fn acquire(&self) -> Acquire<'_> {
+        self.acquire_many(1)
+    }
+
+    /// 一次获取 `permits` 个许可
+    pub fn acquire_many(&self, permits: usize) -> Acquire<'_> {
+        assert!(permits <= Self::MAX_PERMITS, "too many permits");
+        Acquire { semaphore: self, needed: permits, id: None }
+    }
+
+    pub fn try_acquire(&self) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+        self.try_acquire_many(1)
+    }
+
+    pub fn try_acquire_many(&self, permits: usize) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+        let mut state = self.state.lock();
+        if state.closed {
+            Err(TryAcquireError::Closed)
+        } else if state.waiters.is_empty() && state.permits >= permits {
+            state.permits -= permits;
+            Ok(SemaphorePermit { semaphore: self, permits })
+        } else {
+            Err(TryAcquireError::NoPermits)
+        }
+    }
+
+    /// 增加 `permits` 个许可, 并唤醒可以获得许可的等待者
+    ///
+    /// 不会分配内存, 可以在中断处理函数中调用。
+    pub fn add_permits(&self, permits: usize) {
+        let mut state = self.state.lock();
+        state.permits = state.permits.saturating_add(permits).min(Self::MAX_PERMITS);
+        state.grant();
+    }
+
+    /// 关闭信号量, 所有等待者和之后的请求都会返回 [`AcquireError`], 已经获得的许可不受影响
+    pub fn close(&self) {
+        let mut state = self.state.lock();
+        state.closed = true;
+        for waiter in state.waiters.iter_mut() {
+            if let Some(waker) = waiter.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().closed
+    }
+}
+
+impl State {
+    /// 按顺序把许可分配给等待者, 直到剩余的许可不够排在最前面的等待者
+    fn grant(&mut self) {
+        for waiter in self.waiters.iter_mut().filter(|waiter| !waiter.granted) {
+            if waiter.needed > self.permits {
+                break;
+            }
+            self.permits -= waiter.needed;
+            waiter.granted = true;
+            if let Some(waker) = waiter.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Semaphore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock();
+        f.debug_struct("Semaphore")
+            .field("permits", &state.permits)
+            .field("closed", &state.closed)
+            .field("waiters", &state.waiters.len())
+            .finish()
+    }
+}
+
+/// [`Semaphore::acquire`] 返回的 future
+///
+/// 在获得许可之前被释放时, 会离开等待队列, 已经分配给它的许可会被归还。
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    needed: usize,
+    /// 在等待队列中的 id
+    id: Option<u64>,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = Result<SemaphorePermit<'a>, AcquireError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let semaphore = self.semaphore;
+        let needed = self.needed;
+        let mut state = semaphore.state.lock();
+
+        let id = match self.id {
+            Some(id) => id,
+            None => {
+                if state.closed {
+                    return Poll::Ready(Err(AcquireError));
+                }
+                if state.waiters.is_empty() && state.permits >= needed {
+                    state.permits -= needed;
+                    return Poll::Ready(Ok(SemaphorePermit { semaphore, permits: needed }));
+                }
+                let id = state.next_id;
+                state.next_id += 1;
+                // 可能在关中断时分配内存, 见 `super` 的模块文档
+                state.waiters.push_back(Waiter { id, needed, waker: None, granted: false });
+                // 排在前面的可能都是已经获得许可、但还没有被 poll 的等待者
+                state.grant();
+                self.id = Some(id);
+                id
+            }
+        };
+
+        let index = state
+            .waiters
+            .iter()
+            .position(|waiter| waiter.id == id)
+            .expect("semaphore waiter missing");
+        if state.waiters[index].granted {
+            state.waiters.remove(index);
+            self.id = None;
+            return Poll::Ready(Ok(SemaphorePermit { semaphore, permits: needed }));
+        }
+        if state.closed {
+            state.waiters.remove(index);
+            self.id = None;
+            return Poll::Ready(Err(AcquireError));
+        }
+
+        let waiter = &mut state.waiters[index];
+        if !waiter.waker.as_ref().is_some_and(|waker| waker.will_wake(cx.waker())) {
+            waiter.waker = Some(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        let id = match self.id {
+            Some(id) => id,
+            None => return,
+        };
+        let mut state = self.semaphore.state.lock();
+        if let Some(index) = state.waiters.iter().position(|waiter| waiter.id == id) {
+            let waiter = state.waiters.remove(index).unwrap();
+            if waiter.granted {
+                state.permits += waiter.needed;
+            }
+            // 排在它后面的等待者可能可以获得许可了
+            state.grant();
+        }
+    }
+}
+
+/// 获得的许可, 被释放时归还给信号量
+#[must_use = "the permits are released immediately if the permit is dropped"]
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl SemaphorePermit<'_> {
+    /// 不归还许可
+    pub fn forget(mut self) {
+        self.permits = 0;
+    }
+
+    pub fn num_permits(&self) -> usize {
+        self.permits
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        if self.permits > 0 {
+            self.semaphore.add_permits(self.permits);
+        }
+    }
+}
+
+impl fmt::Debug for SemaphorePermit<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SemaphorePermit").field("permits", &self.permits).finish()
+    }
+}
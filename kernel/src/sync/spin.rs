@@ -0,0 +1,96 @@
+//! 持有期间关中断的自旋锁
+
+use core::fmt;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+
+use spinning_top::{const_spinlock, Spinlock, SpinlockGuard};
+use x86_64::instructions::interrupts;
+
+/// 获取锁之前关中断, 释放锁之后恢复之前的中断状态的自旋锁
+///
+/// 中断处理函数和普通代码都需要访问的数据应该使用它: 如果持有普通的自旋锁时发生中断, 而中断处理函数
+/// 又获取同一个锁, 就会死锁。关中断只对当前处理器有效, 其它处理器仍然会自旋等待。
+pub struct IrqSpinlock<T> {
+    inner: Spinlock<T>,
+}
+
+impl<T> IrqSpinlock<T> {
+    pub const fn new(value: T) -> Self {
+        IrqSpinlock { inner: const_spinlock(value) }
+    }
+
+    pub fn lock(&self) -> IrqSpinlockGuard<'_, T> {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        IrqSpinlockGuard { guard: ManuallyDrop::new(self.inner.lock()), enabled }
+    }
+
+    /// 锁已经被持有时返回 `None`, 不会改变中断状态
+    pub fn try_lock(&self) -> Option<IrqSpinlockGuard<'_, T>> {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqSpinlockGuard { guard: ManuallyDrop::new(guard), enabled }),
+            None => {
+                if enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: Default> Default for IrqSpinlock<T> {
+    fn default() -> Self {
+        IrqSpinlock::new(T::default())
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for IrqSpinlock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("IrqSpinlock").field("data", &*guard).finish(),
+            None => f.write_str("IrqSpinlock { <locked> }"),
+        }
+    }
+}
+
+pub struct IrqSpinlockGuard<'a, T> {
+    guard: ManuallyDrop<SpinlockGuard<'a, T>>,
+    /// 获取锁之前中断是否是开启的
+    enabled: bool,
+}
+
+impl<T> Deref for IrqSpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqSpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqSpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        // 先释放锁再开中断, 否则开中断后发生的中断可能会在这个锁上自旋
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.enabled {
+            interrupts::enable();
+        }
+    }
+}
@@ -0,0 +1,197 @@
+//! # 系统调用 (System Call)
+//! 参考: https://wiki.osdev.org/SYSENTER#AMD:_SYSCALL.2FSYSRET
+//!
+//! 用户程序通过 `syscall` 指令进入内核, 内核通过 `sysretq` 返回用户程序。相关的 MSR:
+//!
+//! - STAR: `syscall` 和 `sysretq` 时加载的段选择子, 对 GDT 中段的顺序有要求, 见 [`gdt`]
+//! - LSTAR: `syscall` 的入口地址, 即 `asm/syscall.s` 中的 `syscall_entry`
+//! - SFMASK: `syscall` 时需要清除的 RFLAGS 位
+//!
+//! `syscall` 不会切换栈和 GS base, 所以入口处需要先 `swapgs`, 再从 per-CPU 数据中读取
+//! 当前任务的内核栈 (与 TSS 中的 RSP0 相同, 见 [`gdt::set_kernel_stack`] 和 [`crate::percpu`]) 。
+//!
+//! ## 调用约定
+//! 与 Linux 相同: `rax` 为系统调用号, `rdi`, `rsi`, `rdx`, `r10`, `r8`, `r9` 为参数,
+//! 返回值保存在 `rax` 中, 出错时返回 `-errno` (见 [`Errno`]) 。
+//!
+//! ```text
+//!  Number    Name      Arguments                  Return
+//!    0      write    fd, buf, len              写入的字节数
+//!    1      exit     code                      不返回
+//!    2      yield    -                         0
+//!    3      sleep    milliseconds              0
+//!    4      time     -                         启动后经过的纳秒数
+//!    5      map      addr, len, flags          addr
+//! ```
+
+use core::arch::global_asm;
+use core::time::Duration;
+
+use crate::{gdt, thread, time, userspace::{self, UserExit}};
+use crate::process::{self, File};
+
+use x86_64::{
+    registers::{
+        model_specific::{Efer, EferFlags, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    structures::paging::{mapper::MapToError, PageTableFlags},
+    VirtAddr,
+};
+
+global_asm!(include_str!("asm/syscall.s"));
+
+extern "C" {
+    fn syscall_entry();
+}
+
+/// 系统调用号
+pub mod number {
+    pub const WRITE: u64 = 0;
+    pub const EXIT: u64 = 1;
+    pub const YIELD: u64 = 2;
+    pub const SLEEP: u64 = 3;
+    pub const TIME: u64 = 4;
+    pub const MAP: u64 = 5;
+}
+
+/// `map` 系统调用的 flags: 映射的内存可写
+pub const MAP_WRITE: u64 = 1 << 0;
+/// `map` 系统调用的 flags: 映射的内存可执行
+pub const MAP_EXEC: u64 = 1 << 1;
+
+/// 错误码, 取值与 Linux 相同
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum Errno {
+    /// Bad file descriptor
+    EBADF = 9,
+    /// Out of memory
+    ENOMEM = 12,
+    /// Bad address
+    EFAULT = 14,
+    /// File exists
+    EEXIST = 17,
+    /// Invalid argument
+    EINVAL = 22,
+    /// Function not implemented
+    ENOSYS = 38,
+}
+
+type SyscallResult = Result<u64, Errno>;
+type SyscallHandler = fn(&[u64; 6]) -> SyscallResult;
+
+/// 系统调用表, 下标为系统调用号
+static SYSCALL_TABLE: [SyscallHandler; 6] = [
+    sys_write,
+    sys_exit,
+    sys_yield,
+    sys_sleep,
+    sys_time,
+    sys_map,
+];
+
+/// `syscall_entry` 在内核栈上保存的寄存器
+#[repr(C)]
+#[derive(Debug)]
+struct SyscallFrame {
+    number: u64,
+    /// rdi, rsi, rdx, r10, r8, r9
+    args: [u64; 6],
+}
+
+/// 设置当前处理器的 `syscall`/`sysretq` 相关的 MSR, 每个处理器都需要在
+/// [`crate::percpu::init`] 之后调用一次
+pub fn init() {
+    let selectors = gdt::selectors();
+    Star::write(
+        selectors.user_code_selector,
+        selectors.user_data_selector,
+        selectors.code_selector,
+        selectors.data_selector,
+    )
+    .expect("GDT layout is incompatible with sysret");
+    LStar::write(VirtAddr::new(syscall_entry as *const () as u64));
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG | RFlags::ALIGNMENT_CHECK,
+    );
+    unsafe { Efer::update(|flags| *flags |= EferFlags::SYSTEM_CALL_EXTENSIONS) };
+}
+
+#[no_mangle]
+extern "C" fn syscall_handler(frame: &SyscallFrame) -> i64 {
+    let result = usize::try_from(frame.number)
+        .ok()
+        .and_then(|number| SYSCALL_TABLE.get(number))
+        .map_or(Err(Errno::ENOSYS), |handler| handler(&frame.args));
+
+    match result {
+        Ok(value) => value as i64,
+        Err(errno) => -(errno as i64),
+    }
+}
+
+/// 将用户内存 `buf` 中的 UTF-8 字符串写入到文件描述符 `fd`
+fn sys_write(args: &[u64; 6]) -> SyscallResult {
+    let [fd, buf, len, ..] = *args;
+    match process::file(fd) {
+        Some(File::Console) => {}
+        None => return Err(Errno::EBADF),
+    }
+
+    let buf = VirtAddr::try_new(buf).map_err(|_| Errno::EFAULT)?;
+    if !userspace::is_user_accessible(buf, len, false) {
+        return Err(Errno::EFAULT);
+    }
+    let bytes = unsafe { core::slice::from_raw_parts(buf.as_ptr::<u8>(), len as usize) };
+    let s = core::str::from_utf8(bytes).map_err(|_| Errno::EINVAL)?;
+    print!("{}", s);
+
+    Ok(len)
+}
+
+fn sys_exit(args: &[u64; 6]) -> SyscallResult {
+    unsafe { userspace::exit_current(UserExit::Exited(args[0] as i64)) }
+}
+
+fn sys_yield(_args: &[u64; 6]) -> SyscallResult {
+    thread::yield_now();
+    Ok(0)
+}
+
+fn sys_sleep(args: &[u64; 6]) -> SyscallResult {
+    thread::sleep(Duration::from_millis(args[0]));
+    Ok(0)
+}
+
+fn sys_time(_args: &[u64; 6]) -> SyscallResult {
+    Ok(time::uptime().as_nanos() as u64)
+}
+
+/// 在当前地址空间中映射 `[addr, addr + len)`, `addr` 必须按页对齐
+fn sys_map(args: &[u64; 6]) -> SyscallResult {
+    let [addr, len, flags, ..] = *args;
+    if flags & !(MAP_WRITE | MAP_EXEC) != 0 || len == 0 {
+        return Err(Errno::EINVAL);
+    }
+    let start = VirtAddr::try_new(addr).map_err(|_| Errno::EINVAL)?;
+    if !start.is_aligned(4096u64) || !userspace::is_user_range(start, len) {
+        return Err(Errno::EINVAL);
+    }
+
+    let mut page_flags = PageTableFlags::empty();
+    if flags & MAP_WRITE != 0 {
+        page_flags |= PageTableFlags::WRITABLE;
+    }
+    if flags & MAP_EXEC == 0 {
+        page_flags |= PageTableFlags::NO_EXECUTE;
+    }
+
+    userspace::map_current(start, len, page_flags).map_err(|err| match err {
+        MapToError::FrameAllocationFailed => Errno::ENOMEM,
+        MapToError::PageAlreadyMapped(_) => Errno::EEXIST,
+        MapToError::ParentEntryHugePage => Errno::EINVAL,
+    })?;
+
+    Ok(addr)
+}
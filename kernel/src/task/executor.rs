@@ -1,125 +1,311 @@
-use super::{Task, TaskId, SPAWN_TASKS_QUEUE};
+use super::{JoinHandle, SpawnError, Task, TaskId, TaskSlot, TaskState, TASK_LIST};
+use crate::percpu::{self, PerCpu};
+use crate::thread::{self, ThreadId};
+use crate::{apic, time};
 
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
-use core::task::{Waker, Context, Poll};
-use crossbeam_queue::ArrayQueue;
+use alloc::{string::String, sync::Arc, task::Wake, vec::Vec};
+use core::fmt;
+use core::future::Future;
+use core::sync::atomic::Ordering;
+use core::task::{Waker, Context};
+use core::time::Duration;
+use x86_64::instructions::interrupts;
 
 pub struct Executor {
-    /// store [`Task`]
-    tasks: BTreeMap<TaskId, Task>,
-    /// shared between the executor and wakers
-    task_queue: Arc<ArrayQueue<TaskId>>,
-    /// reuse the same waker for multiple wake-ups of the same task and
-    /// ensure that reference-counted wakers are not deallocated inside interrupt handlers 
-    /// because it could lead to deadlocks
-    waker_cache: BTreeMap<TaskId, Waker>,
+    /// the CPU running this executor, its run queue is shared with wakers and other executors
+    cpu: &'static PerCpu,
+    /// the thread running this executor, parked while there is no ready task;
+    /// `None` on APs, which have no threads and wait with `hlt` instead
+    thread: Option<ThreadId>,
 }
 
 impl Executor {
-    pub fn spawn(&mut self, task: Task) {
-        let task_id = task.id;
-        if self.tasks.insert(task.id, task).is_some() {
-            panic!("task with same {:#?} already in tasks", task_id);
+    /// 在这个执行器所在的处理器上创建任务, 见 [`super::spawn`]。AP 上的执行器会把任务交给 BSP
+    pub fn spawn<F>(&mut self, future: F) -> Result<JoinHandle<F::Output>, SpawnError>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        super::spawn_at(None, false, future, self.cpu)
+    }
+
+    /// 所有任务和每个处理器的就绪队列的信息, 可以通过 `{}` 输出成表格
+    pub fn snapshot() -> Snapshot {
+        // 在锁外释放任务, 任务被释放时会获取 TASK_LIST 的锁
+        let tasks: Vec<Arc<Task>> = TASK_LIST.lock().values().filter_map(|task| task.upgrade()).collect();
+        let tasks = tasks
+            .iter()
+            .map(|task| TaskInfo {
+                id: task.id,
+                name: task.name.clone(),
+                state: task.state(),
+                cpu: task.cpu.load(Ordering::Relaxed),
+                polls: task.polls.load(Ordering::Relaxed),
+                poll_time: Duration::from_nanos(task.poll_nanos.load(Ordering::Relaxed)),
+            })
+            .collect();
+        let cpus = percpu::cpus()
+            .map(|cpu| CpuInfo {
+                cpu: cpu.cpu_id,
+                ready: cpu.run_queue.len() + cpu.any_cpu_run_queue.len(),
+                irq_ready: cpu.irq_run_queue.len(),
+                idle_executors: cpu.idle_executors.load(Ordering::Relaxed),
+            })
+            .collect();
+        Snapshot { tasks, cpus }
+    }
+}
+
+/// 一个任务的信息, 由 [`Executor::snapshot`] 返回
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub name: Option<String>,
+    pub state: TaskState,
+    /// 上一次运行这个任务的处理器
+    pub cpu: usize,
+    /// 被 poll 的次数
+    pub polls: u64,
+    /// 所有 poll 花费的总时间, 精度为一个时钟周期
+    pub poll_time: Duration,
+}
+
+/// 一个处理器的就绪队列的信息, 由 [`Executor::snapshot`] 返回
+#[derive(Debug, Clone)]
+pub struct CpuInfo {
+    pub cpu: usize,
+    /// `run_queue` 和 `any_cpu_run_queue` 中的任务数
+    pub ready: usize,
+    /// 在中断处理函数中被唤醒的任务数
+    pub irq_ready: usize,
+    /// 正在等待任务的执行器的数量
+    pub idle_executors: usize,
+}
+
+/// 执行器的快照, 可以通过 `{}` 输出成表格
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub tasks: Vec<TaskInfo>,
+    pub cpus: Vec<CpuInfo>,
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:>4} {:>6} {:>10} {:>6}", "CPU", "READY", "IRQ READY", "IDLE")?;
+        for cpu in &self.cpus {
+            writeln!(f, "{:>4} {:>6} {:>10} {:>6}", cpu.cpu, cpu.ready, cpu.irq_ready, cpu.idle_executors)?;
         }
-        self.task_queue.push(task_id).expect("queue full");
+        writeln!(f, "{:>4} {:<16} {:<8} {:>4} {:>8} {:>12}", "ID", "NAME", "STATE", "CPU", "POLLS", "POLL TIME")?;
+        for task in &self.tasks {
+            writeln!(
+                f,
+                "{:>4} {:<16} {:<8} {:>4} {:>8} {:>12}",
+                task.id,
+                task.name.as_deref().unwrap_or("-"),
+                alloc::format!("{:?}", task.state),
+                task.cpu,
+                task.polls,
+                alloc::format!("{:?}", task.poll_time),
+            )?;
+        }
+        Ok(())
     }
 }
 
 impl Executor {
+    /// 创建当前处理器的执行器, 它只能在创建它的线程 (或 AP) 中运行
+    ///
+    /// 同一个处理器上可以有多个执行器, 它们共用这个处理器的就绪队列。
     pub fn new() -> Executor {
-        Executor { 
-            tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)), 
-            waker_cache: BTreeMap::new(),
+        let cpu = percpu::current();
+        let thread = cpu.current_thread.get();
+        if let Some(thread) = thread {
+            interrupts::without_interrupts(|| cpu.executor_threads.lock().push(thread));
         }
+        Executor { cpu, thread }
     }
 
     pub fn run(&mut self) -> ! {
         loop {
-            self.get_spawn_task();
             self.run_ready_tasks();
+            if let Some(task) = self.steal() {
+                self.run_task(task);
+                continue;
+            }
             // interrupt can happen here
             self.sleep_if_idle();
         }
     }
 
-    fn get_spawn_task(&mut self) {
-        let queue = SPAWN_TASKS_QUEUE.get_or_init(|| { ArrayQueue::new(100) });
-        while let Some(raw_task) = queue.pop() {
-            let task = unsafe { raw_task.into_task() };
-            self.task_queue.push(task.id).expect("queue full");
-            self.tasks.insert(task.id, task);
+    fn run_ready_tasks(&mut self) {
+        while let Some(task) = pop_ready(self.cpu) {
+            self.run_task(task);
         }
     }
 
-    fn sleep_if_idle(&self) {
-        use x86_64::instructions::interrupts::{self, enable_and_hlt};
-        // 防止在检查 task_queue 是否为 empty 后, 又发生中断, 这时候 task_queue 就不是真的空的
-        // hlt 会让 cpu 休息到下一个中断发生, 如果 task_queue 不是空就 hlt 就会导致这个任务可能很久都不会被处理
-        interrupts::disable();
-        if self.task_queue.is_empty() {
-            enable_and_hlt();
+    fn run_task(&self, task: Arc<Task>) {
+        // 在 poll 之前清除, 这样 poll 过程中的唤醒会把任务重新放入就绪队列
+        task.set_state(TaskState::Running);
+        task.scheduled.store(false, Ordering::SeqCst);
+        task.cpu.store(self.cpu.cpu_id, Ordering::Relaxed);
+        // the waker is the task itself, creating it does not allocate
+        let waker = Waker::from(task.clone());
+        let mut context = Context::from_waker(&waker);
+
+        let start = time::uptime();
+        let poll = task.poll(&mut context);
+        let elapsed = time::uptime().saturating_sub(start);
+        task.polls.fetch_add(1, Ordering::Relaxed);
+        task.poll_nanos.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+
+        if poll.is_ready() {
+            task.set_state(TaskState::Finished);
         } else {
-            interrupts::enable();
+            // poll 过程中被唤醒时, 状态已经是 Ready 了
+            let _ = task.state.compare_exchange(
+                TaskState::Running as u8,
+                TaskState::Pending as u8,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            );
         }
     }
 
-    fn run_ready_tasks(&mut self) {
-        // destructure `self` to avoid borrow checker errors
-        let Self {
-            tasks,
-            task_queue,
-            waker_cache,
-        } = self;
-
-        while let Some(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                None => continue, // task no longer exists
-            };
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-            let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
-                    // task done -> remove it and its cached waker
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
+    /// 从其它处理器的就绪队列中窃取一个任务, 从下一个处理器开始依次尝试
+    ///
+    /// 没有线程的处理器只窃取 [`super::Builder::any_cpu`] 的任务。
+    fn steal(&self) -> Option<Arc<Task>> {
+        (1..percpu::MAX_CPUS)
+            .map(|offset| (self.cpu.cpu_id + offset) % percpu::MAX_CPUS)
+            .filter_map(percpu::get)
+            .find_map(|cpu| {
+                if runs_threads(self.cpu) || !runs_threads(cpu) {
+                    pop_ready(cpu)
+                } else {
+                    cpu.any_cpu_run_queue.pop()
+                }
+            })
+    }
+
+    fn sleep_if_idle(&self) {
+        // 防止在检查就绪队列是否为空后, 又发生中断, 这时候就绪队列就不是真的空的
+        // park 会让出 CPU 直到有 waker 或 spawn 唤醒这个线程, 如果队列不是空就 park 就会导致这个任务可能很久都不会被处理
+        // 在检查之后被其它线程唤醒也没关系, unpark 会留下一个 token, park 会直接返回;
+        // 关中断时 hlt 也不会错过中断, enable_and_hlt 会在 hlt 之后才响应中断
+        // 其它处理器先放入任务再检查 idle_executors, 这里先增加 idle_executors 再检查队列, 两者至少有一方能看到对方的修改
+        interrupts::disable();
+        self.cpu.idle_executors.fetch_add(1, Ordering::SeqCst);
+        if !has_ready(self.cpu) && !self.can_steal() {
+            match self.thread {
+                Some(_) => thread::park(),
+                None => {
+                    interrupts::enable_and_hlt();
+                    interrupts::disable();
                 }
-                Poll::Pending => {}
             }
         }
+        self.cpu.idle_executors.fetch_sub(1, Ordering::SeqCst);
+        interrupts::enable();
+    }
+
+    fn can_steal(&self) -> bool {
+        percpu::cpus().filter(|cpu| cpu.cpu_id != self.cpu.cpu_id).any(|cpu| {
+            if runs_threads(self.cpu) || !runs_threads(cpu) {
+                has_ready(cpu)
+            } else {
+                !cpu.any_cpu_run_queue.is_empty()
+            }
+        })
     }
+}
 
+/// 从 `cpu` 的就绪队列中取出一个任务, 先取中断处理函数唤醒的任务
+fn pop_ready(cpu: &PerCpu) -> Option<Arc<Task>> {
+    cpu.irq_run_queue.pop().or_else(|| cpu.run_queue.pop()).or_else(|| cpu.any_cpu_run_queue.pop())
 }
 
+fn has_ready(cpu: &PerCpu) -> bool {
+    !cpu.irq_run_queue.is_empty() || !cpu.run_queue.is_empty() || !cpu.any_cpu_run_queue.is_empty()
+}
+
+/// 处理器上是否有线程, 目前只有 BSP 运行线程 (见 [`crate::thread`])
+pub(super) fn runs_threads(cpu: &PerCpu) -> bool {
+    cpu.cpu_id == 0
+}
 
-struct TaskWaker {
-    task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+/// 为任务占用一个位置, 并把它放入 `cpu` 的就绪队列
+pub(super) fn submit(mut task: Task, cpu: &'static PerCpu) -> Result<Arc<Task>, SpawnError> {
+    task.slot = Some(TaskSlot::reserve()?);
+    let task = Arc::new(task);
+    // 在放入就绪队列之前加入, 否则任务可能在加入之前就被其它处理器运行完并释放
+    TASK_LIST.lock().insert(task.id, Arc::downgrade(&task));
+    schedule(task.clone(), cpu);
+    Ok(task)
 }
 
-impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        // the Waker type supports From conversions for all Arc-wrapped values that implement the Wake trait
-        Waker::from(Arc::new(TaskWaker {
-            task_id,
-            task_queue,
-        }))
+/// 把任务放入 `cpu` 的就绪队列并通知它
+///
+/// 在中断处理函数中和关中断时使用不需要分配内存的 `irq_run_queue`, 见 [`super`] 。
+/// 如果 `cpu` 上没有空闲的执行器, 再唤醒一个空闲的处理器来窃取任务。
+fn schedule(task: Arc<Task>, cpu: &'static PerCpu) {
+    task.scheduled.store(true, Ordering::SeqCst);
+    task.cpu.store(cpu.cpu_id, Ordering::Relaxed);
+    task.set_state(TaskState::Ready);
+    if !interrupts::are_enabled() || percpu::in_interrupt() {
+        if let Err(task) = cpu.irq_run_queue.push(task) {
+            // 任务的数量不超过 MAX_TASKS 时不会发生, 这时不能释放任务, 只能泄漏它
+            log::error!("interrupt run queue of CPU {} is full, leaking {:?}", cpu.cpu_id, task);
+            core::mem::forget(task);
+            return;
+        }
+    } else if task.any_cpu && runs_threads(cpu) {
+        cpu.any_cpu_run_queue.push(task);
+    } else {
+        cpu.run_queue.push(task);
     }
+    notify(cpu);
+
+    if cpu.idle_executors.load(Ordering::SeqCst) == 0 {
+        let idle = percpu::cpus()
+            .find(|other| other.cpu_id != cpu.cpu_id && other.idle_executors.load(Ordering::SeqCst) > 0);
+        if let Some(idle) = idle {
+            notify(idle);
+        }
+    }
+}
 
-    fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+/// 通知 `cpu` 上的执行器有新的任务
+///
+/// 当前处理器上的执行器线程会被直接唤醒, 其它处理器只在有空闲的执行器时才会收到 IPI 。
+pub(super) fn notify(cpu: &PerCpu) {
+    if cpu.cpu_id == percpu::current().cpu_id {
+        wake_local();
+    } else if cpu.idle_executors.load(Ordering::SeqCst) > 0 && apic::is_initialized() {
+        apic::send_fixed(cpu.apic_id, apic::WAKEUP_VECTOR);
     }
 }
 
-impl Wake for TaskWaker {
+/// 唤醒当前处理器上所有的执行器线程, 也由唤醒 IPI 的处理函数调用
+///
+/// AP 上的执行器通过 hlt 等待, 收到 IPI 时就已经被唤醒了。
+pub(crate) fn wake_local() {
+    interrupts::without_interrupts(|| {
+        for &thread in percpu!(executor_threads).lock().iter() {
+            thread::unpark(thread);
+        }
+    });
+}
+
+impl Wake for Task {
     fn wake(self: Arc<Self>) {
-        self.wake_task();
+        // 已经在就绪队列中 (或正在被放入) 时, 就绪队列持有另一个引用, 这里释放 self 不会释放任务
+        if !self.scheduled.swap(true, Ordering::SeqCst) {
+            let cpu = percpu::get(self.cpu.load(Ordering::Relaxed)).expect("task on an unknown CPU");
+            schedule(self, cpu);
+        }
     }
 
     fn wake_by_ref(self: &Arc<Self>) {
-        self.wake_task();
+        self.clone().wake();
     }
-}
\ No newline at end of file
+}
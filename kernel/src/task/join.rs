@@ -0,0 +1,159 @@
+//! 等待任务结束并取得它的返回值, 见 [`JoinHandle`]
+
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use alloc::sync::{Arc, Weak};
+use spinning_top::Spinlock;
+
+use super::Task;
+
+/// 任务没有正常结束的原因
+///
+/// 内核不支持 unwinding, 任务 panic 时会直接停机, 所以只有被取消这一种情况。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// 任务被 [`JoinHandle::abort`] 取消了
+    Cancelled,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::Cancelled => write!(f, "task was cancelled"),
+        }
+    }
+}
+
+enum Slot<T> {
+    /// 任务还没有结束, 保存等待它的 waker
+    Running(Option<Waker>),
+    Finished(Result<T, JoinError>),
+    /// 结果已经被 [`JoinHandle`] 取走
+    Taken,
+}
+
+/// 任务与它的 [`JoinHandle`] 共享的结果
+pub(super) struct JoinState<T> {
+    slot: Spinlock<Slot<T>>,
+}
+
+impl<T> JoinState<T> {
+    fn finish(&self, result: Result<T, JoinError>) {
+        let waker = {
+            let mut slot = self.slot.lock();
+            match core::mem::replace(&mut *slot, Slot::Finished(result)) {
+                Slot::Running(waker) => waker,
+                // 只会被调用一次
+                _ => unreachable!("task finished twice"),
+            }
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// 包装任务的 future, 把返回值保存到 [`JoinState`] 中
+///
+/// 在完成之前被释放 (任务被取消) 时, 结果为 [`JoinError::Cancelled`] 。
+pub(super) struct Joinable<F: Future> {
+    future: F,
+    state: Option<Arc<JoinState<F::Output>>>,
+}
+
+impl<F: Future> Joinable<F> {
+    pub(super) fn new(future: F) -> (Self, Arc<JoinState<F::Output>>) {
+        let state = Arc::new(JoinState { slot: Spinlock::new(Slot::Running(None)) });
+        (Joinable { future, state: Some(state.clone()) }, state)
+    }
+}
+
+impl<F: Future> Future for Joinable<F> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        // `future` 不会被移动, `state` 没有被 pin
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        match future.poll(cx) {
+            Poll::Ready(output) => {
+                if let Some(state) = this.state.take() {
+                    state.finish(Ok(output));
+                }
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<F: Future> Drop for Joinable<F> {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.take() {
+            state.finish(Err(JoinError::Cancelled));
+        }
+    }
+}
+
+/// [`super::spawn`] 返回的任务的句柄, 它本身是一个 future, 在任务结束时返回任务的结果
+///
+/// 释放 `JoinHandle` 不会取消任务, 任务会在后台继续运行。`JoinHandle` 不会让任务一直存在,
+/// 任务结束后就会被释放 (结果保存在共享的状态中) 。
+pub struct JoinHandle<T> {
+    task: Weak<Task>,
+    state: Arc<JoinState<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub(super) fn new(task: &Arc<Task>, state: Arc<JoinState<T>>) -> Self {
+        JoinHandle { task: Arc::downgrade(task), state }
+    }
+
+    /// 取消任务, 它的 future 会在下一次被执行器取出时被释放, 之后等待这个句柄会返回
+    /// [`JoinError::Cancelled`] 。任务已经结束时没有效果。
+    pub fn abort(&self) {
+        if let Some(task) = self.task.upgrade() {
+            task.abort();
+        }
+    }
+
+    /// 任务是否已经结束 (包括被取消)
+    pub fn is_finished(&self) -> bool {
+        !matches!(*self.state.slot.lock(), Slot::Running(_))
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    /// Panics if polled again after returning `Poll::Ready`.
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut slot = self.state.slot.lock();
+        match &mut *slot {
+            Slot::Running(waker) => {
+                // 避免每次 poll 都克隆 waker
+                if !waker.as_ref().is_some_and(|waker| waker.will_wake(cx.waker())) {
+                    *waker = Some(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+            Slot::Finished(_) => match core::mem::replace(&mut *slot, Slot::Taken) {
+                Slot::Finished(result) => Poll::Ready(result),
+                _ => unreachable!(),
+            },
+            Slot::Taken => panic!("JoinHandle polled after completion"),
+        }
+    }
+}
+
+impl<T> fmt::Debug for JoinHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JoinHandle")
+            .field("finished", &self.is_finished())
+            .finish_non_exhaustive()
+    }
+}
@@ -1,82 +1,329 @@
+//! Keyboard driver and key event service.
+//!
+//! The interrupt handler only queues raw scancodes. [`service`] runs as a task, decodes them with
+//! the current [`Layout`] and publishes a [`KeyEvent`] for every key press and release to all
+//! receivers returned by [`subscribe`]:
+//!
+//! ```ignore
+//! Builder::new().name("keyboard").spawn(keyboard::service())?;
+//! let mut events = keyboard::subscribe();
+//! while let Some(event) = events.recv().await {
+//!     if let Some(DecodedKey::Unicode(ch)) = event.key {
+//!         print!("{}", ch);
+//!     }
+//! }
+//! ```
+//!
+//! The default layout can be chosen at build time with `--keyboard-layout` (see the README), which
+//! stands in for a kernel command line until there is one, and changed at runtime with
+//! [`set_layout`]. The builder rejects unknown layout names.
+
+use core::fmt;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicU8, Ordering};
 use core::{pin::Pin, task::{Poll, Context}};
 
-use conquer_once::spin::OnceCell;
-use crossbeam_queue::ArrayQueue;
-use futures_util::task::AtomicWaker;
+use alloc::vec::Vec;
 use futures_util::stream::{Stream, StreamExt};
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-
-static WAKER: AtomicWaker = AtomicWaker::new();
-static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
-
-/// Called by the keyboard interrupt handler
-///
-/// Must not block or allocate.
-pub(crate) fn add_scancode(scancode: u8) {
-    if let Ok(queue) = SCANCODE_QUEUE.try_get() {
-        match queue.push(scancode) {
-            Ok(()) => WAKER.wake(), // wake executor
-            Err(_) => log::warn!("scancode queue full; dropping keyboard input"),
+use pc_keyboard::{layouts, DecodeState, DecodedKey, HandleControl, KeyCode, KeyState, KeyboardLayout, ScancodeSet, ScancodeSet1};
+use spin::Mutex;
+
+use crate::interrupts::InterruptIndex;
+use crate::irq::{self, IrqReturn, Sharing};
+use crate::ps2::{self, Leds};
+use crate::sync::mpsc::{self, TrySendError};
+use crate::sync::{IrqChannel, IrqStream};
+
+static SCANCODES: IrqChannel<u8, 100> = IrqChannel::new();
+
+/// Key events buffered for each subscriber, later events are dropped while it is full
+const SUBSCRIBER_CAPACITY: usize = 64;
+
+static SUBSCRIBERS: Mutex<Vec<mpsc::Sender<KeyEvent>>> = Mutex::new(Vec::new());
+static LAYOUT: AtomicU8 = AtomicU8::new(Layout::Us as u8);
+
+/// Registers the keyboard interrupt handler and selects the layout given at build time
+pub fn init() {
+    if let Some(name) = option_env!("KERNEL_KEYBOARD_LAYOUT") {
+        match name.parse() {
+            Ok(layout) => set_layout(layout),
+            Err(err) => log::warn!("{}: {}, using {}", err, name, layout()),
         }
-    } else {
-        // It’s important that we don’t try to initialize the queue in this function 
-        // because it will be called by the interrupt handler, 
-        // which should not perform heap allocations. 
-        log::warn!("scancode queue uninitialized");
     }
+    ps2::set_receiver(ps2::Port::First, receive_scancode);
+    irq::register_vector(InterruptIndex::Keyboard.as_u8(), "keyboard", Sharing::Exclusive, keyboard_interrupt)
+        .expect("failed to register the keyboard interrupt handler");
+}
+
+/// Called in interrupt context, must not block or allocate.
+fn keyboard_interrupt() -> IrqReturn {
+    ps2::handle_interrupt(ps2::Port::First)
 }
 
+/// Called by the PS/2 driver, possibly in interrupt context
+fn receive_scancode(scancode: u8) {
+    if SCANCODES.push(scancode).is_err() {
+        log::warn!("scancode queue full; dropping keyboard input");
+    }
+}
+
+/// Scancodes received by the keyboard interrupt handler
 pub struct ScancodeStream {
-    /// prevent construction of [`ScancodeStream`] from outside of the module.
-    _private: (),
+    inner: IrqStream<u8>,
 }
 
 impl ScancodeStream {
     pub fn new() -> Self {
-        SCANCODE_QUEUE.try_init_once(|| ArrayQueue::new(100))
-            .expect("ScancodeStream::new should only be called once");
-        ScancodeStream { _private: () }
+        ScancodeStream { inner: SCANCODES.try_stream().expect("ScancodeStream::new should only be called once") }
     }
 }
 
 impl Stream for ScancodeStream {
     type Item = u8;
 
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
-        let queue = SCANCODE_QUEUE.try_get().expect("not initialized");
-        // the queue is potentially empty
-        if let Some(scancode) = queue.pop() {
-            return Poll::Ready(Some(scancode));
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        Pin::new(&mut self.inner).poll_next(cx)
+    }
+}
+
+/// Keyboard layouts that can be selected at runtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Layout {
+    Us,
+    Uk,
+    De,
+    /// AZERTY
+    Fr,
+    Dvorak,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 5] = [Layout::Us, Layout::Uk, Layout::De, Layout::Fr, Layout::Dvorak];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Us => "us",
+            Layout::Uk => "uk",
+            Layout::De => "de",
+            Layout::Fr => "fr",
+            Layout::Dvorak => "dvorak",
+        }
+    }
+
+    fn map_keycode(self, code: KeyCode, modifiers: &pc_keyboard::Modifiers) -> DecodedKey {
+        // Ctrl combinations are mapped by `Decoder`, which also handles punctuation
+        let handle_ctrl = HandleControl::Ignore;
+        match self {
+            Layout::Us => layouts::Us104Key::map_keycode(code, modifiers, handle_ctrl),
+            Layout::Uk => layouts::Uk105Key::map_keycode(code, modifiers, handle_ctrl),
+            Layout::De => layouts::De105Key::map_keycode(code, modifiers, handle_ctrl),
+            Layout::Fr => layouts::Azerty::map_keycode(code, modifiers, handle_ctrl),
+            Layout::Dvorak => layouts::Dvorak104Key::map_keycode(code, modifiers, handle_ctrl),
+        }
+    }
+}
+
+impl FromStr for Layout {
+    type Err = &'static str;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Layout::ALL
+            .into_iter()
+            .find(|layout| layout.name().eq_ignore_ascii_case(name))
+            .ok_or("unknown keyboard layout")
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The layout used to decode key events
+pub fn layout() -> Layout {
+    let value = LAYOUT.load(Ordering::Relaxed);
+    Layout::ALL.into_iter().find(|&layout| layout as u8 == value).unwrap_or(Layout::Us)
+}
+
+/// Switches the layout, takes effect from the next key event
+pub fn set_layout(layout: Layout) {
+    LAYOUT.store(layout as u8, Ordering::Relaxed);
+    log::info!("keyboard layout: {}", layout);
+}
+
+/// Modifier and lock key state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+    pub lshift: bool,
+    pub rshift: bool,
+    pub lctrl: bool,
+    pub rctrl: bool,
+    pub alt: bool,
+    pub alt_gr: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl Modifiers {
+    const fn new() -> Self {
+        Modifiers {
+            lshift: false,
+            rshift: false,
+            lctrl: false,
+            rctrl: false,
+            alt: false,
+            alt_gr: false,
+            caps_lock: false,
+            // the BIOS turns Num Lock on at boot
+            num_lock: true,
+            scroll_lock: false,
         }
-        // register the Waker in the WAKER static before the second check
-        // a wakeup might happen before we return Poll::Pending, 
-        // but it is guaranteed that we get a wakeup for any scancodes pushed after the check
-        WAKER.register(&cx.waker());
-        // try to pop from the queue a second time
-        match queue.pop() {
-            Some(scancode) => {
-                // remove the registered waker
-                WAKER.take();
-                Poll::Ready(Some(scancode))
+    }
+
+    pub fn shift(&self) -> bool {
+        self.lshift || self.rshift
+    }
+
+    pub fn ctrl(&self) -> bool {
+        self.lctrl || self.rctrl
+    }
+
+    /// The keyboard LEDs that show the lock key state
+    pub fn leds(&self) -> Leds {
+        Leds { scroll_lock: self.scroll_lock, num_lock: self.num_lock, caps_lock: self.caps_lock }
+    }
+
+    fn to_pc_keyboard(self) -> pc_keyboard::Modifiers {
+        pc_keyboard::Modifiers {
+            lshift: self.lshift,
+            rshift: self.rshift,
+            lctrl: self.lctrl,
+            rctrl: self.rctrl,
+            numlock: self.num_lock,
+            capslock: self.caps_lock,
+            alt_gr: self.alt_gr,
+        }
+    }
+
+    /// Updates the state for a modifier or lock key, returns `false` for other keys
+    fn update(&mut self, code: KeyCode, state: KeyState) -> bool {
+        let down = state == KeyState::Down;
+        match code {
+            KeyCode::ShiftLeft => self.lshift = down,
+            KeyCode::ShiftRight => self.rshift = down,
+            KeyCode::ControlLeft => self.lctrl = down,
+            KeyCode::ControlRight => self.rctrl = down,
+            KeyCode::AltLeft => self.alt = down,
+            KeyCode::AltRight => self.alt_gr = down,
+            KeyCode::CapsLock => self.caps_lock ^= down,
+            KeyCode::NumpadLock => self.num_lock ^= down,
+            KeyCode::ScrollLock => self.scroll_lock ^= down,
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// A key press or release
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub state: KeyState,
+    /// Modifier state after this event
+    pub modifiers: Modifiers,
+    /// What the key produces in the current layout, with Ctrl combinations mapped to control
+    /// characters (e.g. Ctrl+C is `'\u{3}'`). `None` for releases and modifier keys.
+    pub key: Option<DecodedKey>,
+}
+
+/// Turns scancodes into key events
+struct Decoder {
+    state: DecodeState,
+    modifiers: Modifiers,
+}
+
+impl Decoder {
+    const fn new() -> Self {
+        Decoder { state: DecodeState::Start, modifiers: Modifiers::new() }
+    }
+
+    fn add_byte(&mut self, scancode: u8, layout: Layout) -> Option<KeyEvent> {
+        let event = match ScancodeSet1::advance_state(&mut self.state, scancode) {
+            Ok(event) => event?,
+            Err(err) => {
+                log::debug!("bad scancode {:#04x}: {:?}", scancode, err);
+                return None;
             }
-            None => Poll::Pending,
+        };
+        let is_modifier = self.modifiers.update(event.code, event.state);
+        let key = if event.state == KeyState::Down && !is_modifier {
+            Some(self.decode(event.code, layout))
+        } else {
+            None
+        };
+        Some(KeyEvent { code: event.code, state: event.state, modifiers: self.modifiers, key })
+    }
+
+    fn decode(&self, code: KeyCode, layout: Layout) -> DecodedKey {
+        match layout.map_keycode(code, &self.modifiers.to_pc_keyboard()) {
+            DecodedKey::Unicode(ch) if self.modifiers.ctrl() => DecodedKey::Unicode(control_char(ch).unwrap_or(ch)),
+            key => key,
         }
     }
 }
 
-pub async fn print_keypresses() {
-    let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1,
-        HandleControl::Ignore);
+/// The control character for Ctrl+`ch`, e.g. Ctrl+A is `'\u{1}'` and Ctrl+[ is ESC
+fn control_char(ch: char) -> Option<char> {
+    match ch {
+        'a'..='z' => Some(char::from(ch as u8 - b'a' + 1)),
+        '@'..='_' => Some(char::from(ch as u8 - b'@')),
+        ' ' => Some('\0'),
+        '?' => Some('\u{7f}'),
+        _ => None,
+    }
+}
+
+/// Receives every key event decoded by [`service`]
+pub fn subscribe() -> mpsc::Receiver<KeyEvent> {
+    let (sender, receiver) = mpsc::channel(SUBSCRIBER_CAPACITY);
+    SUBSCRIBERS.lock().push(sender);
+    receiver
+}
 
+/// Decodes scancodes and publishes key events to subscribers, there should be only one.
+/// Also keeps the keyboard LEDs in sync with the lock keys.
+pub async fn service() {
+    let mut scancodes = ScancodeStream::new();
+    let mut decoder = Decoder::new();
+    let mut leds = decoder.modifiers.leds();
+    update_leds(leds);
     while let Some(scancode) = scancodes.next().await {
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    DecodedKey::Unicode(character) => print!("{}", character),
-                    DecodedKey::RawKey(key) => print!("{:?}", key),
-                }
+        if let Some(event) = decoder.add_byte(scancode, layout()) {
+            if event.modifiers.leds() != leds {
+                leds = event.modifiers.leds();
+                update_leds(leds);
             }
+            publish(event);
         }
     }
-}
\ No newline at end of file
+}
+
+fn update_leds(leds: Leds) {
+    if let Err(err) = ps2::set_leds(leds) {
+        log::warn!("failed to update keyboard LEDs: {}", err);
+    }
+}
+
+fn publish(event: KeyEvent) {
+    SUBSCRIBERS.lock().retain(|subscriber| match subscriber.try_send(event) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+            log::warn!("keyboard subscriber is full; dropping key event");
+            true
+        }
+        Err(TrySendError::Closed(_)) => false,
+    });
+}
@@ -1,19 +1,110 @@
+//! # 异步任务
+//!
+//! 每个处理器都有自己的执行器 ([`executor::Executor`]) 和就绪队列 (保存在 [`crate::percpu`] 中)。
+//! 任务可以在处理器之间移动, 所以任务的 future 必须是 `Send` 的:
+//!
+//! - [`spawn`] 把任务放入当前处理器的就绪队列, [`spawn_on`] 放入指定处理器的就绪队列,
+//!   它们返回的 [`JoinHandle`] 可以用来等待任务的返回值或取消任务
+//! - 空闲的执行器会从其它处理器的就绪队列中窃取任务 (work stealing)
+//! - 只有 BSP 运行线程, AP 上没有当前线程, 所以 [`crate::thread`] 和 [`crate::process`] 的 API 在 AP
+//!   上不能使用。默认的任务只在 BSP 上运行, 用 [`Builder::any_cpu`] 创建的任务才会被 AP 运行
+//! - 任务被唤醒时, 会回到上一次运行它的处理器的就绪队列中, 如果那个处理器正在等待任务,
+//!   就通过 IPI 唤醒它 (见 [`executor::notify`])
+//!
+//! ## 查看任务
+//! 每个任务都有一个 [`TaskId`] 和可选的名字 (见 [`Builder`]), 执行器会记录任务的状态
+//! ([`TaskState`])、被 poll 的次数和时间。[`executor::Executor::snapshot`] 返回所有任务的信息。
+//!
+//! ## 就绪队列
+//! 每个处理器有两个就绪队列:
+//!
+//! - 无界的 `run_queue` (`SegQueue`, 按段分配的无锁队列), 用于创建任务和在开中断时唤醒任务
+//! - 有界的 `irq_run_queue` (`ArrayQueue`), 用于在中断处理函数中和关中断时唤醒任务, 因为那里不能
+//!   分配内存。同步原语持有 [`crate::sync::IrqSpinlock`] 时唤醒等待者也属于后一种情况
+//!
+//! 运行线程的处理器还把 [`Builder::any_cpu`] 的任务放入单独的 `any_cpu_run_queue`, AP 只能从这里
+//! (和其它 AP 的就绪队列) 窃取任务。
+//!
+//! 同一个任务同一时间最多只在一个就绪队列中, 而存在的任务不会超过 [`MAX_TASKS`] 个 (超过时
+//! [`spawn`] 返回 [`SpawnError::TooManyTasks`]), 所以容量为 [`MAX_TASKS`] 的 `irq_run_queue`
+//! 不会被填满。
+//!
+//! ## 中断安全
+//! 唤醒任务不会分配内存: waker 就是任务自己的 `Arc`, 唤醒时会被移动到就绪队列中。只有在任务
+//! 已经在就绪队列中或正在运行时, 唤醒才会直接释放 waker, 这时还有其它的引用, 所以不会释放任务。
+
 pub mod executor;
 pub mod keyboard;
+pub mod mouse;
+mod join;
+
+pub use executor::{CpuInfo, Snapshot, TaskInfo};
+pub use join::{JoinError, JoinHandle};
 
 use core::{
-    future::Future, 
+    fmt,
+    future::Future,
     pin::Pin, task::{Context, Poll},
-    sync::atomic::{AtomicU64, Ordering}, ptr::NonNull,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicU64, AtomicUsize, Ordering},
 };
-use alloc::boxed::Box;
-use crossbeam_queue::ArrayQueue;
-use conquer_once::spin::OnceCell;
+use alloc::{boxed::Box, collections::BTreeMap, string::{String, ToString}, sync::{Arc, Weak}};
+use spinning_top::{const_spinlock, Spinlock};
+
+use crate::percpu::{self, PerCpu};
+use join::Joinable;
+
+/// 同时存在的任务的最大数量, 也是每个处理器的 `irq_run_queue` 的容量
+pub const MAX_TASKS: usize = 1024;
 
-pub(super) static SPAWN_TASKS_QUEUE: OnceCell<ArrayQueue<RawTask>> = OnceCell::uninit();
+/// 存在的任务的数量, 见 [`TaskSlot`]
+static TASKS: AtomicUsize = AtomicUsize::new(0);
+/// 所有被交给执行器的任务, 用于 [`executor::Executor::snapshot`], 任务被释放时移除
+static TASK_LIST: Spinlock<BTreeMap<TaskId, Weak<Task>>> = const_spinlock(BTreeMap::new());
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
+/// 创建任务失败的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    /// 存在的任务已经达到 [`MAX_TASKS`] 个
+    TooManyTasks,
+    /// 这个编号的处理器不存在或还没有启动
+    NoSuchCpu(usize),
+    /// 这个编号的处理器不运行线程, 只能运行 [`Builder::any_cpu`] 的任务
+    NoThreads(usize),
+}
+
+impl fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpawnError::TooManyTasks => write!(f, "too many tasks (at most {})", MAX_TASKS),
+            SpawnError::NoSuchCpu(cpu) => write!(f, "CPU {} is not online", cpu),
+            SpawnError::NoThreads(cpu) => write!(f, "CPU {} does not run threads", cpu),
+        }
+    }
+}
+
+/// 占用 [`MAX_TASKS`] 中的一个位置, 在任务被释放时归还
+#[derive(Debug)]
+struct TaskSlot(());
+
+impl TaskSlot {
+    fn reserve() -> Result<TaskSlot, SpawnError> {
+        TASKS
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |tasks| {
+                (tasks < MAX_TASKS).then_some(tasks + 1)
+            })
+            .map(|_| TaskSlot(()))
+            .map_err(|_| SpawnError::TooManyTasks)
+    }
+}
+
+impl Drop for TaskSlot {
+    fn drop(&mut self) {
+        TASKS.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TaskId(u64);
 
 impl TaskId {
     fn new() -> Self {
@@ -23,49 +114,242 @@ impl TaskId {
         // the compiler is allowed to reorder the fetch_add operation in the instructions stream.
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// 任务的状态
+///
+/// ```text
+///   spawn/唤醒 --> Ready --> Running --> Pending --> (唤醒) Ready
+///                              |
+///                              +--> Finished (完成或被取消)
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TaskState {
+    /// 在就绪队列中, 等待执行器取出
+    Ready,
+    /// 正在被某个执行器 poll
+    Running,
+    /// 返回了 `Poll::Pending`, 等待被唤醒
+    Pending,
+    /// 已经完成或被取消, 但还有 waker 或 [`JoinHandle`] 引用它
+    Finished,
+}
+
+impl TaskState {
+    fn from_u8(state: u8) -> Self {
+        match state {
+            0 => TaskState::Ready,
+            1 => TaskState::Running,
+            2 => TaskState::Pending,
+            _ => TaskState::Finished,
+        }
+    }
 }
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
 pub struct Task {
     id: TaskId,
-    future: Pin<Box<dyn Future<Output = ()>>>,
+    name: Option<String>,
+    /// [`TaskState`]
+    state: AtomicU8,
+    /// 被 poll 的次数
+    polls: AtomicU64,
+    /// 所有 poll 花费的总时间 (纳秒), 精度为一个时钟周期
+    poll_nanos: AtomicU64,
+    /// 任务结束后为 `None`, 同一时间只有一个执行器能 poll 它
+    future: Spinlock<Option<BoxFuture>>,
+    /// 任务是否已经在某个就绪队列中, 防止同一个任务被重复放入就绪队列
+    scheduled: AtomicBool,
+    /// 任务被取消了, 执行器下一次取出它时会释放 future, 见 [`JoinHandle::abort`]
+    aborted: AtomicBool,
+    /// 上一次运行这个任务的处理器, 唤醒时会回到这个处理器
+    cpu: AtomicUsize,
+    /// 可以在没有线程的处理器上运行, 见 [`Builder::any_cpu`]
+    any_cpu: bool,
+    /// 被交给执行器之后才会占用位置, 见 [`executor::submit`]
+    slot: Option<TaskSlot>,
 }
 
 impl Task {
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+    fn new(name: Option<String>, any_cpu: bool, future: impl Future<Output = ()> + Send + 'static) -> Task {
         Task {
             id: TaskId::new(),
-            future: Box::pin(future),
+            name,
+            state: AtomicU8::new(TaskState::Ready as u8),
+            polls: AtomicU64::new(0),
+            poll_nanos: AtomicU64::new(0),
+            future: Spinlock::new(Some(Box::pin(future))),
+            scheduled: AtomicBool::new(false),
+            aborted: AtomicBool::new(false),
+            cpu: AtomicUsize::new(percpu::current().cpu_id),
+            any_cpu,
+            slot: None,
+        }
+    }
+
+    /// poll 任务一次, 返回任务是否已经结束
+    ///
+    /// 如果任务正在被其它处理器 poll (它在 poll 时被唤醒, 又被窃取), 会等待它完成。
+    fn poll(&self, cx: &mut Context) -> Poll<()> {
+        let mut future = self.future.lock();
+        if self.aborted.load(Ordering::Acquire) {
+            *future = None;
+        }
+        match future.as_mut() {
+            Some(inner) => {
+                let poll = inner.as_mut().poll(cx);
+                if poll.is_ready() {
+                    // 在执行器中释放 future, 而不是在最后一个 waker 被释放的地方 (可能是中断处理函数)
+                    *future = None;
+                }
+                poll
+            }
+            None => Poll::Ready(()),
         }
     }
 
-    fn poll(&mut self, cx: &mut Context) -> Poll<()> {
-        self.future.as_mut().poll(cx)
+    fn state(&self) -> TaskState {
+        TaskState::from_u8(self.state.load(Ordering::Acquire))
+    }
+
+    fn set_state(&self, state: TaskState) {
+        self.state.store(state as u8, Ordering::Release);
+    }
+
+    /// 取消任务, 并唤醒它, 让执行器尽快释放它的 future
+    fn abort(self: Arc<Self>) {
+        self.aborted.store(true, Ordering::Release);
+        alloc::task::Wake::wake(self);
     }
 }
 
-#[derive(Debug)]
-pub(super) struct RawTask {
-    pointer: NonNull<dyn Future<Output = ()> + 'static>,
+impl fmt::Debug for Task {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Task")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .field("state", &self.state())
+            .field("scheduled", &self.scheduled)
+            .field("aborted", &self.aborted)
+            .field("cpu", &self.cpu)
+            .field("any_cpu", &self.any_cpu)
+            .finish_non_exhaustive()
+    }
 }
 
-impl RawTask {
-    pub(super) fn new(future: impl Future<Output = ()> + 'static) -> RawTask {
-        let pointer = NonNull::new(Box::leak(Box::new(future)))
-            .expect("Failed to allocate memory for future.");
-        RawTask { pointer }
+impl Drop for Task {
+    fn drop(&mut self) {
+        TASK_LIST.lock().remove(&self.id);
     }
+}
+
+/// 创建任务时的选项
+///
+/// ```ignore
+/// let handle = Builder::new().name("keyboard").cpu(0).spawn(print_keypresses())?;
+/// ```
+#[derive(Debug, Default)]
+pub struct Builder {
+    name: Option<String>,
+    cpu: Option<usize>,
+    any_cpu: bool,
+}
 
-    pub(super) unsafe fn into_task(self) -> Task {
-        let future = Box::from_raw(self.pointer.as_ptr());
-        Task { id: TaskId::new(), future: Pin::new_unchecked(future) }
+impl Builder {
+    pub fn new() -> Self {
+        Builder::default()
     }
+
+    /// 任务的名字, 只用于调试
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// 在编号为 `cpu` 的处理器上创建任务, 默认为当前处理器。当前处理器不运行线程时, 默认为 BSP
+    pub fn cpu(mut self, cpu: usize) -> Self {
+        self.cpu = Some(cpu);
+        self
+    }
+
+    /// 任务不使用需要当前线程的 API ([`crate::thread`], [`crate::process`]), 可以在没有线程的 AP 上
+    /// 运行, 也可以被 AP 窃取。没有这个选项的任务只在运行线程的处理器上运行
+    pub fn any_cpu(mut self) -> Self {
+        self.any_cpu = true;
+        self
+    }
+
+    pub fn spawn<F>(self, future: F) -> Result<JoinHandle<F::Output>, SpawnError>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let cpu = match self.cpu {
+            Some(id) => {
+                let cpu = percpu::get(id).ok_or(SpawnError::NoSuchCpu(id))?;
+                if !self.any_cpu && !executor::runs_threads(cpu) {
+                    return Err(SpawnError::NoThreads(id));
+                }
+                cpu
+            }
+            None => percpu::current(),
+        };
+        spawn_at(self.name, self.any_cpu, future, cpu)
+    }
+}
+
+/// 在当前处理器上创建任务, 空闲的处理器可能会窃取它。任务不能在 AP 上运行, 见 [`Builder::any_cpu`]
+pub fn spawn<F>(future: F) -> Result<JoinHandle<F::Output>, SpawnError>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    Builder::new().spawn(future)
 }
 
-unsafe impl Send for RawTask {}
+/// 在编号为 `cpu` 的处理器上创建任务, `cpu` 需要运行线程, 否则使用 [`Builder::any_cpu`]
+pub fn spawn_on<F>(cpu: usize, future: F) -> Result<JoinHandle<F::Output>, SpawnError>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    Builder::new().cpu(cpu).spawn(future)
+}
 
-pub fn spawn(future: impl Future<Output = ()> + 'static) {
-    let raw_task = RawTask::new(future);
-    SPAWN_TASKS_QUEUE
-        .get_or_init(|| {ArrayQueue::new(100)})
-        .push(raw_task)
-        .expect("queue full");
-}
\ No newline at end of file
+fn spawn_at<F>(
+    name: Option<String>,
+    any_cpu: bool,
+    future: F,
+    cpu: &'static PerCpu,
+) -> Result<JoinHandle<F::Output>, SpawnError>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    // 在 AP 上创建的任务默认交给 BSP
+    let cpu = if any_cpu || executor::runs_threads(cpu) {
+        cpu
+    } else {
+        percpu::get(0).expect("the BSP has no per-CPU data")
+    };
+    let (future, state) = Joinable::new(future);
+    let task = executor::submit(Task::new(name, any_cpu, future), cpu)?;
+    Ok(JoinHandle::new(&task, state))
+}
+
+/// 当前存在的任务的数量, 包括已经结束但还有 waker 引用的任务
+pub fn task_count() -> usize {
+    TASKS.load(Ordering::Acquire)
+}
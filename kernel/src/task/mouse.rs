@@ -0,0 +1,228 @@
+//! PS/2 mouse driver.
+//!
+//! The interrupt handler queues the raw bytes from the auxiliary port. [`MouseStream`] assembles
+//! them into packets and yields a [`MouseEvent`] for each one, like [`ScancodeStream`] does for
+//! the keyboard:
+//!
+//! ```ignore
+//! let mut events = MouseStream::new();
+//! while let Some(event) = events.next().await {
+//!     log::info!("{:?}", event);
+//! }
+//! ```
+//!
+//! [`init`] switches IntelliMouse compatible mice into scroll wheel (and 5-button) mode, which
+//! makes packets 4 bytes long instead of 3. The mouse only starts reporting movement once a
+//! [`MouseStream`] is created, so nothing is queued while no task is listening.
+//!
+//! [`ScancodeStream`]: super::keyboard::ScancodeStream
+
+use core::{pin::Pin, task::{Poll, Context}};
+
+use futures_util::stream::Stream;
+
+use crate::interrupts::InterruptIndex;
+use crate::irq::{self, IrqReturn, Sharing};
+use crate::ps2::{self, Device, Port};
+use crate::sync::{IrqChannel, IrqStream};
+
+// https://wiki.osdev.org/PS/2_Mouse
+const SET_SAMPLE_RATE: u8 = 0xf3;
+const ENABLE_REPORTING: u8 = 0xf4;
+/// Sample rates that switch an IntelliMouse into scroll wheel mode (ID 3)
+const SCROLL_WHEEL_SEQUENCE: [u8; 3] = [200, 100, 80];
+/// Sample rates that switch a scroll wheel mouse into 5-button mode (ID 4)
+const FIVE_BUTTON_SEQUENCE: [u8; 3] = [200, 200, 80];
+/// Samples per second
+const SAMPLE_RATE: u8 = 100;
+
+// Bits of the first byte of a packet
+const LEFT_BUTTON: u8 = 1;
+const RIGHT_BUTTON: u8 = 1 << 1;
+const MIDDLE_BUTTON: u8 = 1 << 2;
+/// Always set, used to find the start of a packet
+const ALWAYS_ONE: u8 = 1 << 3;
+const X_SIGN: u8 = 1 << 4;
+const Y_SIGN: u8 = 1 << 5;
+const X_OVERFLOW: u8 = 1 << 6;
+const Y_OVERFLOW: u8 = 1 << 7;
+
+// Bits of the fourth byte in 5-button mode, the low 4 bits are the wheel movement
+const FOURTH_BUTTON: u8 = 1 << 4;
+const FIFTH_BUTTON: u8 = 1 << 5;
+
+static BYTES: IrqChannel<u8, 256> = IrqChannel::new();
+
+/// Enables scroll wheel mode if there is a mouse on the auxiliary port and registers the mouse
+/// interrupt handler
+pub fn init() {
+    if !ps2::device(Port::Second).is_some_and(Device::is_mouse) {
+        return;
+    }
+    ps2::set_receiver(Port::Second, receive_byte);
+    match enable_extensions() {
+        Ok(device) => log::info!("ps2: mouse mode: {}", device),
+        Err(err) => log::warn!("failed to enable the mouse scroll wheel: {}", err),
+    }
+    irq::register_vector(InterruptIndex::Mouse.as_u8(), "mouse", Sharing::Exclusive, mouse_interrupt)
+        .expect("failed to register the mouse interrupt handler");
+}
+
+/// Sends the "magic" sample rate sequences, the mouse reports a new ID when it supports them
+fn enable_extensions() -> Result<Device, ps2::Error> {
+    set_sample_rates(&SCROLL_WHEEL_SEQUENCE)?;
+    let mut device = ps2::identify(Port::Second)?;
+    if device == Device::ScrollMouse {
+        set_sample_rates(&FIVE_BUTTON_SEQUENCE)?;
+        device = ps2::identify(Port::Second)?;
+    }
+    set_sample_rates(&[SAMPLE_RATE])?;
+    Ok(device)
+}
+
+fn set_sample_rates(rates: &[u8]) -> Result<(), ps2::Error> {
+    rates.iter().try_for_each(|&rate| ps2::send(Port::Second, &[SET_SAMPLE_RATE, rate]))
+}
+
+/// Called in interrupt context, must not block or allocate.
+fn mouse_interrupt() -> IrqReturn {
+    ps2::handle_interrupt(Port::Second)
+}
+
+/// Called by the PS/2 driver, possibly in interrupt context
+fn receive_byte(byte: u8) {
+    if BYTES.push(byte).is_err() {
+        log::warn!("mouse queue full; dropping mouse input");
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MouseButtons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+    /// Only reported by 5-button mice, usually "back"
+    pub fourth: bool,
+    /// Only reported by 5-button mice, usually "forward"
+    pub fifth: bool,
+}
+
+/// One packet from the mouse
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    /// Movement since the last packet, right is positive
+    pub dx: i16,
+    /// Movement since the last packet, up is positive
+    pub dy: i16,
+    /// Buttons held down
+    pub buttons: MouseButtons,
+    /// Scroll wheel movement, scrolling down is positive. Always 0 without a scroll wheel.
+    pub wheel: i8,
+}
+
+/// Assembles bytes into packets
+struct PacketDecoder {
+    packet: [u8; 4],
+    len: usize,
+    /// 3, or 4 with a scroll wheel
+    size: usize,
+    five_buttons: bool,
+}
+
+impl PacketDecoder {
+    fn new(device: Option<Device>) -> Self {
+        let size = match device {
+            Some(Device::ScrollMouse | Device::FiveButtonMouse) => 4,
+            _ => 3,
+        };
+        PacketDecoder { packet: [0; 4], len: 0, size, five_buttons: device == Some(Device::FiveButtonMouse) }
+    }
+
+    fn add_byte(&mut self, byte: u8) -> Option<MouseEvent> {
+        // resynchronize after a dropped byte by waiting for a valid first byte
+        if self.len == 0 && byte & ALWAYS_ONE == 0 {
+            log::debug!("mouse: skipping byte {:#04x} to find the start of a packet", byte);
+            return None;
+        }
+        self.packet[self.len] = byte;
+        self.len += 1;
+        if self.len < self.size {
+            return None;
+        }
+        self.len = 0;
+        self.decode()
+    }
+
+    fn decode(&self) -> Option<MouseEvent> {
+        let flags = self.packet[0];
+        // the movement is meaningless when it overflowed
+        if flags & (X_OVERFLOW | Y_OVERFLOW) != 0 {
+            return None;
+        }
+        let dx = i16::from(self.packet[1]) - if flags & X_SIGN != 0 { 0x100 } else { 0 };
+        let dy = i16::from(self.packet[2]) - if flags & Y_SIGN != 0 { 0x100 } else { 0 };
+
+        let mut buttons = MouseButtons {
+            left: flags & LEFT_BUTTON != 0,
+            right: flags & RIGHT_BUTTON != 0,
+            middle: flags & MIDDLE_BUTTON != 0,
+            ..MouseButtons::default()
+        };
+        let extra = self.packet[3];
+        let wheel = match self.size {
+            4 if self.five_buttons => {
+                buttons.fourth = extra & FOURTH_BUTTON != 0;
+                buttons.fifth = extra & FIFTH_BUTTON != 0;
+                // sign extend the low 4 bits
+                ((extra << 4) as i8) >> 4
+            }
+            4 => extra as i8,
+            _ => 0,
+        };
+        Some(MouseEvent { dx, dy, buttons, wheel })
+    }
+}
+
+/// Mouse events decoded from the bytes received by the mouse interrupt handler
+pub struct MouseStream {
+    inner: IrqStream<u8>,
+    decoder: PacketDecoder,
+}
+
+impl MouseStream {
+    /// Also makes the mouse start reporting movement
+    pub fn new() -> Self {
+        let inner = BYTES.try_stream().expect("MouseStream::new should only be called once");
+        let device = ps2::device(Port::Second);
+        if device.is_some_and(Device::is_mouse) {
+            if let Err(err) = ps2::queue(Port::Second, &[ENABLE_REPORTING]) {
+                log::warn!("failed to enable mouse reporting: {}", err);
+            }
+        }
+        MouseStream { inner, decoder: PacketDecoder::new(device) }
+    }
+}
+
+impl Default for MouseStream {
+    fn default() -> Self {
+        MouseStream::new()
+    }
+}
+
+impl Stream for MouseStream {
+    type Item = MouseEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<MouseEvent>> {
+        let this = &mut *self;
+        loop {
+            let byte = match Pin::new(&mut this.inner).poll_next(cx) {
+                Poll::Ready(Some(byte)) => byte,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            if let Some(event) = this.decoder.add_byte(byte) {
+                return Poll::Ready(Some(event));
+            }
+        }
+    }
+}
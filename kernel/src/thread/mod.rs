@@ -0,0 +1,559 @@
+//! # 内核线程
+//!
+//! 每个线程都有自己的栈 (见 [`memory::alloc_stack`]) 。切换线程时, 当前线程的 callee-saved
+//! 寄存器和 rflags 会被保存在它自己的栈上, 栈指针保存在 [`Thread`] 中, 然后切换到新线程的栈,
+//! 恢复它的寄存器 (见 `asm/thread.s`) 。
+//!
+//! ## 调度
+//! 下一个运行的线程由 [`Scheduler`] 决定, 默认使用 [`RoundRobin`], 可以通过 [`set_scheduler`]
+//! 替换。每个线程有一个 nice 值 (见 [`set_nice`]), 是否使用由调度算法决定。
+//!
+//! ## 抢占
+//! 时钟中断会调用 [`tick`], 当前线程的时间片 (见 [`set_quantum`]) 用完后, 就会在中断处理函数中
+//! 切换到下一个线程。被抢占的线程在下一次被调度时, 会从中断处理函数中返回到被打断的地方。
+//!
+//! ## 线程状态
+//! ```text
+//!                 调度
+//!   spawn --> Ready <---> Running ---> Dead (由其它线程释放栈)
+//!               ^           |
+//!   unpark/唤醒  |           | park/sleep
+//!               +- Blocked <+
+//! ```
+//!
+//! 当没有其它可以运行的线程时, 会运行 idle 线程, 它通过 `hlt` 等待下一个中断。
+//!
+//! 当前线程保存在 per-CPU 数据中 (见 [`crate::percpu`]) 。目前只有 BSP 运行线程, 线程也不会
+//! 在处理器之间迁移。
+//!
+//! ## 中断安全
+//! 线程表的锁只会在关中断的情况下获取, 所以可以在中断处理函数中使用 [`tick`] 和 [`unpark`] 。
+//! 中断处理函数中不能分配或释放堆内存 (被打断的线程可能正持有堆的锁), 所以:
+//!
+//! - 就绪队列的容量在创建线程时预留 (见 [`Scheduler::add`])
+//! - 退出的线程由 [`spawn`] 或 idle 线程释放
+
+mod scheduler;
+
+pub use scheduler::{FairShare, Nice, RoundRobin, Scheduler};
+
+use core::arch::global_asm;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use alloc::{boxed::Box, collections::BTreeMap, string::{String, ToString}, vec::Vec};
+
+use crate::{gdt, memory::{self, Stack}, time, userspace::{self, UserContext}};
+
+use conquer_once::spin::OnceCell;
+use spinning_top::{Spinlock, SpinlockGuard};
+use x86_64::{instructions::interrupts, VirtAddr};
+
+global_asm!(include_str!("../asm/thread.s"));
+
+extern "C" {
+    fn switch_context(old_rsp: *mut u64, new_rsp: u64);
+    fn thread_trampoline();
+}
+
+/// 线程栈的大小 (页数)
+const STACK_PAGES: u64 = 16;
+/// 默认的时间片长度 (时钟中断的次数)
+const DEFAULT_QUANTUM_TICKS: u64 = 1;
+
+static THREADS: OnceCell<Spinlock<ThreadTable>> = OnceCell::uninit();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    Blocked,
+    Dead,
+}
+
+struct Thread {
+    name: String,
+    /// 启动时的线程使用 bootloader 分配的栈, 所以为 `None`
+    stack: Option<Stack>,
+    /// 线程被切换出去时的栈指针
+    rsp: u64,
+    /// 线程在用户态时, 进入内核使用的栈 (TSS 中的 RSP0)
+    kernel_stack: VirtAddr,
+    /// 线程在用户态时的内核状态, 见 [`userspace`]
+    user_context: UserContext,
+    state: ThreadState,
+    nice: Nice,
+    /// 在线程没有阻塞时调用了 [`unpark`], 下一次 [`park`] 会直接返回
+    unpark_token: bool,
+    /// 在这个时钟中断之后唤醒 (sleep)
+    wake_at: Option<u64>,
+    /// 线程的入口函数, 在线程第一次运行时取出
+    entry: Option<Box<dyn FnOnce() + Send>>,
+    /// 不包括正在进行的这一次运行
+    runtime: Duration,
+    /// 最近一次开始运行的时间
+    last_started: Duration,
+    /// 被切换到的次数
+    context_switches: u64,
+}
+
+impl Thread {
+    fn new(name: &str, stack: Option<Stack>, rsp: u64, kernel_stack: VirtAddr) -> Self {
+        Thread {
+            name: name.to_string(),
+            stack,
+            rsp,
+            kernel_stack,
+            user_context: UserContext::default(),
+            state: ThreadState::Ready,
+            nice: Nice::DEFAULT,
+            unpark_token: false,
+            wake_at: None,
+            entry: None,
+            runtime: Duration::ZERO,
+            last_started: Duration::ZERO,
+            context_switches: 0,
+        }
+    }
+}
+
+struct ThreadTable {
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    scheduler: Box<dyn Scheduler>,
+    idle: ThreadId,
+    /// 时间片的长度 (时钟中断的次数)
+    quantum: u64,
+    /// 当前线程在这个时间片中已经运行的时钟中断次数
+    slice: u64,
+    /// 启动后线程切换的总次数
+    context_switches: u64,
+}
+
+impl ThreadTable {
+    fn current_mut(&mut self) -> &mut Thread {
+        let current = current_id();
+        self.threads.get_mut(&current).expect("current thread not in thread table")
+    }
+
+    fn make_ready(&mut self, id: ThreadId) {
+        let thread = self.threads.get_mut(&id).expect("thread not in thread table");
+        thread.state = ThreadState::Ready;
+        thread.wake_at = None;
+        if id != self.idle {
+            self.scheduler.enqueue(id);
+        }
+    }
+}
+
+/// 一个线程的调度信息, 由 [`stats`] 返回
+#[derive(Debug, Clone)]
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    pub name: String,
+    pub state: ThreadState,
+    pub nice: Nice,
+    /// 线程运行的总时间, 精度为一个时钟周期
+    pub runtime: Duration,
+    /// 线程被切换到的次数
+    pub context_switches: u64,
+}
+
+/// 调度器的统计信息, 可以通过 `{}` 输出成表格
+#[derive(Debug, Clone)]
+pub struct SchedulerStats {
+    /// 调度算法的名字
+    pub scheduler: &'static str,
+    pub quantum: Duration,
+    /// 启动后线程切换的总次数
+    pub context_switches: u64,
+    /// 就绪队列中的线程数 (不包括 idle 线程)
+    pub ready: usize,
+    pub threads: Vec<ThreadInfo>,
+}
+
+impl fmt::Display for SchedulerStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "scheduler: {}, quantum: {:?}, context switches: {}, ready: {}",
+            self.scheduler, self.quantum, self.context_switches, self.ready,
+        )?;
+        writeln!(f, "{:>4} {:<16} {:<8} {:>4} {:>12} {:>10}", "TID", "NAME", "STATE", "NICE", "RUNTIME", "SWITCHES")?;
+        for thread in &self.threads {
+            writeln!(
+                f,
+                "{:>4} {:<16} {:<8} {:>4} {:>12} {:>10}",
+                thread.id,
+                thread.name,
+                alloc::format!("{:?}", thread.state),
+                thread.nice,
+                alloc::format!("{:?}", thread.runtime),
+                thread.context_switches,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// 将当前的执行流 (`_start`) 作为第一个线程, 并创建 idle 线程
+///
+/// 需要在内存初始化之后调用。
+pub fn init() {
+    let main = ThreadId::new();
+    let mut main_thread = Box::new(Thread::new("main", None, 0, gdt::kernel_stack()));
+    main_thread.state = ThreadState::Running;
+    let mut scheduler: Box<dyn Scheduler> = Box::new(RoundRobin::new());
+    scheduler.add(main, Nice::DEFAULT);
+
+    // idle 线程不在调度器中, 只在没有其它线程可以运行时被调度
+    let (idle, idle_thread) = new_thread("idle", idle_loop);
+
+    percpu!(current_thread).set(Some(main));
+    let mut threads = BTreeMap::new();
+    threads.insert(main, main_thread);
+    threads.insert(idle, idle_thread);
+    THREADS.init_once(|| Spinlock::new(ThreadTable {
+        threads,
+        scheduler,
+        idle,
+        quantum: DEFAULT_QUANTUM_TICKS,
+        slice: 0,
+        context_switches: 0,
+    }));
+}
+
+/// 创建一个新线程, 它会在之后的某次调度中开始运行
+pub fn spawn(name: &str, f: impl FnOnce() + Send + 'static) -> ThreadId {
+    reap_dead_threads();
+
+    let (id, thread) = new_thread(name, f);
+    interrupts::without_interrupts(|| {
+        let mut table = table();
+        table.scheduler.add(id, thread.nice);
+        table.threads.insert(id, thread);
+        table.make_ready(id);
+    });
+
+    id
+}
+
+/// 当前线程的 id
+pub fn current() -> ThreadId {
+    current_id()
+}
+
+/// 让出 CPU, 切换到下一个就绪的线程
+pub fn yield_now() {
+    interrupts::without_interrupts(|| {
+        let table = table();
+        schedule(table, ThreadState::Ready);
+    });
+}
+
+/// 阻塞当前线程, 直到其它线程或中断处理函数调用 [`unpark`]
+pub fn park() {
+    interrupts::without_interrupts(|| {
+        let mut table = table();
+        let current = table.current_mut();
+        if current.unpark_token {
+            current.unpark_token = false;
+            return;
+        }
+        schedule(table, ThreadState::Blocked);
+    });
+}
+
+/// 唤醒被 [`park`] 阻塞的线程, 可以在中断处理函数中调用
+pub fn unpark(id: ThreadId) {
+    interrupts::without_interrupts(|| {
+        let mut table = table();
+        let thread = match table.threads.get_mut(&id) {
+            Some(thread) => thread,
+            None => return,
+        };
+        match thread.state {
+            ThreadState::Blocked if thread.wake_at.is_none() => table.make_ready(id),
+            ThreadState::Dead => {}
+            _ => thread.unpark_token = true,
+        }
+    });
+}
+
+/// 让当前线程睡眠至少 `duration`
+pub fn sleep(duration: Duration) {
+    let deadline = time::ticks() + time::duration_to_ticks(duration);
+    interrupts::without_interrupts(|| {
+        let mut table = table();
+        table.current_mut().wake_at = Some(deadline);
+        schedule(table, ThreadState::Blocked);
+    });
+}
+
+/// 结束当前线程
+pub fn exit() -> ! {
+    interrupts::disable();
+    let table = table();
+    schedule(table, ThreadState::Dead);
+    unreachable!("dead thread was scheduled again");
+}
+
+/// 修改线程的 nice 值, 线程不存在时返回 `false`
+pub fn set_nice(id: ThreadId, nice: Nice) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut table = table();
+        match table.threads.get_mut(&id) {
+            Some(thread) => thread.nice = nice,
+            None => return false,
+        }
+        if id != table.idle {
+            table.scheduler.set_nice(id, nice);
+        }
+        true
+    })
+}
+
+/// 替换调度算法, 所有线程的 nice 值和状态会被保留, 就绪队列中的顺序不会被保留
+pub fn set_scheduler(mut scheduler: Box<dyn Scheduler>) {
+    let name = scheduler.name();
+    let old = interrupts::without_interrupts(|| {
+        let mut table = table();
+        for (&id, thread) in &table.threads {
+            if id == table.idle || thread.state == ThreadState::Dead {
+                continue;
+            }
+            scheduler.add(id, thread.nice);
+            if thread.state == ThreadState::Ready {
+                scheduler.enqueue(id);
+            }
+        }
+        core::mem::replace(&mut table.scheduler, scheduler)
+    });
+    log::info!("scheduler changed from {} to {}", old.name(), name);
+}
+
+/// 设置时间片的长度, 会被向上取整到时钟中断的周期 (见 [`time::set_frequency`]), 至少为一个周期
+///
+/// 时间片以时钟中断的次数保存, 修改时钟中断的频率后需要重新设置。
+pub fn set_quantum(quantum: Duration) {
+    let ticks = time::duration_to_ticks(quantum).max(1);
+    interrupts::without_interrupts(|| table().quantum = ticks);
+}
+
+/// 时间片的长度
+pub fn quantum() -> Duration {
+    let ticks = interrupts::without_interrupts(|| table().quantum);
+    time::tick_duration() * ticks as u32
+}
+
+/// 调度器和所有线程的统计信息
+pub fn stats() -> SchedulerStats {
+    interrupts::without_interrupts(|| {
+        let table = table();
+        let now = time::uptime();
+        let threads = table
+            .threads
+            .iter()
+            .map(|(&id, thread)| {
+                let mut runtime = thread.runtime;
+                if id == current_id() {
+                    runtime += now.saturating_sub(thread.last_started);
+                }
+                ThreadInfo {
+                    id,
+                    name: thread.name.clone(),
+                    state: thread.state,
+                    nice: thread.nice,
+                    runtime,
+                    context_switches: thread.context_switches,
+                }
+            })
+            .collect();
+
+        SchedulerStats {
+            scheduler: table.scheduler.name(),
+            quantum: time::tick_duration() * table.quantum as u32,
+            context_switches: table.context_switches,
+            ready: table.scheduler.ready_count(),
+            threads,
+        }
+    })
+}
+
+/// 由时钟中断调用: 唤醒睡眠结束的线程, 并在时间片用完时切换线程
+pub(crate) fn tick() {
+    let mut table = match THREADS.get() {
+        Some(table) => table.lock(),
+        None => return,
+    };
+
+    let now = time::ticks();
+    let ThreadTable { threads, scheduler, idle, .. } = &mut *table;
+    for (&id, thread) in threads.iter_mut() {
+        if thread.state == ThreadState::Blocked && thread.wake_at.is_some_and(|t| t <= now) {
+            thread.wake_at = None;
+            thread.state = ThreadState::Ready;
+            if id != *idle {
+                scheduler.enqueue(id);
+            }
+        }
+    }
+
+    table.slice += 1;
+    let (current, slice, quantum) = (current_id(), table.slice, table.quantum);
+    let preempt = if current == table.idle {
+        table.scheduler.ready_count() > 0
+    } else {
+        table.scheduler.tick(current, slice, quantum)
+    };
+    if preempt {
+        schedule(table, ThreadState::Ready);
+    }
+}
+
+/// 当前处理器上正在运行的线程
+fn current_id() -> ThreadId {
+    percpu!(current_thread).get().expect("thread::init has not been called")
+}
+
+fn table() -> SpinlockGuard<'static, ThreadTable> {
+    THREADS.get().expect("thread::init has not been called").lock()
+}
+
+/// 分配栈并创建一个还没有加入线程表的线程
+fn new_thread(name: &str, f: impl FnOnce() + Send + 'static) -> (ThreadId, Box<Thread>) {
+    let stack = memory::alloc_stack(STACK_PAGES).expect("failed to allocate thread stack");
+    let id = ThreadId::new();
+
+    // switch_context 恢复寄存器时使用的初始栈, 见 `asm/thread.s`
+    let stack_top = stack.end().as_u64();
+    let initial_frame: [u64; 8] = [
+        0x2,                                  // rflags, 关中断, 在 thread_start 中开启
+        0,                                    // r15
+        0,                                    // r14
+        0,                                    // r13
+        id.0,                                 // r12, thread_trampoline 的参数
+        0,                                    // rbx
+        0,                                    // rbp
+        thread_trampoline as *const () as u64, // 返回地址
+    ];
+    let rsp = stack_top - core::mem::size_of_val(&initial_frame) as u64;
+    unsafe { (rsp as *mut [u64; 8]).write(initial_frame) };
+
+    let mut thread = Box::new(Thread::new(name, Some(stack), rsp, stack.end()));
+    thread.entry = Some(Box::new(f));
+    (id, thread)
+}
+
+/// 将当前线程的状态设置为 `state`, 并切换到下一个就绪的线程
+///
+/// 必须在关中断的情况下调用。
+fn schedule(mut table: SpinlockGuard<'static, ThreadTable>, state: ThreadState) {
+    let current = current_id();
+    match state {
+        ThreadState::Ready => table.make_ready(current),
+        state => table.current_mut().state = state,
+    }
+
+    let next = table.scheduler.pick_next().unwrap_or(table.idle);
+    table.slice = 0;
+    if next == current {
+        table.current_mut().state = ThreadState::Running;
+        return;
+    }
+    percpu!(current_thread).set(Some(next));
+    table.context_switches += 1;
+
+    // Box 中的线程在切换完成前不会被释放 (关中断, 且退出的线程只会在其它线程中被释放)
+    let now = time::uptime();
+    let old: *mut Thread = &mut **table.threads.get_mut(&current).unwrap();
+    let new: *mut Thread = &mut **table.threads.get_mut(&next).expect("next thread not in thread table");
+    unsafe {
+        (*old).runtime += now.saturating_sub((*old).last_started);
+        (*new).last_started = now;
+        (*new).context_switches += 1;
+        (*new).state = ThreadState::Running;
+
+        (*old).kernel_stack = gdt::kernel_stack();
+        gdt::set_kernel_stack((*new).kernel_stack);
+        userspace::switch_context(&mut (*old).user_context, &(*new).user_context);
+
+        drop(table);
+        switch_context(&mut (*old).rsp, (*new).rsp);
+    }
+}
+
+/// 新线程的入口, 由 `thread_trampoline` 调用
+#[no_mangle]
+extern "C" fn thread_start(id: u64) -> ! {
+    let entry = {
+        let mut table = table();
+        let thread = table.threads.get_mut(&ThreadId(id)).expect("new thread not in thread table");
+        thread.entry.take().expect("thread started twice")
+    };
+    interrupts::enable();
+
+    entry();
+    exit();
+}
+
+fn idle_loop() {
+    loop {
+        reap_dead_threads();
+
+        interrupts::disable();
+        if table().scheduler.ready_count() == 0 {
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+        yield_now();
+    }
+}
+
+/// 释放已经退出的线程的栈和 [`Thread`], 不能在中断处理函数中调用
+fn reap_dead_threads() {
+    let dead: Vec<Box<Thread>> = interrupts::without_interrupts(|| {
+        let mut table = table();
+        let current = current_id();
+        let ids: Vec<ThreadId> = table
+            .threads
+            .iter()
+            .filter(|(&id, thread)| thread.state == ThreadState::Dead && id != current)
+            .map(|(&id, _)| id)
+            .collect();
+        ids.into_iter()
+            .filter_map(|id| {
+                table.scheduler.remove(id);
+                table.threads.remove(&id)
+            })
+            .collect()
+    });
+
+    for thread in dead {
+        log::debug!("thread {} exited", thread.name);
+        if let Some(stack) = thread.stack {
+            unsafe { memory::free_stack(stack) };
+        }
+    }
+}
@@ -0,0 +1,213 @@
+//! # 调度算法
+//!
+//! [`Scheduler`] 只负责决定下一个运行的线程, 线程的状态、栈和上下文切换由 [`crate::thread`] 管理。
+//! idle 线程不会被加入调度器, 在调度器中没有就绪的线程时才会运行。
+//!
+//! ## 中断安全
+//! [`Scheduler::enqueue`], [`Scheduler::pick_next`] 和 [`Scheduler::tick`] 可能在中断处理函数中
+//! 被调用, 不能分配或释放堆内存, 需要的内存应该在 [`Scheduler::add`] 中预留。
+
+use core::fmt;
+
+use alloc::{collections::{BTreeMap, VecDeque}, vec::Vec};
+
+use super::ThreadId;
+
+/// 线程的 nice 值, 范围为 -20 (优先级最高) 到 19 (优先级最低), 与 Linux 相同
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Nice(i8);
+
+impl Nice {
+    pub const MIN: Nice = Nice(-20);
+    pub const MAX: Nice = Nice(19);
+    pub const DEFAULT: Nice = Nice(0);
+
+    /// 超出范围的值会被截断到 [`Nice::MIN`] 或 [`Nice::MAX`]
+    pub fn new(value: i8) -> Self {
+        Nice(value.clamp(Self::MIN.0, Self::MAX.0))
+    }
+
+    pub fn as_i8(self) -> i8 {
+        self.0
+    }
+
+    /// 在 [`FairShare`] 中的权重, nice 值每减小 1, 权重大约增加 25%
+    pub fn weight(self) -> u64 {
+        // 与 Linux 的 sched_prio_to_weight 相同
+        const WEIGHTS: [u64; 40] = [
+            88761, 71755, 56483, 46273, 36291,
+            29154, 23254, 18705, 14949, 11916,
+            9548, 7620, 6100, 4904, 3906,
+            3121, 2501, 1991, 1586, 1277,
+            1024, 820, 655, 526, 423,
+            335, 272, 215, 172, 137,
+            110, 87, 70, 56, 45,
+            36, 29, 23, 18, 15,
+        ];
+        WEIGHTS[(self.0 - Self::MIN.0) as usize]
+    }
+}
+
+impl fmt::Display for Nice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// 调度算法
+pub trait Scheduler: Send {
+    /// 调度算法的名字, 用于调试输出
+    fn name(&self) -> &'static str;
+
+    /// 一个新线程加入调度器, 此时它还不在就绪队列中
+    ///
+    /// 不会在中断处理函数中调用, 可以在这里为就绪队列预留内存。
+    fn add(&mut self, id: ThreadId, nice: Nice);
+
+    /// 线程已经退出, 从调度器中移除, 不会在中断处理函数中调用
+    fn remove(&mut self, id: ThreadId);
+
+    /// 修改线程的 nice 值, 默认忽略
+    fn set_nice(&mut self, _id: ThreadId, _nice: Nice) {}
+
+    /// 线程进入就绪状态
+    fn enqueue(&mut self, id: ThreadId);
+
+    /// 从就绪队列中取出下一个要运行的线程
+    fn pick_next(&mut self) -> Option<ThreadId>;
+
+    /// 就绪队列中的线程数
+    fn ready_count(&self) -> usize;
+
+    /// 由时钟中断调用, `current` 在这个时间片中已经运行了 `slice` 个时钟中断,
+    /// 时间片的长度为 `quantum`, 返回是否需要抢占 `current`
+    fn tick(&mut self, current: ThreadId, slice: u64, quantum: u64) -> bool;
+}
+
+/// 轮转调度: 按照进入就绪状态的顺序运行, 忽略 nice 值
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    ready: VecDeque<ThreadId>,
+    threads: usize,
+}
+
+impl RoundRobin {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Scheduler for RoundRobin {
+    fn name(&self) -> &'static str {
+        "round-robin"
+    }
+
+    fn add(&mut self, _id: ThreadId, _nice: Nice) {
+        self.threads += 1;
+        self.ready.reserve(self.threads - self.ready.len());
+    }
+
+    fn remove(&mut self, id: ThreadId) {
+        self.threads -= 1;
+        self.ready.retain(|&ready| ready != id);
+    }
+
+    fn enqueue(&mut self, id: ThreadId) {
+        self.ready.push_back(id);
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId> {
+        self.ready.pop_front()
+    }
+
+    fn ready_count(&self) -> usize {
+        self.ready.len()
+    }
+
+    fn tick(&mut self, _current: ThreadId, slice: u64, quantum: u64) -> bool {
+        slice >= quantum
+    }
+}
+
+/// 按权重公平分配 CPU 时间, 与 Linux 的 CFS 类似
+///
+/// 每个线程有一个虚拟运行时间 (vruntime), 运行时按 `NICE_0_WEIGHT / weight` 的比例增长,
+/// 每次调度选择 vruntime 最小的线程。所以 CPU 时间按权重 (见 [`Nice::weight`]) 分配,
+/// nice 值每相差 1, 得到的 CPU 时间大约相差 25% 。
+///
+/// 就绪队列是一个无序的数组, 选择下一个线程需要 O(n) 的时间, 但是不需要在中断中分配内存。
+#[derive(Debug, Default)]
+pub struct FairShare {
+    entities: BTreeMap<ThreadId, Entity>,
+    ready: Vec<ThreadId>,
+    /// 就绪和正在运行的线程的 vruntime 的下界, 被唤醒的线程的 vruntime 至少为这个值,
+    /// 否则睡眠了很久的线程会长时间独占 CPU
+    min_vruntime: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entity {
+    vruntime: u64,
+    weight: u64,
+}
+
+impl FairShare {
+    /// nice 值为 0 的线程的权重
+    const NICE_0_WEIGHT: u64 = 1024;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Scheduler for FairShare {
+    fn name(&self) -> &'static str {
+        "fair-share"
+    }
+
+    fn add(&mut self, id: ThreadId, nice: Nice) {
+        self.entities.insert(id, Entity { vruntime: self.min_vruntime, weight: nice.weight() });
+        self.ready.reserve(self.entities.len() - self.ready.len());
+    }
+
+    fn remove(&mut self, id: ThreadId) {
+        self.entities.remove(&id);
+        self.ready.retain(|&ready| ready != id);
+    }
+
+    fn set_nice(&mut self, id: ThreadId, nice: Nice) {
+        if let Some(entity) = self.entities.get_mut(&id) {
+            entity.weight = nice.weight();
+        }
+    }
+
+    fn enqueue(&mut self, id: ThreadId) {
+        if let Some(entity) = self.entities.get_mut(&id) {
+            entity.vruntime = entity.vruntime.max(self.min_vruntime);
+            self.ready.push(id);
+        }
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId> {
+        let entities = &self.entities;
+        let (index, vruntime) = self
+            .ready
+            .iter()
+            .enumerate()
+            .map(|(index, id)| (index, entities[id].vruntime))
+            .min_by_key(|&(_, vruntime)| vruntime)?;
+        self.min_vruntime = self.min_vruntime.max(vruntime);
+        Some(self.ready.swap_remove(index))
+    }
+
+    fn ready_count(&self) -> usize {
+        self.ready.len()
+    }
+
+    fn tick(&mut self, current: ThreadId, slice: u64, quantum: u64) -> bool {
+        if let Some(entity) = self.entities.get_mut(&current) {
+            entity.vruntime += Self::NICE_0_WEIGHT * Self::NICE_0_WEIGHT / entity.weight;
+        }
+        slice >= quantum
+    }
+}
@@ -0,0 +1,80 @@
+//! # 时间
+//! 通过统计 PIT (Programmable Interval Timer) 触发的时钟中断次数来计算系统启动后经过的时间。
+//!
+//! PIT 的输入频率为 1193182 Hz, 默认的分频系数为 65536, 所以时钟中断大约每 54.9 ms 触发一次。
+//! 可以通过 [`set_frequency`] 修改时钟中断的频率, 修改前经过的时间不受影响。
+//! 参考: https://wiki.osdev.org/Programmable_Interval_Timer
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use x86_64::instructions::{interrupts, port::Port};
+
+/// PIT 的输入频率 (Hz)
+const PIT_FREQUENCY: u64 = 1_193_182;
+/// PIT 默认的分频系数
+const PIT_DEFAULT_DIVISOR: u64 = 65536;
+
+const PIT_CHANNEL_0_PORT: u16 = 0x40;
+const PIT_COMMAND_PORT: u16 = 0x43;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// 启动后经过的纳秒数, 每次时钟中断增加一个周期
+static NANOS: AtomicU64 = AtomicU64::new(0);
+/// 当前的分频系数
+static DIVISOR: AtomicU64 = AtomicU64::new(PIT_DEFAULT_DIVISOR);
+
+/// 由时钟中断调用
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    NANOS.fetch_add(tick_nanos(), Ordering::Relaxed);
+}
+
+/// 启动后触发的时钟中断次数
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// 启动后经过的时间, 精度为一个时钟周期
+pub fn uptime() -> Duration {
+    Duration::from_nanos(NANOS.load(Ordering::Relaxed))
+}
+
+/// 时钟中断的周期
+pub fn tick_duration() -> Duration {
+    Duration::from_nanos(tick_nanos())
+}
+
+/// 将 `duration` 转换为时钟中断的次数 (向上取整)
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let ticks = (duration.as_nanos() * u128::from(PIT_FREQUENCY))
+        .div_ceil(u128::from(DIVISOR.load(Ordering::Relaxed)) * 1_000_000_000);
+    ticks as u64
+}
+
+/// 将时钟中断的频率设置为最接近 `hz` 的值, 返回实际的频率
+///
+/// 频率的范围大约为 18.2 Hz 到 1193182 Hz, 超出范围时会被截断。
+/// 已经开始的 [`crate::thread::sleep`] 仍按原来的周期计算。
+pub fn set_frequency(hz: u32) -> u32 {
+    let divisor = (PIT_FREQUENCY / u64::from(hz.max(1))).clamp(1, PIT_DEFAULT_DIVISOR);
+
+    interrupts::without_interrupts(|| {
+        let mut command: Port<u8> = Port::new(PIT_COMMAND_PORT);
+        let mut channel_0: Port<u8> = Port::new(PIT_CHANNEL_0_PORT);
+        unsafe {
+            // 通道 0, 先写低字节再写高字节, 模式 3 (方波), 二进制计数
+            command.write(0b0011_0110);
+            // 分频系数 65536 需要写入 0
+            channel_0.write(divisor as u8);
+            channel_0.write((divisor >> 8) as u8);
+        }
+        DIVISOR.store(divisor, Ordering::Relaxed);
+    });
+
+    (PIT_FREQUENCY / divisor) as u32
+}
+
+fn tick_nanos() -> u64 {
+    DIVISOR.load(Ordering::Relaxed) * 1_000_000_000 / PIT_FREQUENCY
+}
@@ -0,0 +1,459 @@
+//! # 终端的行规程 (line discipline)
+//!
+//! [`Tty`] 从按键的 stream 中读取输入, 通过一个 [`fmt::Write`] 回显, 有两种模式:
+//!
+//! - [`Mode::Canonical`]: [`Tty::read_line`] 支持行编辑, 按下 Enter 后才返回一整行:
+//!   - Backspace / Delete 删除光标前 / 后的字符
+//!   - 左右方向键、Home 和 End 移动光标
+//!   - 上下方向键浏览历史 (最多 [`HISTORY_LEN`] 行)
+//!   - Ctrl+C 放弃当前行, 返回 [`ReadError::Interrupted`]
+//!   - 在空行上按 Ctrl+D 返回 [`ReadError::Eof`]
+//! - [`Mode::Raw`]: 不回显, 也不解释任何按键, [`Tty::read_key`] 原样返回每一个按键
+//!   (如: Ctrl+C 为 `Input::Char('\u{3}')`), [`Tty::read_line`] 只是收集字符直到 Enter
+//!
+//! 回显时通过退格符 (`'\x08'`, 只移动光标, 不擦除字符) 移动光标, 所以输出端只需要支持
+//! 退格、换行和可打印的 ASCII 字符, [`crate::logger`] 和串口终端都满足这个要求。
+//!
+//! 输入端可以是键盘 ([`KeyboardInput`]) 或 VT100 终端 ([`TerminalInput`], 如: 串口) 。
+//!
+//! ```ignore
+//! let mut tty = Tty::new(KeyboardInput::new(), Console);
+//! loop {
+//!     print!("> ");
+//!     match tty.read_line().await {
+//!         Ok(line) => println!("{}", line),
+//!         Err(ReadError::Interrupted) => continue,
+//!         Err(_) => break,
+//!     }
+//! }
+//! ```
+
+use core::fmt::{self, Write};
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use futures_util::stream::{Stream, StreamExt};
+use pc_keyboard::{DecodedKey, KeyCode};
+
+use crate::sync::mpsc;
+use crate::task::keyboard::{self, KeyEvent};
+
+/// 保存的历史的最大行数
+pub const HISTORY_LEN: usize = 32;
+
+const BACKSPACE: char = '\u{8}';
+const DELETE: char = '\u{7f}';
+const CTRL_C: char = '\u{3}';
+const CTRL_D: char = '\u{4}';
+
+/// 终端的一次按键
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Input {
+    /// 字符, 包括控制字符 (如: Enter 为 `'\n'`, Backspace 为 `'\u{8}'`, Ctrl+C 为 `'\u{3}'`)
+    Char(char),
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    /// 向后删除, 有的输入端把它作为字符 `'\u{7f}'` 发送
+    Delete,
+}
+
+/// 终端的模式, 见 [`crate::tty`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Canonical,
+    Raw,
+}
+
+/// [`Tty::read_line`] 没有读到一行的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadError {
+    /// 按下了 Ctrl+C, 当前行被放弃
+    Interrupted,
+    /// 在空行上按下了 Ctrl+D
+    Eof,
+    /// 输入的 stream 已经结束
+    Closed,
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadError::Interrupted => write!(f, "interrupted"),
+            ReadError::Eof => write!(f, "end of input"),
+            ReadError::Closed => write!(f, "input closed"),
+        }
+    }
+}
+
+/// 行规程, 从 `I` 读取按键, 向 `W` 回显
+pub struct Tty<I, W> {
+    input: I,
+    output: W,
+    mode: Mode,
+    /// 最早的一行在最前面
+    history: VecDeque<String>,
+}
+
+impl<I, W> Tty<I, W>
+where
+    I: Stream<Item = Input> + Unpin,
+    W: Write,
+{
+    /// 创建一个 [`Mode::Canonical`] 模式的终端
+    pub fn new(input: I, output: W) -> Self {
+        Tty { input, output, mode: Mode::Canonical, history: VecDeque::new() }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+    }
+
+    /// 用于输出, 如: 在提示符之前输出命令的结果
+    pub fn output(&mut self) -> &mut W {
+        &mut self.output
+    }
+
+    /// 读取下一个按键, 不会回显, 也不会被解释
+    pub async fn read_key(&mut self) -> Option<Input> {
+        self.input.next().await
+    }
+
+    /// 读取一行, 不包括行尾的换行符, 见 [`crate::tty`]
+    pub async fn read_line(&mut self) -> Result<String, ReadError> {
+        match self.mode {
+            Mode::Canonical => self.read_line_canonical().await,
+            Mode::Raw => self.read_line_raw().await,
+        }
+    }
+
+    async fn read_line_raw(&mut self) -> Result<String, ReadError> {
+        let mut line = String::new();
+        loop {
+            match self.read_key().await.ok_or(ReadError::Closed)? {
+                Input::Char('\n') | Input::Char('\r') => return Ok(line),
+                Input::Char(ch) => line.push(ch),
+                _ => {}
+            }
+        }
+    }
+
+    async fn read_line_canonical(&mut self) -> Result<String, ReadError> {
+        let mut editor = LineEditor { line: Vec::new(), cursor: 0, history: None };
+        loop {
+            let key = self.read_key().await.ok_or(ReadError::Closed)?;
+            let out = &mut self.output;
+            match key {
+                Input::Char('\n') | Input::Char('\r') => {
+                    let _ = out.write_char('\n');
+                    let line: String = editor.line.into_iter().collect();
+                    self.remember(&line);
+                    return Ok(line);
+                }
+                Input::Char(CTRL_C) => {
+                    let _ = out.write_str("^C\n");
+                    return Err(ReadError::Interrupted);
+                }
+                Input::Char(CTRL_D) if editor.line.is_empty() => {
+                    let _ = out.write_char('\n');
+                    return Err(ReadError::Eof);
+                }
+                Input::Char(BACKSPACE) => editor.backspace(out),
+                Input::Char(DELETE) | Input::Delete => editor.delete(out),
+                Input::Char(ch) if is_printable(ch) => editor.insert(out, ch),
+                Input::Char(_) => {}
+                Input::Left => editor.left(out),
+                Input::Right => editor.right(out),
+                Input::Home => editor.home(out),
+                Input::End => editor.end(out),
+                Input::Up => editor.history_prev(out, &self.history),
+                Input::Down => editor.history_next(out, &self.history),
+            }
+        }
+    }
+
+    /// 把一行加入历史, 忽略空行和与上一行相同的行
+    fn remember(&mut self, line: &str) {
+        if line.trim().is_empty() || self.history.back().is_some_and(|last| last == line) {
+            return;
+        }
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(String::from(line));
+    }
+}
+
+/// 可以回显的字符, 输出端只支持 ASCII
+fn is_printable(ch: char) -> bool {
+    ch == ' ' || ch.is_ascii_graphic()
+}
+
+/// 正在编辑的一行, 每个操作都会同时更新屏幕
+struct LineEditor {
+    line: Vec<char>,
+    /// 光标在 `line` 中的位置
+    cursor: usize,
+    /// 正在浏览的历史的下标, `None` 表示正在编辑新的一行
+    history: Option<usize>,
+}
+
+impl LineEditor {
+    fn insert(&mut self, out: &mut impl Write, ch: char) {
+        self.line.insert(self.cursor, ch);
+        self.cursor += 1;
+        let _ = out.write_char(ch);
+        // 重新输出光标之后的部分, 再把光标移回来
+        self.redraw_tail(out, 0);
+    }
+
+    fn backspace(&mut self, out: &mut impl Write) {
+        if self.cursor == 0 {
+            return;
+        }
+        self.cursor -= 1;
+        self.line.remove(self.cursor);
+        let _ = out.write_char(BACKSPACE);
+        self.redraw_tail(out, 1);
+    }
+
+    fn delete(&mut self, out: &mut impl Write) {
+        if self.cursor == self.line.len() {
+            return;
+        }
+        self.line.remove(self.cursor);
+        self.redraw_tail(out, 1);
+    }
+
+    fn left(&mut self, out: &mut impl Write) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            let _ = out.write_char(BACKSPACE);
+        }
+    }
+
+    fn right(&mut self, out: &mut impl Write) {
+        if let Some(&ch) = self.line.get(self.cursor) {
+            self.cursor += 1;
+            let _ = out.write_char(ch);
+        }
+    }
+
+    fn home(&mut self, out: &mut impl Write) {
+        move_back(out, self.cursor);
+        self.cursor = 0;
+    }
+
+    fn end(&mut self, out: &mut impl Write) {
+        for &ch in &self.line[self.cursor..] {
+            let _ = out.write_char(ch);
+        }
+        self.cursor = self.line.len();
+    }
+
+    fn history_prev(&mut self, out: &mut impl Write, history: &VecDeque<String>) {
+        let index = match self.history {
+            Some(0) => return,
+            Some(index) => index - 1,
+            None if history.is_empty() => return,
+            None => history.len() - 1,
+        };
+        self.history = Some(index);
+        self.replace(out, &history[index]);
+    }
+
+    fn history_next(&mut self, out: &mut impl Write, history: &VecDeque<String>) {
+        match self.history {
+            Some(index) if index + 1 < history.len() => {
+                self.history = Some(index + 1);
+                self.replace(out, &history[index + 1]);
+            }
+            Some(_) => {
+                self.history = None;
+                self.replace(out, "");
+            }
+            None => {}
+        }
+    }
+
+    /// 把整行替换成 `text`, 光标移到行尾
+    fn replace(&mut self, out: &mut impl Write, text: &str) {
+        self.home(out);
+        let old_len = self.line.len();
+        self.line = text.chars().collect();
+        // 用空格覆盖旧的一行多出来的部分
+        let erase = old_len.saturating_sub(self.line.len());
+        self.redraw_tail(out, erase);
+        self.end(out);
+    }
+
+    /// 输出光标之后的字符和 `erase` 个空格, 然后把光标移回原来的位置
+    fn redraw_tail(&self, out: &mut impl Write, erase: usize) {
+        let tail = &self.line[self.cursor..];
+        for &ch in tail {
+            let _ = out.write_char(ch);
+        }
+        for _ in 0..erase {
+            let _ = out.write_char(' ');
+        }
+        move_back(out, tail.len() + erase);
+    }
+}
+
+fn move_back(out: &mut impl Write, count: usize) {
+    for _ in 0..count {
+        let _ = out.write_char(BACKSPACE);
+    }
+}
+
+/// 通过 [`crate::logger`] 输出到屏幕
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        crate::logger::_print(format_args!("{}", s));
+        Ok(())
+    }
+}
+
+/// 把键盘的按键事件转换成 [`Input`]
+pub struct KeyboardInput {
+    events: mpsc::Receiver<KeyEvent>,
+}
+
+impl KeyboardInput {
+    /// 订阅 [`keyboard::service`] 的按键事件, 布局和 Ctrl 组合键的映射由它处理
+    pub fn new() -> Self {
+        KeyboardInput { events: keyboard::subscribe() }
+    }
+}
+
+impl Default for KeyboardInput {
+    fn default() -> Self {
+        KeyboardInput::new()
+    }
+}
+
+impl Stream for KeyboardInput {
+    type Item = Input;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Input>> {
+        let this = &mut *self;
+        loop {
+            let event = match this.events.poll_recv(cx) {
+                Poll::Ready(Some(event)) => event,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            if let Some(input) = event.key.and_then(decode) {
+                return Poll::Ready(Some(input));
+            }
+        }
+    }
+}
+
+/// 终端不关心的按键 (如: 功能键) 返回 `None`
+fn decode(key: DecodedKey) -> Option<Input> {
+    match key {
+        DecodedKey::Unicode(ch) => Some(Input::Char(ch)),
+        DecodedKey::RawKey(KeyCode::ArrowLeft) => Some(Input::Left),
+        DecodedKey::RawKey(KeyCode::ArrowRight) => Some(Input::Right),
+        DecodedKey::RawKey(KeyCode::ArrowUp) => Some(Input::Up),
+        DecodedKey::RawKey(KeyCode::ArrowDown) => Some(Input::Down),
+        DecodedKey::RawKey(KeyCode::Home) => Some(Input::Home),
+        DecodedKey::RawKey(KeyCode::End) => Some(Input::End),
+        DecodedKey::RawKey(KeyCode::Delete) => Some(Input::Delete),
+        DecodedKey::RawKey(_) => None,
+    }
+}
+
+/// 把 VT100 终端 (如: 串口) 发送的字节解码成 [`Input`]
+///
+/// 终端的 Enter 发送 `'\r'`, Backspace 通常发送 `'\u{7f}'`, 方向键等发送以 ESC 开头的转义序列。
+/// 只支持 ASCII, 不认识的转义序列会被忽略。
+pub struct TerminalInput<S> {
+    bytes: S,
+    escape: Escape,
+}
+
+/// 转义序列的解析状态
+#[derive(Clone, Copy)]
+enum Escape {
+    None,
+    /// 收到了 ESC
+    Start,
+    /// 收到了 `ESC [`, 以及之后的数字参数
+    Csi(u8),
+}
+
+impl<S> TerminalInput<S> {
+    pub fn new(bytes: S) -> Self {
+        TerminalInput { bytes, escape: Escape::None }
+    }
+
+    fn decode(&mut self, byte: u8) -> Option<Input> {
+        match (self.escape, byte) {
+            (Escape::None, 0x1b) => {
+                self.escape = Escape::Start;
+                None
+            }
+            (Escape::None, b'\r') => Some(Input::Char('\n')),
+            // 终端的 Backspace
+            (Escape::None, 0x7f) => Some(Input::Char(BACKSPACE)),
+            (Escape::None, byte) => Some(Input::Char(char::from(byte))),
+            (Escape::Start, b'[') | (Escape::Start, b'O') => {
+                self.escape = Escape::Csi(0);
+                None
+            }
+            (Escape::Start, _) => {
+                self.escape = Escape::None;
+                None
+            }
+            (Escape::Csi(param), b'0'..=b'9') => {
+                self.escape = Escape::Csi(param.saturating_mul(10).saturating_add(byte - b'0'));
+                None
+            }
+            (Escape::Csi(param), byte) => {
+                self.escape = Escape::None;
+                match (byte, param) {
+                    (b'A', _) => Some(Input::Up),
+                    (b'B', _) => Some(Input::Down),
+                    (b'C', _) => Some(Input::Right),
+                    (b'D', _) => Some(Input::Left),
+                    (b'H', _) | (b'~', 1) | (b'~', 7) => Some(Input::Home),
+                    (b'F', _) | (b'~', 4) | (b'~', 8) => Some(Input::End),
+                    (b'~', 3) => Some(Input::Delete),
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+impl<S: Stream<Item = u8> + Unpin> Stream for TerminalInput<S> {
+    type Item = Input;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Input>> {
+        loop {
+            let byte = match Pin::new(&mut self.bytes).poll_next(cx) {
+                Poll::Ready(Some(byte)) => byte,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            if let Some(input) = self.decode(byte) {
+                return Poll::Ready(Some(input));
+            }
+        }
+    }
+}
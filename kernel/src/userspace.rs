@@ -0,0 +1,432 @@
+//! # 用户态 (ring 3)
+//!
+//! ## 地址空间
+//! 每个用户程序都有自己的四级页表 ([`AddressSpace`])。新建地址空间时, 内核页表中所有已使用的
+//! P4 项都会被复制过去, 所以内核的映射在所有地址空间中都是共享的 (且不带 `USER_ACCESSIBLE`,
+//! 用户程序无法访问)。用户程序只能使用 [`USER_SPACE_START`] 到 [`USER_SPACE_END`] 之间的地址。
+//!
+//! ## 进入用户态
+//! 参考: https://wiki.osdev.org/Getting_to_Ring_3
+//!
+//! 通过 `iretq` 进入 ring 3: 在栈上构造一个中断栈帧 (SS, RSP, RFLAGS, CS, RIP), 其中 CS 和 SS
+//! 是 RPL 为 3 的用户段选择子, `iretq` 就会 "返回" 到用户程序中。
+//!
+//! ## 离开用户态
+//! 用户程序在 ring 3 下触发 Page Fault 或 General Protection Fault 时, 不会导致内核崩溃。
+//! 中断处理函数会通过 [`exit_from_interrupt`] 修改中断栈帧, 使 `iretq` 返回到
+//! [`AddressSpace::run`] 的调用者中, 并返回用户程序结束的原因 ([`UserExit`])。
+//! 用户程序通过 `exit` 系统调用结束时, 则通过 [`exit_current`] 直接返回。
+//!
+//! ```ignore
+//! let mut space = AddressSpace::new()?;
+//! space.map(code_start, code.len() as u64, PageTableFlags::empty())?;
+//! space.copy_to(code_start, code)?;
+//! space.map(stack_start, stack_size, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)?;
+//! let exit = unsafe { space.run(code_start, stack_start + stack_size) };
+//! ```
+
+use core::arch::{asm, global_asm};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{gdt, memory};
+
+use spinning_top::{const_spinlock, Spinlock};
+use x86_64::{
+    align_down,
+    instructions::interrupts,
+    registers::{control::Cr3, rflags},
+    structures::{
+        idt::{InterruptStackFrame, PageFaultErrorCode},
+        paging::{
+            mapper::{MapToError, TranslateResult}, FrameAllocator, FrameDeallocator, Mapper,
+            OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate,
+        },
+    },
+    VirtAddr,
+};
+
+global_asm!(include_str!("asm/userspace.s"));
+
+extern "C" {
+    fn enter_user_mode(entry: u64, user_stack: u64, user_cs: u64, user_ss: u64, kernel_rsp: *mut u64);
+    fn return_from_user();
+    fn exit_to_kernel(kernel_rsp: u64, kernel_rflags: u64) -> !;
+}
+
+/// 用户空间的起始地址 (P4 index 32)
+pub const USER_SPACE_START: u64 = 0x_1000_0000_0000;
+/// 用户空间的结束地址 (不包含, P4 index 64)
+pub const USER_SPACE_END: u64 = 0x_2000_0000_0000;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// 进入用户态时内核的栈指针, 离开用户态时需要恢复
+static KERNEL_RSP: AtomicU64 = AtomicU64::new(0);
+/// 进入用户态时内核的 RFLAGS
+static KERNEL_RFLAGS: AtomicU64 = AtomicU64::new(0);
+/// 用户程序结束的原因, 由 [`exit_from_interrupt`] 设置
+static EXIT: Spinlock<Option<UserExit>> = const_spinlock(None);
+
+/// 上面的三个全局变量属于当前线程, 切换线程时需要保存和恢复, 见 [`crate::thread`]
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct UserContext {
+    kernel_rsp: u64,
+    kernel_rflags: u64,
+    exit: Option<UserExit>,
+}
+
+/// 把当前线程的状态保存到 `old` 中, 并恢复 `new`, 必须在关中断的情况下调用
+pub(crate) fn switch_context(old: &mut UserContext, new: &UserContext) {
+    let mut exit = EXIT.lock();
+    *old = UserContext {
+        kernel_rsp: KERNEL_RSP.load(Ordering::Relaxed),
+        kernel_rflags: KERNEL_RFLAGS.load(Ordering::Relaxed),
+        exit: exit.take(),
+    };
+    KERNEL_RSP.store(new.kernel_rsp, Ordering::Relaxed);
+    KERNEL_RFLAGS.store(new.kernel_rflags, Ordering::Relaxed);
+    *exit = new.exit;
+}
+
+/// 用户程序结束的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserExit {
+    /// 通过 `exit` 系统调用结束
+    Exited(i64),
+    /// 被内核结束, 见 [`crate::process::kill`]
+    Killed,
+    /// 访问了非法的地址
+    PageFault {
+        addr: VirtAddr,
+        error_code: PageFaultErrorCode,
+    },
+    /// 执行了特权指令或访问了非法的段
+    GeneralProtectionFault { error_code: u64 },
+}
+
+/// 在写入用户内存时, 目标地址没有被映射
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnmappedAddress(pub VirtAddr);
+
+/// 一个用户程序的地址空间
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    /// 创建一个新的地址空间, 其中只包含内核的映射
+    pub fn new() -> Result<Self, MapToError<Size4KiB>> {
+        let level_4_frame = memory::frame_allocator()
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+
+        let mut kernel_page_table = memory::mapper();
+        let level_4_table = unsafe { table_at(level_4_frame, kernel_page_table.phys_offset()) };
+        level_4_table.zero();
+        for (index, entry) in kernel_page_table.level_4_table().iter().enumerate() {
+            if is_user_p4_index(index) {
+                assert!(entry.is_unused(), "kernel memory mapped in user space");
+            } else {
+                level_4_table[index] = entry.clone();
+            }
+        }
+
+        Ok(AddressSpace { level_4_frame })
+    }
+
+    /// 返回这个地址空间的四级页表所在的 frame
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// 返回这个地址空间是否是当前正在使用的地址空间
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// 为 `[start, start + size)` 分配清零的 frame, 并以 `flags | PRESENT | USER_ACCESSIBLE` 映射
+    ///
+    /// Panics if the range is not inside of the user space.
+    pub fn map(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        assert!(is_user_range(start, size), "{:?} + {:#x} is not in user space", start, size);
+        unsafe { map_user_pages(self.level_4_frame, start, size, flags) }
+    }
+
+    /// 将 `data` 复制到这个地址空间的 `addr` 处, 目标内存必须已经映射
+    pub fn copy_to(&mut self, addr: VirtAddr, data: &[u8]) -> Result<(), UnmappedAddress> {
+        let page_table = self.page_table();
+
+        let mut copied = 0;
+        while copied < data.len() {
+            let addr = addr + copied;
+            let page = Page::<Size4KiB>::containing_address(addr);
+            let frame = page_table.translate_page(page).map_err(|_| UnmappedAddress(addr))?;
+
+            let offset = addr - page.start_address();
+            let len = usize::min(data.len() - copied, (PAGE_SIZE - offset) as usize);
+            let dest = page_table.phys_offset() + frame.start_address().as_u64() + offset;
+            unsafe {
+                core::ptr::copy_nonoverlapping(data[copied..].as_ptr(), dest.as_mut_ptr(), len);
+            }
+            copied += len;
+        }
+
+        Ok(())
+    }
+
+    /// 切换到这个地址空间, 并在 ring 3 下从 `entry` 开始执行, 直到用户程序结束
+    ///
+    /// ## Safety
+    /// `entry` 和 `stack_top` 必须位于这个地址空间中已映射的用户内存中。
+    pub unsafe fn run(&self, entry: VirtAddr, stack_top: VirtAddr) -> UserExit {
+        let (previous, flags) = Cr3::read();
+        Cr3::write(self.level_4_frame, flags);
+
+        let exit = enter(entry, stack_top);
+
+        Cr3::write(previous, flags);
+        exit
+    }
+
+    fn page_table(&mut self) -> OffsetPageTable<'_> {
+        unsafe { page_table(self.level_4_frame) }
+    }
+}
+
+impl Drop for AddressSpace {
+    /// 释放用户空间中映射的所有 frame 和页表, 内核的页表是共享的, 不会被释放
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropping the active address space");
+
+        let phys_offset = memory::mapper().phys_offset();
+        let mut frame_allocator = memory::frame_allocator();
+        let level_4_table = unsafe { table_at(self.level_4_frame, phys_offset) };
+        for (index, entry) in level_4_table.iter().enumerate() {
+            if is_user_p4_index(index) && !entry.is_unused() {
+                unsafe { free_table(entry.addr().as_u64(), 3, phys_offset, &mut *frame_allocator) };
+            }
+        }
+        unsafe { frame_allocator.deallocate_frame(self.level_4_frame) };
+    }
+}
+
+/// 以 ring 3 从 `entry` 开始执行, 使用当前的地址空间, 直到用户程序结束
+///
+/// ## Safety
+/// `entry` 和 `stack_top` 必须位于当前地址空间中已映射的用户内存中。
+pub unsafe fn enter(entry: VirtAddr, stack_top: VirtAddr) -> UserExit {
+    let selectors = gdt::selectors();
+    KERNEL_RFLAGS.store(rflags::read_raw(), Ordering::Relaxed);
+
+    // 在用户态发生中断或系统调用时, 使用当前栈中还没有被使用的部分, 这样每个线程都有自己的 RSP0
+    let rsp: u64;
+    asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack));
+    gdt::set_kernel_stack(VirtAddr::new(align_down(rsp - 0x100, 16)));
+
+    // AtomicU64 和 u64 的内存布局相同
+    enter_user_mode(
+        entry.as_u64(),
+        stack_top.as_u64(),
+        u64::from(selectors.user_code_selector.0),
+        u64::from(selectors.user_data_selector.0),
+        &KERNEL_RSP as *const AtomicU64 as *mut u64,
+    );
+
+    interrupts::without_interrupts(|| EXIT.lock().take())
+        .expect("returned from user mode without an exit reason")
+}
+
+/// 在内核态中结束当前的用户程序, 返回到 [`enter`] 的调用者中
+///
+/// ## Safety
+/// 必须在用户程序进入内核后 (如: 系统调用中) 调用, 且当前栈不能是 [`enter`] 的调用者所使用的栈。
+pub unsafe fn exit_current(exit: UserExit) -> ! {
+    *EXIT.lock() = Some(exit);
+    exit_to_kernel(KERNEL_RSP.load(Ordering::Relaxed), KERNEL_RFLAGS.load(Ordering::Relaxed))
+}
+
+/// 在当前的地址空间中为 `[start, start + size)` 分配清零的 frame, 并映射为用户内存。
+/// 失败时不会留下已经映射的页
+///
+/// Panics if the range is not inside of the user space.
+pub fn map_current(
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    assert!(is_user_range(start, size), "{:?} + {:#x} is not in user space", start, size);
+    unsafe { map_user_pages(Cr3::read().0, start, size, flags) }
+}
+
+/// 返回 `[start, start + size)` 是否全部是当前地址空间中已映射的用户内存
+///
+/// 系统调用在访问用户传入的指针前需要先检查, 否则用户程序可以让内核访问任意地址。
+pub fn is_user_accessible(start: VirtAddr, size: u64, writable: bool) -> bool {
+    if !is_user_range(start, size) {
+        return false;
+    }
+    if size == 0 {
+        return true;
+    }
+
+    let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if writable {
+        required |= PageTableFlags::WRITABLE;
+    }
+
+    let page_table = unsafe { page_table(Cr3::read().0) };
+    let start_page = Page::<Size4KiB>::containing_address(start);
+    let end_page = Page::containing_address(start + (size - 1));
+    Page::range_inclusive(start_page, end_page).all(|page| {
+        match page_table.translate(page.start_address()) {
+            TranslateResult::Mapped { flags, .. } => flags.contains(required),
+            _ => false,
+        }
+    })
+}
+
+/// 返回中断是否发生在用户态
+pub fn is_user_mode(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment & 0b11 == 3
+}
+
+/// 在中断处理函数中结束当前的用户程序
+///
+/// 修改中断栈帧, 使中断处理函数返回到 [`enter`] 的调用者中, 而不是用户程序中。
+///
+/// ## Safety
+/// 中断必须发生在用户态, 见 [`is_user_mode`] 。
+pub unsafe fn exit_from_interrupt(stack_frame: &mut InterruptStackFrame, exit: UserExit) {
+    *EXIT.lock() = Some(exit);
+
+    let selectors = gdt::selectors();
+    stack_frame.as_mut().update(|frame| {
+        frame.instruction_pointer = VirtAddr::new(return_from_user as *const () as u64);
+        frame.code_segment = u64::from(selectors.code_selector.0);
+        frame.cpu_flags = KERNEL_RFLAGS.load(Ordering::Relaxed);
+        frame.stack_pointer = VirtAddr::new(KERNEL_RSP.load(Ordering::Relaxed));
+        frame.stack_segment = u64::from(selectors.data_selector.0);
+    });
+}
+
+fn is_user_p4_index(index: usize) -> bool {
+    let start = Page::<Size4KiB>::containing_address(VirtAddr::new(USER_SPACE_START)).p4_index();
+    let end = Page::<Size4KiB>::containing_address(VirtAddr::new(USER_SPACE_END)).p4_index();
+    (usize::from(start)..usize::from(end)).contains(&index)
+}
+
+/// 返回 `[start, start + size)` 是否位于用户空间中
+pub fn is_user_range(start: VirtAddr, size: u64) -> bool {
+    start.as_u64() >= USER_SPACE_START
+        && start.as_u64().checked_add(size).is_some_and(|end| end <= USER_SPACE_END)
+}
+
+/// 失败时会撤销已经完成的映射并释放它们的 frame, 不会留下映射了一部分的区域。
+///
+/// ## Safety
+/// `level_4_frame` 必须是一个包含了内核映射的四级页表。
+unsafe fn map_user_pages(
+    level_4_frame: PhysFrame,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    if size == 0 {
+        return Ok(());
+    }
+
+    let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let active = Cr3::read().0 == level_4_frame;
+    let mut page_table = page_table(level_4_frame);
+    let mut frame_allocator = memory::frame_allocator();
+
+    let start_page = Page::<Size4KiB>::containing_address(start);
+    let end_page = Page::containing_address(start + (size - 1));
+    for page in Page::range_inclusive(start_page, end_page) {
+        if let Err(err) = map_user_page(&mut page_table, &mut *frame_allocator, page, flags, active) {
+            for mapped in Page::range(start_page, page) {
+                match page_table.unmap(mapped) {
+                    Ok((frame, flush)) => {
+                        if active {
+                            flush.flush();
+                        } else {
+                            flush.ignore();
+                        }
+                        frame_allocator.deallocate_frame(frame);
+                    }
+                    Err(err) => log::warn!("failed to unmap user page {:?}: {:?}", mapped, err),
+                }
+            }
+            return Err(err);
+        }
+    }
+
+    Ok(())
+}
+
+/// 为 `page` 分配一个清零的 frame 并映射它
+unsafe fn map_user_page(
+    page_table: &mut OffsetPageTable,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    page: Page,
+    flags: PageTableFlags,
+    active: bool,
+) -> Result<(), MapToError<Size4KiB>> {
+    let frame = frame_allocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    let addr = page_table.phys_offset() + frame.start_address().as_u64();
+    core::ptr::write_bytes(addr.as_mut_ptr::<u8>(), 0, PAGE_SIZE as usize);
+
+    match page_table.map_to(page, frame, flags, frame_allocator) {
+        // 不是当前的地址空间时, TLB 中不会有这个页的缓存
+        Ok(flush) if active => flush.flush(),
+        Ok(flush) => flush.ignore(),
+        Err(err) => {
+            frame_allocator.deallocate_frame(frame);
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// ## Safety
+/// `level_4_frame` 必须是一个四级页表, 且在返回值的生命周期内不能有其它对它的引用。
+unsafe fn page_table<'a>(level_4_frame: PhysFrame) -> OffsetPageTable<'a> {
+    let phys_offset = memory::mapper().phys_offset();
+    OffsetPageTable::new(table_at(level_4_frame, phys_offset), phys_offset)
+}
+
+/// ## Safety
+/// `frame` 必须是一个页表, 且完整的物理内存必须被映射到 `physical_memory_offset` 处。
+unsafe fn table_at(frame: PhysFrame, physical_memory_offset: VirtAddr) -> &'static mut PageTable {
+    let virt = physical_memory_offset + frame.start_address().as_u64();
+    &mut *virt.as_mut_ptr()
+}
+
+/// 释放 `level` 级页表 `table_addr` 中映射的所有 frame, 以及页表自身
+///
+/// 用户空间只会映射 4KiB 的页, 所以不需要处理 huge page 。
+unsafe fn free_table(
+    table_addr: u64,
+    level: u8,
+    physical_memory_offset: VirtAddr,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    let frame = PhysFrame::containing_address(x86_64::PhysAddr::new(table_addr));
+    let table = table_at(frame, physical_memory_offset);
+    for entry in table.iter().filter(|entry| !entry.is_unused()) {
+        if level > 1 {
+            free_table(entry.addr().as_u64(), level - 1, physical_memory_offset, frame_allocator);
+        } else {
+            frame_allocator.deallocate_frame(PhysFrame::containing_address(entry.addr()));
+        }
+    }
+    frame_allocator.deallocate_frame(frame);
+}
@@ -1,6 +1,53 @@
 use std::{process::Command, path::Path, io::{Read, Write}};
 
-fn build_kernel() {
+/// `--keyboard-layout` 可以使用的布局, 需要和内核的 `task::keyboard::Layout::ALL` 保持一致
+const KEYBOARD_LAYOUTS: [&str; 5] = ["us", "uk", "de", "fr", "dvorak"];
+
+/// 构建选项, 通过命令行参数指定, 如:
+///
+/// ```shell
+/// cargo run --release -- --kernel-stack-size 131072
+/// ```
+#[derive(Debug, Default)]
+struct BuildConfig {
+    /// 内核栈的大小 (单位: 字节), 会被向上取整到页大小。不指定时使用 bootloader 的默认值
+    kernel_stack_size: Option<u64>,
+    /// 默认的键盘布局 (如: `uk`), 运行时可以通过 shell 的 `layout` 命令切换。不指定时使用 `us`。
+    /// 内核还没有命令行, 所以在构建时通过环境变量 `KERNEL_KEYBOARD_LAYOUT` 传给内核
+    keyboard_layout: Option<String>,
+}
+
+impl BuildConfig {
+    fn from_args() -> BuildConfig {
+        let mut config = BuildConfig::default();
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--kernel-stack-size" => {
+                    let size = args.next()
+                        .and_then(|size| size.parse().ok())
+                        .expect("[Error]: --kernel-stack-size expects a number of bytes");
+                    config.kernel_stack_size = Some(size);
+                }
+                "--keyboard-layout" => {
+                    let layout = args.next().expect("[Error]: --keyboard-layout expects a layout name");
+                    if !KEYBOARD_LAYOUTS.iter().any(|name| name.eq_ignore_ascii_case(&layout)) {
+                        panic!(
+                            "[Error]: Unknown keyboard layout: {}, expected one of {}",
+                            layout,
+                            KEYBOARD_LAYOUTS.join(", ")
+                        );
+                    }
+                    config.keyboard_layout = Some(layout);
+                }
+                other => panic!("[Error]: Unknown argument: {}", other),
+            }
+        }
+        config
+    }
+}
+
+fn build_kernel(config: &BuildConfig) {
     println!("[Build]: Building libkernel.a ...");
 
     let mut cargo = Command::new(env!("CARGO"));
@@ -15,6 +62,9 @@ fn build_kernel() {
         .arg("-Z").arg("unstable-options")
         .arg("-Zbuild-std=core,compiler_builtins,alloc")
         .arg("-Zbuild-std-features=compiler-builtins-mem");
+    if let Some(layout) = &config.keyboard_layout {
+        cargo.env("KERNEL_KEYBOARD_LAYOUT", layout);
+    }
     let output = cargo.output().expect("[Error]: Failed to run cargo to build kernel elf");
     if !output.status.success() {
         panic!("[Error]: Failed to build kernel elf: \n{}", String::from_utf8_lossy(&output.stderr));
@@ -83,9 +133,13 @@ fn build_kernel() {
     println!("[Build]: Finished: ./target/libkernel.a");
 }
 
-fn build_bootloader() {
+fn build_bootloader(config: &BuildConfig) {
     println!("[Build]: Building bootloader elf ...");
     let mut cargo = Command::new(env!("CARGO"));
+    // 构建选项通过环境变量传递给 bootloader 的 build.rs
+    if let Some(size) = config.kernel_stack_size {
+        cargo.env("KERNEL_STACK_SIZE", size.to_string());
+    }
     // 构建 bootloader
     cargo.current_dir("./boot");
     cargo.arg("build")
@@ -164,10 +218,11 @@ fn create_disk_image(
 }
 
 fn main() {
+    let config = BuildConfig::from_args();
     // 构建 kernel
-    build_kernel();
+    build_kernel(&config);
     // 构建 bootloader
-    build_bootloader();
+    build_bootloader(&config);
     // 构建 bin 文件
     let bootloader_elf_path = Path::new("target/x86_64-bootloader/bootloader/boot");
     let output_bin_path = Path::new("./target/os.img");